@@ -0,0 +1,228 @@
+//! Benchmarks of building, searching and snapshotting trees, run with
+//! `cargo bench --bench add_node`.
+//!
+//! Each group covers several sizes so that the scaling of `Tree::add_node`, `Tree::find_head` and
+//! `Tree::snapshot_cow` can be read off the results. `Tree::add_nodes` is compared against adding
+//! the same blocks one at a time, and the ancestor cache against uncached inserts; nothing is
+//! asserted.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ethereum_types::H256 as Hash256;
+use nate_tree::{Block, Store, Tree};
+
+const GENESIS: u64 = 0;
+
+fn hash(i: u64) -> Hash256 {
+    Hash256::from_low_u64_be(i)
+}
+
+/// Returns a store holding a genesis block at slot `0` and a single chain of `len` blocks at
+/// slots `1..=len`, with the hashes of the chain in order.
+fn chain(len: u64) -> (Store, Vec<Hash256>) {
+    let mut store: Store = Store::new();
+    store.insert(hash(GENESIS), Block::genesis(hash(GENESIS), 0));
+
+    let mut parent = hash(GENESIS);
+    let hashes = (1..=len)
+        .map(|slot| {
+            let block = Block::new(slot, parent, &store).expect("parent should be in store");
+            parent = hash(slot);
+            store.insert(parent, block);
+            parent
+        })
+        .collect();
+
+    (store, hashes)
+}
+
+/// Returns a store holding a genesis block at slot `0` and `fanout` children of it at slot `1`.
+fn fanout(fanout: u64) -> (Store, Vec<Hash256>) {
+    let mut store: Store = Store::new();
+    store.insert(hash(GENESIS), Block::genesis(hash(GENESIS), 0));
+
+    let hashes = (1..=fanout)
+        .map(|i| {
+            let block = Block::new(1, hash(GENESIS), &store).expect("parent should be in store");
+            store.insert(hash(i), block);
+            hash(i)
+        })
+        .collect();
+
+    (store, hashes)
+}
+
+/// Returns a store holding a genesis block at slot `0` and two chains from it, one at the even
+/// slots `2..=2 * len` and one at the odd slots `1..2 * len`, with the hashes of each chain.
+fn interleaved(len: u64) -> (Store, Vec<Hash256>, Vec<Hash256>) {
+    let mut store: Store = Store::new();
+    store.insert(hash(GENESIS), Block::genesis(hash(GENESIS), 0));
+
+    let mut chains = (vec![], vec![]);
+    for slot in 1..=2 * len {
+        let chain = if slot % 2 == 0 {
+            &mut chains.0
+        } else {
+            &mut chains.1
+        };
+        let parent = chain.last().copied().unwrap_or(hash(GENESIS));
+        let block = Block::new(slot, parent, &store).expect("parent should be in store");
+        store.insert(hash(slot), block);
+        chain.push(hash(slot));
+    }
+
+    (store, chains.0, chains.1)
+}
+
+/// Returns a tree with every block of `hashes` added, in order.
+fn build(store: Store, hashes: &[Hash256]) -> Tree {
+    let mut tree = Tree::new(hash(GENESIS), 0).with_store(store);
+    for hash in hashes {
+        tree.add_node(*hash, *hash).expect("should add block");
+    }
+    tree
+}
+
+/// Measures adding every block of each scenario to an empty tree. Throughput is reported per
+/// block, so the time per insert can be compared across sizes.
+fn add_node(c: &mut Criterion) {
+    bench_add_node(c, "chain", chain, &[100, 1_000, 10_000]);
+    bench_add_node(c, "fanout", fanout, &[10, 100, 1_000]);
+}
+
+fn bench_add_node(
+    c: &mut Criterion,
+    name: &str,
+    scenario: fn(u64) -> (Store, Vec<Hash256>),
+    sizes: &[u64],
+) {
+    let mut group = c.benchmark_group(format!("add_node/{}", name));
+    for &size in sizes {
+        let (store, hashes) = scenario(size);
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter_batched(
+                || store.clone(),
+                |store| build(store, &hashes),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Measures adding a chain whose every slot lands between two heights of a chain already in the
+/// tree, one block at a time and as a batch, which shifts the existing heights only once.
+fn add_nodes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_nodes/interleaved");
+    // Adding one block at a time takes seconds at the largest size.
+    group.sample_size(10);
+    for &size in &[100, 1_000, 5_000] {
+        let (store, existing, added) = interleaved(size);
+        let tree = build(store, &existing);
+        group.throughput(Throughput::Elements(size));
+        group.bench_with_input(BenchmarkId::new("add_node", size), &size, |b, _| {
+            b.iter_batched(
+                || Tree::restore(tree.snapshot_full().snapshot()),
+                |mut tree| {
+                    for hash in &added {
+                        tree.add_node(*hash, *hash).expect("should add block");
+                    }
+                    tree
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("add_nodes", size), &size, |b, _| {
+            b.iter_batched(
+                || Tree::restore(tree.snapshot_full().snapshot()),
+                |mut tree| {
+                    tree.add_nodes(added.iter().map(|hash| (*hash, *hash)))
+                        .expect("should add blocks");
+                    tree
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Measures adding a long chain with and without the ancestor cache, which only pays for itself
+/// when lookups repeat.
+fn ancestor_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_node/ancestor_cache");
+    group.sample_size(10);
+    let size = 50_000;
+    let (store, hashes) = chain(size);
+    group.throughput(Throughput::Elements(size));
+    for &(name, capacity) in &[("uncached", None), ("cached", Some(1 << 16))] {
+        group.bench_with_input(BenchmarkId::new(name, size), &capacity, |b, capacity| {
+            b.iter_batched(
+                || {
+                    let mut tree = Tree::new(hash(GENESIS), 0).with_store(store.clone());
+                    if let Some(capacity) = capacity {
+                        tree.enable_ancestor_cache(*capacity);
+                    }
+                    tree
+                },
+                |mut tree| {
+                    for hash in &hashes {
+                        tree.add_node(*hash, *hash).expect("should add block");
+                    }
+                    tree
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Measures finding the head of a single chain from its genesis.
+fn find_head(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_head/chain");
+    for &size in &[100, 1_000, 10_000] {
+        let (store, hashes) = chain(size);
+        let tree = build(store, &hashes);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| tree.find_head(hash(GENESIS)))
+        });
+    }
+    group.finish();
+}
+
+/// Measures taking a snapshot of a single chain, and taking one before a mutation of the live
+/// tree, which copies the nodes. `full` is the baseline of copying every collection and the store
+/// up front, as a clone of the tree without sharing would.
+fn snapshot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot/chain");
+    for &size in &[1_000, 100_000] {
+        let (store, hashes) = chain(size);
+        let mut tree = build(store, &hashes);
+        group.bench_with_input(BenchmarkId::new("cow", size), &size, |b, _| {
+            b.iter(|| tree.snapshot_cow())
+        });
+        group.bench_with_input(BenchmarkId::new("cow_then_mutate", size), &size, |b, _| {
+            b.iter(|| {
+                let snapshot = tree.snapshot_cow();
+                tree.apply_score_change(hash(GENESIS), 1)
+                    .expect("should apply vote");
+                snapshot
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("full", size), &size, |b, _| {
+            b.iter(|| tree.snapshot_full())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    add_node,
+    add_nodes,
+    ancestor_cache,
+    find_head,
+    snapshot
+);
+criterion_main!(benches);
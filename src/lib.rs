@@ -1,228 +1,8343 @@
 use ethereum_types::H256 as Hash256;
-use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::iter::FromIterator;
 use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 pub const SKIP_LIST_LEN: usize = 16;
 
+/// The number of slots in an epoch, as on mainnet, see `Tree::find_head_filtered`.
+pub const SLOTS_PER_EPOCH: Slot = 32;
+
 pub type Height = usize;
 pub type Slot = u64;
+pub type Epoch = u64;
+pub type ValidatorIndex = u64;
+
+pub type Result<T, H = Hash256> = core::result::Result<T, Error<H>>;
+
+/// A hash that identifies a block, e.g., `H256`.
+///
+/// Implemented for every type that meets the bounds, so small types such as `u64` can be used in
+/// place of real hashes.
+pub trait BlockHash: Eq + std::hash::Hash + Ord + Copy {}
+
+impl<T: Eq + std::hash::Hash + Ord + Copy> BlockHash for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error<H = Hash256> {
+    /// The block is not known to the store or the tree.
+    UnknownBlock(H),
+    /// None of the block's ancestors are in the tree.
+    MissingParent(H),
+    /// The store or the tree refers to a block that it does not contain.
+    InconsistentStore,
+    /// A height outside of the tree was requested.
+    HeightOutOfRange,
+    /// A block's slot is not greater than the slot of its parent.
+    NonMonotonicSlot { slot: Slot, parent_slot: Slot },
+    /// A node's `parent_hash` refers to a block that is not in the tree.
+    DanglingParent { node: H, parent: H },
+    /// A node lists a child that is not in the tree or whose `parent_hash` is another block.
+    UnlinkedChild { parent: H, child: H },
+    /// A node's children are not sorted by hash, or list a child more than once.
+    UnsortedChildren(H),
+    /// A node is missing from `blocks_at_height`, or is listed at a height that does not match
+    /// its slot or its `Node::height`.
+    InconsistentHeight(H),
+    /// The node is reachable from the root more than once.
+    Cycle(H),
+    /// The block is in the tree but is not a descendant of the root.
+    NotDescendant(H),
+    /// An attestation is from an earlier slot than the block it attests to.
+    FutureTarget { slot: Slot, target_slot: Slot },
+    /// An attestation is not newer than the latest message of its validator.
+    StaleAttestation { slot: Slot, latest_slot: Slot },
+    /// An attestation is from a later slot than the current slot, see `Tree::on_tick`.
+    FutureSlot { slot: Slot, current_slot: Slot },
+    /// The root of the tree cannot be removed.
+    RemoveRoot,
+    /// Removing weight from the node would make its score negative.
+    ScoreUnderflow(H),
+    /// Adding weight to the node would make its score greater than `u64::MAX`.
+    ScoreOverflow(H),
+    /// A `TreeBuilder` was built without a root.
+    MissingRoot,
+    /// A participant of an aggregate attestation has no balance, see `Tree::process_aggregate`.
+    MissingBalance(ValidatorIndex),
+    /// A block is described more than once, e.g., to `Tree::from_chain_json` or `StoreBuilder`,
+    /// or differently from the block already stored under its hash, see `Tree::on_block`.
+    DuplicateBlock(H),
+    /// The input to `Tree::from_chain_json` is not a valid chain description, with the message of
+    /// the parser.
+    InvalidJson(String),
+}
+
+impl<H: std::fmt::Debug> std::fmt::Display for Error<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::UnknownBlock(hash) => write!(f, "unknown block {:?}", hash),
+            Error::MissingParent(hash) => {
+                write!(f, "no ancestor of block {:?} is in the tree", hash)
+            }
+            Error::InconsistentStore => {
+                write!(f, "the store refers to a block it does not contain")
+            }
+            Error::HeightOutOfRange => write!(f, "height out of range"),
+            Error::NonMonotonicSlot { slot, parent_slot } => write!(
+                f,
+                "block slot {} does not exceed parent slot {}",
+                slot, parent_slot
+            ),
+            Error::DanglingParent { node, parent } => {
+                write!(
+                    f,
+                    "node {:?} has parent {:?} outside the tree",
+                    node, parent
+                )
+            }
+            Error::UnlinkedChild { parent, child } => {
+                write!(f, "node {:?} lists unlinked child {:?}", parent, child)
+            }
+            Error::UnsortedChildren(hash) => {
+                write!(f, "children of node {:?} are not sorted", hash)
+            }
+            Error::InconsistentHeight(hash) => write!(f, "node {:?} is at the wrong height", hash),
+            Error::Cycle(hash) => write!(f, "node {:?} is reachable more than once", hash),
+            Error::NotDescendant(hash) => {
+                write!(f, "block {:?} does not descend from the root", hash)
+            }
+            Error::FutureTarget { slot, target_slot } => write!(
+                f,
+                "attestation slot {} is before target slot {}",
+                slot, target_slot
+            ),
+            Error::StaleAttestation { slot, latest_slot } => write!(
+                f,
+                "attestation slot {} is not after latest message slot {}",
+                slot, latest_slot
+            ),
+            Error::FutureSlot { slot, current_slot } => write!(
+                f,
+                "attestation slot {} is after current slot {}",
+                slot, current_slot
+            ),
+            Error::RemoveRoot => write!(f, "the root cannot be removed"),
+            Error::ScoreUnderflow(hash) => write!(f, "score of node {:?} would underflow", hash),
+            Error::ScoreOverflow(hash) => write!(f, "score of node {:?} would overflow", hash),
+            Error::MissingRoot => write!(f, "tree builder has no root"),
+            Error::MissingBalance(validator) => write!(f, "validator {} has no balance", validator),
+            Error::DuplicateBlock(hash) => {
+                write!(f, "block {:?} is described more than once", hash)
+            }
+            Error::InvalidJson(message) => write!(f, "invalid chain description: {}", message),
+        }
+    }
+}
+
+impl<H: std::fmt::Debug> std::error::Error for Error<H> {}
 
-#[derive(Default, Clone)]
-pub struct Node {
-    pub parent_hash: Option<Hash256>,
-    pub children: Vec<Hash256>,
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Node<H = Hash256> {
+    pub parent_hash: Option<H>,
+    /// Sorted by hash, without duplicates, so children are always visited in the same order.
+    pub children: Vec<H>,
     pub score: u64,
+    /// See `Tree::height_of`.
     pub height: Height,
-    pub block_hash: Hash256,
+    pub block_hash: H,
+    /// The child that `Tree::find_head` descends into, or `None` if the node has no children.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub best_child: Option<H>,
+    /// A node on the path that `Tree::find_head` takes from this node, or `None` if the node has
+    /// no children.
+    ///
+    /// This is often the leaf at the end of the path. Adding a block only updates its parent, so it
+    /// may instead be a node part of the way down, from which `find_head` carries on. Score
+    /// changes bring the nodes they pass through up to date with the node below them.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub best_descendant: Option<H>,
+    /// The epoch that the votes in the chain of this block would justify if they were counted at
+    /// the end of the epoch, or `None` if unknown, see `Tree::set_unrealized`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unrealized_justified_epoch: Option<Epoch>,
 }
 
-impl Node {
+impl<H: BlockHash> Node<H> {
+    /// Returns a node for `block_hash` with no parent, no children and no score.
+    fn new(block_hash: H) -> Self {
+        Self {
+            parent_hash: None,
+            children: vec![],
+            score: 0,
+            height: 0,
+            block_hash,
+            best_child: None,
+            best_descendant: None,
+            unrealized_justified_epoch: None,
+        }
+    }
+
     fn does_not_have_children(&self) -> bool {
         self.children.is_empty()
     }
+
+    /// Adds `child` to `children`, keeping them sorted. Does nothing if `child` is already a
+    /// child.
+    fn add_child(&mut self, child: H) {
+        if let Err(i) = self.children.binary_search(&child) {
+            self.children.insert(i, child);
+        }
+    }
+
+    fn replace_child(&mut self, old: H, new: H) {
+        if let Ok(i) = self.children.binary_search(&old) {
+            self.children.remove(i);
+            self.add_child(new);
+        }
+    }
+}
+
+/// Hooks for recording what a `Tree` spends its time on, e.g., as Prometheus metrics.
+///
+/// Every method does nothing by default, so implementations need only override the events they
+/// record. Install an implementation with `Tree::with_metrics`.
+pub trait Metrics: Send + Sync {
+    /// Called after each call to `Tree::add_node`, successful or not, with how long it took.
+    fn on_add_node(&self, _duration: Duration) {}
+
+    /// Called after the head is selected by `Tree::update_head`, with how long the search took
+    /// and whether the new head does not descend from the old head.
+    fn on_find_head(&self, _duration: Duration, _reorg: bool) {}
+
+    /// Called after `Tree::prune` or `Tree::set_root` with the number of nodes removed.
+    fn on_prune(&self, _removed: usize) {}
+}
+
+/// A callback registered with `Tree::on_head_change`, called with the new head and the previous
+/// head.
+pub type HeadCallback<H = Hash256> = Box<dyn FnMut(H, Option<H>) + Send + Sync>;
+
+/// The `Metrics` of a `Tree` without `Tree::with_metrics`, which records nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// The collections of a tree are behind `Arc`s so that `Tree::snapshot` and `Tree::snapshot_cow`
+/// can share them instead of copying. A mutation copies a collection (with `Arc::make_mut`) only
+/// if it is still shared with a snapshot.
+///
+/// The blocks are kept in `S`, an in-memory `Store` unless another `BlockStore` is given to
+/// `TreeBuilder::with_store`, with skip lists of `N` entries.
+pub struct Tree<H = Hash256, const N: usize = SKIP_LIST_LEN, S = Store<H, N>> {
+    store: Arc<S>,
+    nodes: Arc<HashMap<H, Node<H>>>,
+    root: H,
+    slots_at_height: Arc<SortedList<Slot>>,
+    blocks_at_height: Arc<HashMap<Height, Vec<H>>>,
+    /// The block and slot of the most recent attestation from each validator.
+    latest_messages: Arc<HashMap<ValidatorIndex, (H, Slot)>>,
+    /// The balance counted for the latest message of each validator, see
+    /// `Tree::message_balance`.
+    message_balances: Arc<HashMap<ValidatorIndex, u64>>,
+    /// Validators that attested to two different blocks at the same slot, whose weight is no
+    /// longer counted, see `Tree::is_equivocating`.
+    equivocating: Arc<HashSet<ValidatorIndex>>,
+    /// Validators whose latest message is for a block that is not in the tree, by the node that
+    /// the message counts towards, so that `Tree::add_node` can find the votes to move to a new
+    /// node without scanning every latest message.
+    ///
+    /// A validator may be left behind after its latest message changes; each is checked against
+    /// its latest message when used.
+    votes_off_tree: Arc<HashMap<H, HashSet<ValidatorIndex>>>,
+    /// Results of ancestor lookups, if enabled with `Tree::enable_ancestor_cache`.
+    ///
+    /// Behind a `Mutex` so that lookups from `&self` can fill it while the tree stays `Sync`.
+    ancestor_cache: Option<Mutex<AncestorCache<H>>>,
+    /// The head as of the last call to `Tree::update_head`.
+    head: H,
+    /// See `Tree::update_justified`.
+    justified: Checkpoint<H>,
+    /// See `Tree::update_finalized`.
+    finalized: Checkpoint<H>,
+    /// The slot of the clock as of the last call to `Tree::on_tick`.
+    current_slot: Slot,
+    metrics: Box<dyn Metrics>,
+    /// See `Tree::on_head_change`.
+    head_callbacks: Vec<HeadCallback<H>>,
+    /// The head last passed to the head callbacks, or found when the first was registered.
+    notified_head: Option<H>,
+}
+
+/// An in-memory copy of a `Tree`, including its `Store`, see `Tree::snapshot`.
+#[derive(Clone)]
+pub struct TreeSnapshot<H = Hash256, const N: usize = SKIP_LIST_LEN, S = Store<H, N>> {
+    store: Arc<S>,
+    nodes: Arc<HashMap<H, Node<H>>>,
+    root: H,
+    slots_at_height: Arc<SortedList<Slot>>,
+    blocks_at_height: Arc<HashMap<Height, Vec<H>>>,
+    latest_messages: Arc<HashMap<ValidatorIndex, (H, Slot)>>,
+    message_balances: Arc<HashMap<ValidatorIndex, u64>>,
+    equivocating: Arc<HashSet<ValidatorIndex>>,
+    votes_off_tree: Arc<HashMap<H, HashSet<ValidatorIndex>>>,
+    head: H,
+    justified: Checkpoint<H>,
+    finalized: Checkpoint<H>,
+    current_slot: Slot,
+}
+
+/// A block and the epoch for which it is the checkpoint, e.g., the justified or finalized
+/// checkpoint of `Tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Checkpoint<H = Hash256> {
+    pub root: H,
+    pub epoch: Epoch,
+}
+
+/// The order in which `Tree::walk_subtree` visits nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraversalOrder {
+    /// Each node before its children, depth-first.
+    PreOrder,
+    /// Each node after its children, depth-first.
+    PostOrder,
+    /// Each node before any node further from the root of the walk.
+    BreadthFirst,
+}
+
+/// A change of head, returned by `Tree::update_head`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadUpdate<H = Hash256> {
+    pub new_head: H,
+    pub old_head: H,
+    /// If `new_head` does not descend from `old_head`, the number of slots from `old_head` back
+    /// to their common ancestor. Otherwise `None`.
+    pub reorg_depth: Option<usize>,
+}
+
+/// Where a block was placed in the tree, returned by `Tree::add_node`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodePlacement<H = Hash256> {
+    /// The height of the node when it was added, see `Tree::height_of`.
+    pub height: Height,
+    /// The node that the block was added beneath, i.e., its most recent ancestor in the tree, or
+    /// `None` for the root.
+    pub parent_hash: Option<H>,
+}
+
+/// The differences between the nodes of two trees, returned by `Tree::diff`.
+///
+/// Every list is sorted by hash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeDiff<H = Hash256> {
+    /// Nodes of the other tree that are not in this tree.
+    pub added: Vec<H>,
+    /// Nodes of this tree that are not in the other tree.
+    pub removed: Vec<H>,
+    /// Nodes of both trees whose fields (e.g., score, parent or children) differ, with the node in
+    /// this tree and then the node in the other tree.
+    pub changed: Vec<(H, Node<H>, Node<H>)>,
+}
+
+impl<H> TreeDiff<H> {
+    /// Returns `true` if the trees have the same nodes.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Every block from the root of a tree to its leaves, including the blocks that the tree omits
+/// between its nodes, returned by `Tree::expand`.
+#[derive(Debug, Clone)]
+pub struct FullTree<H = Hash256> {
+    pub root: H,
+    pub blocks: HashMap<H, FullNode<H>>,
+}
+
+/// A block of a `FullTree`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FullNode<H = Hash256> {
+    /// The parent block, or `None` for the root.
+    pub parent: Option<H>,
+    /// Sorted by hash.
+    pub children: Vec<H>,
+    pub slot: Slot,
+    /// Whether the block is a node of the reduced tree, rather than only in the store.
+    pub in_tree: bool,
+}
+
+/// A breadth-first iterator over the descendants of a node, see `Tree::descendants`.
+struct Descendants<'a, H> {
+    nodes: &'a HashMap<H, Node<H>>,
+    queue: VecDeque<H>,
+}
+
+impl<'a, H: BlockHash> Iterator for Descendants<'a, H> {
+    type Item = H;
+
+    fn next(&mut self) -> Option<H> {
+        let hash = self.queue.pop_front()?;
+        if let Some(node) = self.nodes.get(&hash) {
+            self.queue.extend(node.children.iter().copied());
+        }
+        Some(hash)
+    }
+}
+
+/// An iterator over a block and its ancestors in the store, see `Tree::ancestors`.
+struct Ancestors<'a, H, S, const N: usize> {
+    store: &'a S,
+    next: Option<H>,
+    /// The number of blocks that may still be yielded. A chain cannot be longer than the store,
+    /// so this bounds the walk even if a corrupt store links blocks in a cycle.
+    remaining: usize,
+}
+
+impl<'a, H: BlockHash, S: BlockStore<H, N>, const N: usize> Iterator for Ancestors<'a, H, S, N> {
+    type Item = H;
+
+    fn next(&mut self) -> Option<H> {
+        let hash = self.next.take()?;
+        let block = self.store.get(&hash)?;
+        self.remaining = self.remaining.checked_sub(1)?;
+
+        let parent = block.ancestor_skip_list[0];
+        if parent != hash {
+            self.next = Some(parent);
+        }
+        Some(hash)
+    }
+}
+
+/// The state of a `Tree`, excluding its `Store`, in a form that can be serialized.
+///
+/// Blocks are serialized separately (`Block` implements serde) and provided again when the tree
+/// is restored with `Tree::from_state`.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+pub struct TreeState<H: BlockHash = Hash256> {
+    pub nodes: HashMap<H, Node<H>>,
+    pub root: H,
+    pub blocks_at_height: HashMap<Height, Vec<H>>,
+    pub slots_at_height: Vec<Slot>,
+    #[serde(default)]
+    pub latest_messages: HashMap<ValidatorIndex, (H, Slot)>,
+    #[serde(default)]
+    pub message_balances: HashMap<ValidatorIndex, u64>,
+    #[serde(default)]
+    pub equivocating: HashSet<ValidatorIndex>,
+    #[serde(default)]
+    pub head: Option<H>,
+    #[serde(default)]
+    pub justified: Option<Checkpoint<H>>,
+    #[serde(default)]
+    pub finalized: Option<Checkpoint<H>>,
+    #[serde(default)]
+    pub current_slot: Slot,
+}
+
+/// Summarizes the tree rather than listing every node.
+impl<H: BlockHash + std::fmt::Debug, const N: usize, S: BlockStore<H, N> + Clone> std::fmt::Debug
+    for Tree<H, N, S>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Tree")
+            .field("root", &self.root)
+            .field("head", &self.head)
+            .field("nodes", &self.nodes.len())
+            .field("leaves", &self.leaf_count())
+            .field("blocks", &self.store.len())
+            .finish_non_exhaustive()
+    }
 }
 
-pub struct Tree {
-    store: Store,
-    nodes: HashMap<Hash256, Node>,
-    root: Hash256,
-    slots_at_height: SortedList<Slot>,
-    blocks_at_height: HashMap<Height, Vec<Hash256>>,
+/// Returns a tree whose root is a sentinel, `H::default()` (`Hash256::zero()` for the default
+/// hash), as a genesis block at slot `0`, so that a `Tree` can be embedded in a type that derives
+/// `Default`.
+///
+/// The sentinel is not a real block, so the tree must be `reset` to a real anchor before use. Until
+/// then it behaves as any tree with only a root, e.g., `find_head` returns the sentinel.
+impl<H: BlockHash + Default> Default for Tree<H> {
+    fn default() -> Self {
+        Self::new(H::default(), 0)
+    }
 }
 
-impl Tree {
-    pub fn new(root: Hash256, height: Height) -> Self {
-        let mut node: Node = Node::default();
-        node.height = 0;
+impl<H: BlockHash> Tree<H> {
+    /// Returns a tree containing only `root`, a genesis block at `genesis_slot`.
+    ///
+    /// The genesis block is also added to the store, as `Block::genesis(root, genesis_slot)`, so
+    /// that ancestor queries that reach the root are answered from the store like any other
+    /// block. Whatever its slot, the root is at height `0`. See `TreeBuilder` to start from an
+    /// existing store.
+    pub fn new(root: H, genesis_slot: Slot) -> Self {
+        let mut store = Store::default();
+        store.insert(root, Block::genesis(root, genesis_slot));
+        Self::from_store(root, genesis_slot, store)
+    }
+}
 
+impl<H: BlockHash, const N: usize, S: BlockStore<H, N> + Clone> Tree<H, N, S> {
+    /// Returns a tree containing only `root`, a genesis block at `slot` that is in `store`, at
+    /// height `0`.
+    fn from_store(root: H, slot: Slot, store: S) -> Self {
         let mut nodes = HashMap::new();
-        nodes.insert(root, Node::default());
+        nodes.insert(root, Node::new(root));
 
         let mut blocks_at_height = HashMap::new();
-        blocks_at_height.insert(height, vec![root]);
+        blocks_at_height.insert(0, vec![root]);
+
+        let mut slots_at_height = SortedList::new();
+        slots_at_height.insert(slot);
 
         Self {
-            store: Store::default(),
-            nodes,
+            store: Arc::new(store),
+            nodes: Arc::new(nodes),
             root,
-            slots_at_height: SortedList::new(),
-            blocks_at_height,
+            slots_at_height: Arc::new(slots_at_height),
+            blocks_at_height: Arc::new(blocks_at_height),
+            latest_messages: Arc::default(),
+            message_balances: Arc::default(),
+            equivocating: Arc::default(),
+            votes_off_tree: Arc::default(),
+            ancestor_cache: None,
+            head: root,
+            justified: Checkpoint { root, epoch: 0 },
+            finalized: Checkpoint { root, epoch: 0 },
+            current_slot: slot,
+            metrics: Box::new(NoMetrics),
+            head_callbacks: vec![],
+            notified_head: None,
         }
     }
 
-    pub fn add_node(&mut self, hash: Hash256, block_hash: Hash256) -> Option<()> {
-        // TODO: resolve clone.
-        let mut prev_in_tree = self
-            .find_prev_in_tree(hash, 0..self.slots_at_height.len())?
-            .clone();
+    /// Clears the tree and its store in place and starts again from `root`, a genesis block at
+    /// `slot`, as `Tree::new` would, e.g., for a hard reset to a new anchor.
+    ///
+    /// Unlike replacing the tree with a new one, the capacity of the collections is kept, as are
+    /// the metrics, the head callbacks and whether the ancestor cache is enabled. The latest
+    /// messages are forgotten, and both checkpoints are reset to `root` at epoch `0`. The head
+    /// callbacks are not called for the reset; the next change is reported from `root`.
+    pub fn reset(&mut self, root: H, slot: Slot) {
+        // Unlike the other collections, a shared store is copied before it is cleared, as a
+        // `BlockStore` need not have an empty default.
+        let store = Arc::make_mut(&mut self.store);
+        store.clear();
+        store.insert(root, Block::genesis(root, slot));
 
-        let mut node = Node {
-            block_hash,
-            parent_hash: Some(prev_in_tree.block_hash),
-            ..Node::default()
+        let nodes = unshared_for_clear(&mut self.nodes);
+        nodes.clear();
+        nodes.insert(root, Node::new(root));
+        self.root = root;
+
+        let blocks_at_height = unshared_for_clear(&mut self.blocks_at_height);
+        blocks_at_height.clear();
+        blocks_at_height.insert(0, vec![root]);
+        let slots_at_height = unshared_for_clear(&mut self.slots_at_height);
+        slots_at_height.0.clear();
+        slots_at_height.insert(slot);
+
+        unshared_for_clear(&mut self.latest_messages).clear();
+        unshared_for_clear(&mut self.message_balances).clear();
+        unshared_for_clear(&mut self.equivocating).clear();
+        unshared_for_clear(&mut self.votes_off_tree).clear();
+        self.clear_ancestor_cache();
+        self.head = root;
+        if !self.head_callbacks.is_empty() {
+            self.notified_head = Some(root);
+        }
+        self.justified = Checkpoint { root, epoch: 0 };
+        self.finalized = Checkpoint { root, epoch: 0 };
+        self.current_slot = slot;
+    }
+
+    /// Caches the results of up to `capacity` ancestor lookups, evicting the least recently used
+    /// result when full. Replaces any existing cache.
+    ///
+    /// The cache saves walking the same skip lists again when lookups repeat, e.g., when many
+    /// blocks are compared against the same children of a fork. Lookups for a single chain
+    /// imported in order rarely repeat, in which case the cache only adds overhead.
+    pub fn enable_ancestor_cache(&mut self, capacity: usize) {
+        self.ancestor_cache = Some(Mutex::new(AncestorCache::new(capacity)));
+    }
+
+    /// Stops caching ancestor lookups and drops any cached results.
+    pub fn disable_ancestor_cache(&mut self) {
+        self.ancestor_cache = None;
+    }
+
+    /// Uses `store` as the block store of the tree, replacing the store of `Tree::new`, which
+    /// holds only the genesis block.
+    ///
+    /// `store` should hold the root and every block that will be added to the tree.
+    pub fn with_store(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self.clear_ancestor_cache();
+        self
+    }
+
+    /// Registers `callback` to be called with the new head and the previous head whenever a call
+    /// to `on_block` or `on_attestation` changes the head, as `update_head` would select it.
+    ///
+    /// The previous head is the head last reported, or the head when the first callback was
+    /// registered, or `None` if that head could not be found. Callbacks are called in the order
+    /// they were registered and only when the head differs. Once a callback is registered, the
+    /// head is selected after every successful `on_block` and `on_attestation`, which takes time
+    /// linear in the number of nodes while the justified checkpoint is above the root.
+    pub fn on_head_change(&mut self, callback: HeadCallback<H>) {
+        if self.head_callbacks.is_empty() {
+            self.notified_head = self.select_head().ok();
+        }
+        self.head_callbacks.push(callback);
+    }
+
+    /// Selects the head and calls the head callbacks if it differs from the head last reported.
+    fn notify_head_change(&mut self) {
+        if self.head_callbacks.is_empty() {
+            return;
+        }
+        let head = match self.select_head() {
+            Ok(head) if self.notified_head != Some(head) => head,
+            _ => return,
         };
 
-        if prev_in_tree.does_not_have_children() {
-            node.parent_hash = Some(prev_in_tree.block_hash);
-            prev_in_tree.children.push(hash);
-        } else {
-            for child_hash in prev_in_tree.children {
-                let ancestor_hash = self.find_least_common_ancestor(hash, child_hash)?;
-                if ancestor_hash != prev_in_tree.block_hash {
-                    let child = self.nodes.get_mut(&child_hash)?;
-                    let common_ancestor = Node {
-                        block_hash: ancestor_hash,
-                        parent_hash: Some(prev_in_tree.block_hash),
-                        ..Node::default()
-                    };
-                    child.parent_hash = Some(common_ancestor.block_hash);
-                    node.parent_hash = Some(common_ancestor.block_hash);
+        let previous = self.notified_head.replace(head);
+        for callback in self.head_callbacks.iter_mut() {
+            callback(head, previous);
+        }
+    }
+
+    /// Reports insertions, head selection and pruning to `metrics` instead of discarding them.
+    pub fn with_metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = Box::new(metrics);
+        self
+    }
+
+    /// Returns the hash of the root of the tree.
+    pub fn root(&self) -> H {
+        self.root
+    }
+
+    /// Returns the justified checkpoint, see `Tree::update_justified`.
+    pub fn justified(&self) -> Checkpoint<H> {
+        self.justified
+    }
+
+    /// Returns the finalized checkpoint, see `Tree::update_finalized`.
+    pub fn finalized(&self) -> Checkpoint<H> {
+        self.finalized
+    }
+
+    /// Returns the height of `hash`, or `None` if it is not a node in the tree.
+    ///
+    /// The height of a node is the index of its slot among the distinct slots of all nodes, so
+    /// the root is at height `0`. Unlike a slot, a height changes as nodes at earlier slots are
+    /// added or pruned.
+    pub fn height_of(&self, hash: H) -> Option<Height> {
+        self.nodes.get(&hash).map(|node| node.height)
+    }
+
+    /// Returns the score of `hash`, the total weight of the votes counted for it and its
+    /// descendants, or `None` if it is not a node in the tree.
+    pub fn score_of(&self, hash: H) -> Option<u64> {
+        self.nodes.get(&hash).map(|node| node.score)
+    }
+
+    /// Returns the parent of `hash` in the tree, i.e., its most recent ancestor that is a node, or
+    /// `None` if it is not a node in the tree. The root has no parent, so gives `Some(None)`.
+    pub fn parent_of(&self, hash: H) -> Option<Option<H>> {
+        self.nodes.get(&hash).map(|node| node.parent_hash)
+    }
+
+    /// Returns the children of `hash` in the tree, sorted by hash, or `None` if it is not a node
+    /// in the tree.
+    pub fn children_of(&self, hash: H) -> Option<&[H]> {
+        self.nodes.get(&hash).map(|node| node.children.as_slice())
+    }
+
+    /// Returns the nodes at `height`, in the order they were added, or an empty slice if no node
+    /// is at `height`.
+    pub fn blocks_at_height(&self, height: Height) -> &[H] {
+        self.blocks_at_height
+            .get(&height)
+            .map_or(&[], |blocks| blocks.as_slice())
+    }
+
+    /// Returns the number of nodes at `height`, i.e., how many competing blocks share its slot.
+    pub fn height_fanout(&self, height: Height) -> usize {
+        self.blocks_at_height(height).len()
+    }
+
+    /// Returns `true` if `hash` is a node in the tree.
+    pub fn contains_block(&self, hash: &H) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    /// Returns the number of nodes in the tree, including the root.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if the tree has no nodes.
+    ///
+    /// A tree always holds its root, so this is only `true` for a corrupt tree.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns an estimate of the heap memory held by the nodes, the store and the height
+    /// structures of the tree, in bytes, e.g., to alarm when the tree grows unexpectedly.
+    ///
+    /// Collections are counted by their capacity, so the estimate grows linearly with the number
+    /// of nodes and blocks. The overhead of the hash maps and of the attestation collections is
+    /// not counted. Collections shared with a snapshot are counted in full.
+    pub fn approx_memory_bytes(&self) -> usize {
+        use std::mem::size_of;
+
+        let nodes = self.nodes.capacity() * size_of::<(H, Node<H>)>()
+            + self
+                .nodes
+                .values()
+                .map(|node| node.children.capacity() * size_of::<H>())
+                .sum::<usize>();
+        let store = self.store.approx_memory_bytes();
+        let heights = self.slots_at_height.0.capacity() * size_of::<Slot>()
+            + self.blocks_at_height.capacity() * size_of::<(Height, Vec<H>)>()
+            + self
+                .blocks_at_height
+                .values()
+                .map(|blocks| blocks.capacity() * size_of::<H>())
+                .sum::<usize>();
+
+        nodes + store + heights
+    }
+
+    /// Returns every node without children, sorted by hash.
+    ///
+    /// These are the candidates for the head. In a healthy network there is usually only one; more
+    /// indicate competing forks that have not yet been pruned.
+    pub fn leaves(&self) -> Vec<H> {
+        let mut leaves = self
+            .nodes
+            .iter()
+            .filter(|(_hash, node)| node.does_not_have_children())
+            .map(|(hash, _node)| *hash)
+            .collect::<Vec<_>>();
+        leaves.sort();
+        leaves
+    }
+
+    /// Returns every leaf (see `Tree::leaves`) with the score of its chain, from the highest score
+    /// to the lowest, e.g., to see the runners-up to the head during a contentious fork.
+    ///
+    /// The score of a chain is the total weight of the votes counted for the leaf and for each of
+    /// its ancestors in the tree. The weight counted for a node itself is its `score` less the
+    /// scores of its children. Leaves with the same score are ordered by descending hash.
+    pub fn head_candidates(&self) -> Vec<(H, u64)> {
+        let mut chain_scores = HashMap::new();
+        let mut candidates = vec![];
+        for hash in std::iter::once(self.root).chain(self.descendants(self.root)) {
+            let node = match self.nodes.get(&hash) {
+                Some(node) => node,
+                None => continue,
+            };
+            // Every node comes after its parent, so the parent's chain score is known.
+            let above = node
+                .parent_hash
+                .and_then(|parent| chain_scores.get(&parent).copied())
+                .unwrap_or(0);
+            let chain_score = self.own_weight(node).saturating_add(above);
+            chain_scores.insert(hash, chain_score);
+            if node.does_not_have_children() {
+                candidates.push((hash, chain_score));
+            }
+        }
+
+        candidates.sort_by_key(|&(hash, score)| std::cmp::Reverse((score, hash)));
+        candidates
+    }
+
+    /// Returns the number of nodes without children, see `Tree::leaves`.
+    pub fn leaf_count(&self) -> usize {
+        self.nodes
+            .values()
+            .filter(|node| node.does_not_have_children())
+            .count()
+    }
+
+    /// Returns the earliest slot of any node in the tree.
+    ///
+    /// The root is the earliest node, so after `Tree::prune` this is the slot of the finalized
+    /// root. This is O(1), since `slots_at_height` is kept sorted as nodes are added and removed.
+    pub fn min_slot(&self) -> Option<Slot> {
+        self.slots_at_height.first().cloned()
+    }
+
+    /// Returns the latest slot of any node in the tree, in O(1), see `Tree::min_slot`.
+    pub fn max_slot(&self) -> Option<Slot> {
+        self.slots_at_height.last().cloned()
+    }
+
+    /// Returns a copy of the tree and its store, e.g., to roll back a risky import with
+    /// `Tree::restore`.
+    ///
+    /// The collections are shared with the tree until either is mutated, see
+    /// `Tree::snapshot_cow`. The ancestor cache is not included.
+    pub fn snapshot(&self) -> TreeSnapshot<H, N, S> {
+        TreeSnapshot {
+            store: self.store.clone(),
+            nodes: self.nodes.clone(),
+            root: self.root,
+            slots_at_height: self.slots_at_height.clone(),
+            blocks_at_height: self.blocks_at_height.clone(),
+            latest_messages: self.latest_messages.clone(),
+            message_balances: self.message_balances.clone(),
+            equivocating: self.equivocating.clone(),
+            votes_off_tree: self.votes_off_tree.clone(),
+            head: self.head,
+            justified: self.justified,
+            finalized: self.finalized,
+            current_slot: self.current_slot,
+        }
+    }
+
+    /// Returns the tree captured by `snapshot`, without an ancestor cache, metrics or head
+    /// callbacks.
+    pub fn restore(snapshot: TreeSnapshot<H, N, S>) -> Self {
+        Self {
+            store: snapshot.store,
+            nodes: snapshot.nodes,
+            root: snapshot.root,
+            slots_at_height: snapshot.slots_at_height,
+            blocks_at_height: snapshot.blocks_at_height,
+            latest_messages: snapshot.latest_messages,
+            message_balances: snapshot.message_balances,
+            equivocating: snapshot.equivocating,
+            votes_off_tree: snapshot.votes_off_tree,
+            ancestor_cache: None,
+            head: snapshot.head,
+            justified: snapshot.justified,
+            finalized: snapshot.finalized,
+            current_slot: snapshot.current_slot,
+            metrics: Box::new(NoMetrics),
+            head_callbacks: vec![],
+            notified_head: None,
+        }
+    }
+
+    /// Replaces the collections of the tree with those captured by `snapshot`, keeping its
+    /// metrics and head callbacks, e.g., to roll back a failed operation.
+    fn restore_in_place(&mut self, snapshot: TreeSnapshot<H, N, S>) {
+        self.store = snapshot.store;
+        self.nodes = snapshot.nodes;
+        self.root = snapshot.root;
+        self.slots_at_height = snapshot.slots_at_height;
+        self.blocks_at_height = snapshot.blocks_at_height;
+        self.latest_messages = snapshot.latest_messages;
+        self.message_balances = snapshot.message_balances;
+        self.equivocating = snapshot.equivocating;
+        self.votes_off_tree = snapshot.votes_off_tree;
+        self.head = snapshot.head;
+        self.justified = snapshot.justified;
+        self.finalized = snapshot.finalized;
+        self.current_slot = snapshot.current_slot;
+        self.clear_ancestor_cache();
+    }
+
+    /// Returns a read-only copy of the tree that shares its collections and store with this tree,
+    /// e.g., to answer queries from other threads without holding a lock on the live tree.
+    ///
+    /// Taking the snapshot only copies a few fields, however large the tree. While a snapshot is
+    /// alive, the first mutation of each collection of the live tree copies that collection, so
+    /// the snapshot is unaffected. The snapshot has no ancestor cache or metrics.
+    pub fn snapshot_cow(&self) -> Arc<Self> {
+        Arc::new(Self::restore(self.snapshot()))
+    }
 
-                    self.nodes
-                        .insert(common_ancestor.block_hash, common_ancestor);
+    /// Equivalent to `snapshot_cow`, except every collection and the store are copied up front,
+    /// so that nothing is shared with this tree and mutating it never has to copy a collection.
+    ///
+    /// This takes time and memory linear in the size of the tree and store.
+    pub fn snapshot_full(&self) -> Arc<Self> {
+        let mut snapshot = self.snapshot();
+        snapshot.store = Arc::new((*snapshot.store).clone());
+        snapshot.nodes = Arc::new((*snapshot.nodes).clone());
+        snapshot.slots_at_height = Arc::new((*snapshot.slots_at_height).clone());
+        snapshot.blocks_at_height = Arc::new((*snapshot.blocks_at_height).clone());
+        snapshot.latest_messages = Arc::new((*snapshot.latest_messages).clone());
+        snapshot.message_balances = Arc::new((*snapshot.message_balances).clone());
+        snapshot.equivocating = Arc::new((*snapshot.equivocating).clone());
+        snapshot.votes_off_tree = Arc::new((*snapshot.votes_off_tree).clone());
+        Arc::new(Self::restore(snapshot))
+    }
+
+    /// Returns the nodes added, removed and changed in going from this tree to `other`, e.g., to
+    /// compare the tree before and after an operation, or the trees of two clients.
+    ///
+    /// Only the nodes are compared: attestations and the store are not, nor are the roots, except
+    /// as far as they change the nodes.
+    pub fn diff(&self, other: &Self) -> TreeDiff<H> {
+        let mut diff = TreeDiff {
+            added: vec![],
+            removed: vec![],
+            changed: vec![],
+        };
+        if Arc::ptr_eq(&self.nodes, &other.nodes) {
+            return diff;
+        }
+
+        for (hash, node) in self.nodes.iter() {
+            match other.nodes.get(hash) {
+                Some(other_node) if other_node != node => {
+                    diff.changed.push((*hash, node.clone(), other_node.clone()))
                 }
+                Some(_) => {}
+                None => diff.removed.push(*hash),
             }
         }
+        diff.added = other
+            .nodes
+            .keys()
+            .filter(|hash| !self.nodes.contains_key(hash))
+            .copied()
+            .collect();
 
-        self.nodes.insert(hash, node);
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort_by_key(|(hash, _, _)| *hash);
+        diff
+    }
 
-        Some(())
+    /// Returns the serializable state of the tree.
+    #[cfg(feature = "serde")]
+    pub fn to_state(&self) -> TreeState<H> {
+        TreeState {
+            nodes: (*self.nodes).clone(),
+            root: self.root,
+            blocks_at_height: (*self.blocks_at_height).clone(),
+            slots_at_height: self.slots_at_height.0.clone(),
+            latest_messages: (*self.latest_messages).clone(),
+            message_balances: (*self.message_balances).clone(),
+            equivocating: (*self.equivocating).clone(),
+            head: Some(self.head),
+            justified: Some(self.justified),
+            finalized: Some(self.finalized),
+            current_slot: self.current_slot,
+        }
     }
 
-    fn find_prev_in_tree(&mut self, hash: Hash256, range: Range<Height>) -> Option<&mut Node> {
-        if range.len() == 0 || range.end > self.slots_at_height.len() {
-            None
+    /// Restores a tree from `state`, using `store` as its block store.
+    ///
+    /// The children of each node are sorted by hash, in case the state was not written by
+    /// `to_state`.
+    #[cfg(feature = "serde")]
+    pub fn from_state(state: TreeState<H>, store: S) -> Self {
+        let mut nodes = state.nodes;
+        for node in nodes.values_mut() {
+            node.children.sort();
+            node.children.dedup();
+        }
+
+        let mut tree = Self {
+            store: Arc::new(store),
+            nodes: Arc::new(nodes),
+            root: state.root,
+            slots_at_height: Arc::new(state.slots_at_height.into_iter().collect()),
+            blocks_at_height: Arc::new(state.blocks_at_height),
+            latest_messages: Arc::new(state.latest_messages),
+            message_balances: Arc::new(state.message_balances),
+            equivocating: Arc::new(state.equivocating),
+            votes_off_tree: Arc::default(),
+            ancestor_cache: None,
+            head: state.head.unwrap_or(state.root),
+            justified: state.justified.unwrap_or(Checkpoint {
+                root: state.root,
+                epoch: 0,
+            }),
+            finalized: state.finalized.unwrap_or(Checkpoint {
+                root: state.root,
+                epoch: 0,
+            }),
+            current_slot: state.current_slot,
+            metrics: Box::new(NoMetrics),
+            head_callbacks: vec![],
+            notified_head: None,
+        };
+        // States saved before `Node::best_descendant` existed have no best descendants.
+        tree.update_all_best_descendants();
+        tree.index_votes_off_tree();
+        tree
+    }
+
+    /// Adds the block `hash` from the store to the tree, beneath its most recent ancestor that is
+    /// already in the tree, and returns where it was placed.
+    ///
+    /// Adding a block that is already in the tree does nothing and returns its current placement,
+    /// so that blocks may be imported more than once (e.g., when received from several peers).
+    /// Returns
+    /// `Error::UnknownBlock` if the block or its parent is not in the store, `Error::Cycle` if the
+    /// store claims the block is its own ancestor, and `Error::NonMonotonicSlot` if the slot of
+    /// the block is not greater than the slot of its parent.
+    pub fn add_node(&mut self, hash: H, block_hash: H) -> Result<NodePlacement<H>, H> {
+        let start = Instant::now();
+        let result = self.insert_node(hash, block_hash, None);
+        self.metrics.on_add_node(start.elapsed());
+        result
+    }
+
+    /// Adds `hash` to the tree as `add_node` does. If `known_prev` is given, it must be the most
+    /// recent ancestor of `hash` in the tree, which is then not searched for.
+    fn insert_node(
+        &mut self,
+        hash: H,
+        block_hash: H,
+        known_prev: Option<H>,
+    ) -> Result<NodePlacement<H>, H> {
+        if self.nodes.contains_key(&hash) {
+            return self.placement_of(hash);
+        }
+
+        let block = self.get_block(hash)?;
+        let slot = block.slot;
+        let parent = block.ancestor_skip_list[0];
+
+        let parent_slot = self.get_block(parent)?.slot;
+        if self.is_ancestor(hash, parent) == Some(true) {
+            return Err(Error::Cycle(hash));
+        }
+        if slot <= parent_slot {
+            return Err(Error::NonMonotonicSlot { slot, parent_slot });
+        }
+
+        let prev_hash = match known_prev {
+            Some(prev_hash) => prev_hash,
+            None => self.find_prev_in_tree(hash, 0..self.slots_at_height.len())?,
+        };
+        let prev_in_tree = self.get_node(prev_hash)?;
+        let old_best_child = prev_in_tree.best_child;
+
+        // Votes for blocks that are not in the tree count towards their most recent ancestor in
+        // the tree. Those counted at `prev_hash` may belong to the new nodes once they are added.
+        let moved_votes = self
+            .votes_off_tree
+            .get(&prev_hash)
+            .into_iter()
+            .flatten()
+            .filter_map(|validator| {
+                let (block, _slot) = self.latest_messages.get(validator)?;
+                Some((*validator, *block))
+                    .filter(|_| self.find_node_for_block(*block) == Ok(prev_hash))
+            })
+            .collect::<Vec<_>>();
+
+        let mut node = Node {
+            parent_hash: Some(prev_hash),
+            ..Node::new(block_hash)
+        };
+
+        if prev_in_tree.does_not_have_children() {
+            self.get_mut_node(prev_hash)?.add_child(hash);
         } else {
-            let mid_height = range.len() / 2;
-            let mid_slot = self.slot_at_height(mid_height)?;
-            let mid_ancestor = self.find_ancestor_at_slot(hash, mid_slot)?;
+            let mut descendant = None;
+            let mut common_ancestor = None;
 
-            if self.exists_above_height(hash, mid_height)? {
-                if self.exists_between_heights(hash, mid_height..mid_height + 1)? {
-                    self.nodes.get_mut(&mid_ancestor)
-                } else {
-                    self.find_prev_in_tree(hash, mid_height..range.end)
+            for &child_hash in &prev_in_tree.children {
+                if self.find_ancestor_at_slot(child_hash, slot)? == Some(hash) {
+                    descendant = Some(child_hash);
+                    break;
+                }
+
+                let ancestor_hash = self
+                    .find_least_common_ancestor(hash, child_hash)?
+                    .ok_or(Error::InconsistentStore)?;
+                if ancestor_hash != prev_hash {
+                    common_ancestor = Some((child_hash, ancestor_hash));
+                    break;
                 }
+            }
+
+            if let Some(child_hash) = descendant {
+                // The new node sits between `prev_in_tree` and one of its children.
+                let child = self.get_mut_node(child_hash)?;
+                child.parent_hash = Some(hash);
+                node.score = child.score;
+                node.add_child(child_hash);
+
+                self.get_mut_node(prev_hash)?
+                    .replace_child(child_hash, hash);
+            } else if let Some((child_hash, ancestor_hash)) = common_ancestor {
+                let ancestor_slot = self.get_block(ancestor_hash)?.slot;
+                let mut common_ancestor = Node {
+                    parent_hash: Some(prev_hash),
+                    score: self.get_node(child_hash)?.score,
+                    ..Node::new(ancestor_hash)
+                };
+                common_ancestor.add_child(child_hash);
+                common_ancestor.add_child(hash);
+                node.parent_hash = Some(ancestor_hash);
+
+                self.get_mut_node(child_hash)?.parent_hash = Some(ancestor_hash);
+                self.get_mut_node(prev_hash)?
+                    .replace_child(child_hash, ancestor_hash);
+
+                Arc::make_mut(&mut self.nodes).insert(ancestor_hash, common_ancestor);
+                self.register_height(ancestor_hash, ancestor_slot);
             } else {
-                self.find_prev_in_tree(hash, range.start..mid_height)
+                self.get_mut_node(prev_hash)?.add_child(hash);
             }
         }
+
+        Arc::make_mut(&mut self.nodes).insert(hash, node);
+        self.register_height(hash, slot);
+        self.move_votes_below(prev_hash, moved_votes)?;
+        self.update_best_descendants_after_insert(hash, prev_hash, old_best_child)?;
+        self.placement_of(hash)
     }
 
-    fn exists_above_height(&self, hash: Hash256, height: Height) -> Option<bool> {
-        let ancestor_at_height = self.find_ancestor_at_height(hash, height)?;
-        let blocks_at_height = self.blocks_at_height.get(&height)?;
+    /// Moves the weight of each of `votes` (pairs of validator and block) that counted towards
+    /// `prev_hash` to the node that it counts towards now, after nodes are added below
+    /// `prev_hash`. Each new node gains the weight and so does each node between it and
+    /// `prev_hash`, while `prev_hash` and its ancestors keep theirs.
+    ///
+    /// `votes` must be every vote of `votes_off_tree` at `prev_hash` that still counts there, as
+    /// the entry is rebuilt from them.
+    fn move_votes_below(&mut self, prev_hash: H, votes: Vec<(ValidatorIndex, H)>) -> Result<(), H> {
+        if self.votes_off_tree.contains_key(&prev_hash) {
+            Arc::make_mut(&mut self.votes_off_tree).remove(&prev_hash);
+        }
+
+        let mut moved = HashMap::<H, u64>::new();
+        for (validator, block) in votes {
+            let node = self.find_node_for_block(block)?;
+            self.index_vote(validator, block, node);
+            if node != prev_hash {
+                let weight = moved.entry(node).or_default();
+                *weight = weight.saturating_add(self.message_balance(validator));
+            }
+        }
 
-        Some(blocks_at_height.contains(&ancestor_at_height))
+        for (mut hash, weight) in moved {
+            while hash != prev_hash {
+                let node = self.get_mut_node(hash)?;
+                node.score = node
+                    .score
+                    .checked_add(weight)
+                    .ok_or(Error::ScoreOverflow(hash))?;
+                hash = node.parent_hash.ok_or(Error::InconsistentStore)?;
+            }
+        }
+        Ok(())
     }
 
-    fn exists_between_heights(&self, hash: Hash256, range: Range<Height>) -> Option<bool> {
-        let low_blocks = self.blocks_at_height.get(&range.start)?;
-        let high_blocks = self.blocks_at_height.get(&range.end)?;
+    /// Records in `votes_off_tree` that the latest message of `validator`, for `block`, counts
+    /// towards `node`, unless `block` is itself the node.
+    fn index_vote(&mut self, validator: ValidatorIndex, block: H, node: H) {
+        if block != node {
+            Arc::make_mut(&mut self.votes_off_tree)
+                .entry(node)
+                .or_default()
+                .insert(validator);
+        }
+    }
 
-        let low_ancestor = self.find_ancestor_at_height(hash, range.start)?;
-        let high_ancestor = self.find_ancestor_at_height(hash, range.end)?;
+    /// Rebuilds `votes_off_tree` from the latest messages, e.g., after nodes are removed that
+    /// votes counted towards directly.
+    fn index_votes_off_tree(&mut self) {
+        let mut votes_off_tree = HashMap::<H, HashSet<ValidatorIndex>>::new();
+        for (&validator, (block, _slot)) in self.latest_messages.iter() {
+            match self.find_node_for_block(*block) {
+                Ok(node) if node != *block => {
+                    votes_off_tree.entry(node).or_default().insert(validator);
+                }
+                _ => {}
+            }
+        }
+        self.votes_off_tree = Arc::new(votes_off_tree);
+    }
 
-        Some(low_blocks.contains(&low_ancestor) && !high_blocks.contains(&high_ancestor))
+    /// Returns the weight that `node` holds in its own right: its score, less the scores of its
+    /// children.
+    fn own_weight(&self, node: &Node<H>) -> u64 {
+        let children = node
+            .children
+            .iter()
+            .filter_map(|child| self.nodes.get(child))
+            .fold(0, |total: u64, child| total.saturating_add(child.score));
+        node.score.saturating_sub(children)
     }
 
-    fn find_ancestor_at_height(&self, child: Hash256, height: Height) -> Option<Hash256> {
-        self.find_ancestor_at_slot(child, self.slot_at_height(height)?)
+    fn placement_of(&self, hash: H) -> Result<NodePlacement<H>, H> {
+        let node = self.get_node(hash)?;
+        Ok(NodePlacement {
+            height: node.height,
+            parent_hash: node.parent_hash,
+        })
     }
 
-    fn find_ancestor_at_slot(&self, child: Hash256, slot: Slot) -> Option<Hash256> {
-        get_ancestor_hash_at_slot(slot, child, &self.store)
+    /// Imports `block` into the store and adds it to the tree.
+    ///
+    /// The skip list of `block` is rebuilt from its parent, so a block from `Block::with_parent`
+    /// may be given. A block that is already in the store with the same slot and parent is not
+    /// imported again, only added to the tree. Returns an error, leaving the store and tree
+    /// unchanged, if the parent is not in the store, if the slot of `block` is not greater than
+    /// the slot of its parent, if `add_node` fails, or `Error::DuplicateBlock` if the store
+    /// holds a different block under `hash`.
+    pub fn on_block(&mut self, hash: H, block: Block<H, N>) -> Result<(), H> {
+        let (slot, parent) = (block.slot, block.ancestor_skip_list[0]);
+        let imported = match self.store.get(&hash) {
+            Some(stored) if stored.slot == slot && stored.ancestor_skip_list[0] == parent => false,
+            Some(_) => return Err(Error::DuplicateBlock(hash)),
+            None => {
+                let block = Block::new(slot, parent, &*self.store)?;
+                Arc::make_mut(&mut self.store).insert_block(hash, block)?;
+                true
+            }
+        };
+
+        self.add_node(hash, hash).inspect_err(|_| {
+            if imported {
+                Arc::make_mut(&mut self.store).remove(&hash);
+                self.clear_ancestor_cache();
+            }
+        })?;
+        self.notify_head_change();
+
+        Ok(())
     }
 
-    fn find_least_common_ancestor(&self, a: Hash256, b: Hash256) -> Option<Hash256> {
-        find_least_common_ancestor(a, b, &self.store)
+    /// Adds each `(hash, block_hash)` pair in `blocks` to the tree, with the same outcome as
+    /// calling `add_node` for each block in order of increasing slot, e.g., to import many blocks
+    /// at once during sync.
+    ///
+    /// Work is shared between the blocks in two ways. Every new slot is added to
+    /// `slots_at_height` up front, so existing heights are shifted at most once rather than once
+    /// per slot that lands below them. And a block whose parent was added earlier in the batch is
+    /// placed beneath it directly, without searching the heights for its previous node.
+    ///
+    /// Returns an error without modifying the tree if any block is not in the store. If inserting
+    /// a block fails, the blocks with lower slots remain in the tree.
+    pub fn add_nodes(&mut self, blocks: impl IntoIterator<Item = (H, H)>) -> Result<(), H> {
+        let mut blocks = blocks
+            .into_iter()
+            .map(|(hash, block_hash)| {
+                let block = self.get_block(hash)?;
+                Ok((block.slot, hash, block_hash, block.ancestor_skip_list[0]))
+            })
+            .collect::<Result<Vec<_>, H>>()?;
+        blocks.sort_by_key(|(slot, _hash, _block_hash, _parent)| *slot);
+
+        let new_slots = self.register_slots(blocks.iter().map(|(slot, ..)| *slot));
+        let mut added = HashSet::new();
+        let mut result = Ok(());
+        for (_slot, hash, block_hash, parent) in blocks {
+            let start = Instant::now();
+            let known_prev = Some(parent).filter(|parent| added.contains(parent));
+            result = self.insert_node(hash, block_hash, known_prev).map(|_| ());
+            self.metrics.on_add_node(start.elapsed());
+            if result.is_err() {
+                break;
+            }
+            added.insert(hash);
+        }
+
+        // Slots whose blocks were already in the tree, or were not added, are left empty.
+        let empty = new_slots.iter().any(|slot| {
+            let height = self.slots_at_height.index_of(slot);
+            height.is_none_or(|height| self.blocks_at_height.get(&height).is_none_or(Vec::is_empty))
+        });
+        if empty {
+            self.retain_heights();
+        }
+        result
     }
 
-    fn slot_at_height(&self, height: Height) -> Option<Slot> {
-        self.slots_at_height.nth(height).cloned()
+    /// Rebuilds the score of every node from the latest messages, so that each node's `score` is
+    /// the total balance of the validators whose latest message is in its subtree.
+    ///
+    /// As in `process_attestation`, a message counts towards its block if it is in the tree and
+    /// otherwise towards the block's most recent ancestor in the tree. Messages for blocks that no
+    /// longer descend from the root are ignored. Weight added directly with `apply_score_change`
+    /// is discarded.
+    pub fn recompute_scores(&mut self) {
+        let weights = self.latest_message_weights();
+        let scores = self.subtree_scores(self.root, &weights);
+        self.set_scores(scores);
     }
-}
 
-pub struct Block {
-    pub slot: Slot,
-    ancestor_skip_list: [Hash256; SKIP_LIST_LEN],
-}
+    /// Equivalent to `recompute_scores`, except the subtree of each child of the root is computed
+    /// in parallel.
+    #[cfg(feature = "rayon")]
+    pub fn recompute_scores_parallel(&mut self)
+    where
+        H: Send + Sync,
+        S: Send + Sync,
+    {
+        use rayon::prelude::*;
 
-fn get_ancestor_hash_at_slot(slot: Slot, start: Hash256, store: &Store) -> Option<Hash256> {
-    let mut block = store.get(&start)?;
+        let weights = self.latest_message_weights();
+        let children = self
+            .nodes
+            .get(&self.root)
+            .map(|node| node.children.clone())
+            .unwrap_or_default();
+        let subtrees = children
+            .par_iter()
+            .map(|child| self.subtree_scores(*child, &weights))
+            .collect::<Vec<_>>();
 
-    loop {
-        if slot >= block.slot {
-            break None;
-        } else {
-            let delta = block.slot - slot;
+        let mut scores = HashMap::new();
+        let mut root_score = weights.get(&self.root).copied().unwrap_or(0);
+        for (child, subtree) in children.iter().zip(subtrees) {
+            root_score = root_score.saturating_add(subtree[child]);
+            scores.extend(subtree);
+        }
+        scores.insert(self.root, root_score);
 
-            if delta > SKIP_LIST_LEN as u64 {
-                block = store.get(&block.ancestor_skip_list[SKIP_LIST_LEN - 1])?;
-            } else if delta.is_power_of_two() {
-                break Some(block.ancestor_skip_list[delta.trailing_zeros() as usize]);
-            } else {
-                let i = delta.next_power_of_two() - 1;
-                block = store.get(&block.ancestor_skip_list[i as usize])?;
+        self.set_scores(scores);
+    }
+
+    /// Returns the total balance of the latest messages that count towards each node.
+    fn latest_message_weights(&self) -> HashMap<H, u64> {
+        let mut weights = HashMap::<H, u64>::new();
+        for (&validator, (block, _slot)) in self.latest_messages.iter() {
+            if let Ok(node) = self.find_node_for_block(*block) {
+                let weight = weights.entry(node).or_default();
+                *weight = weight.saturating_add(self.message_balance(validator));
             }
         }
+        weights
     }
-}
 
-fn find_least_common_ancestor(a_root: Hash256, b_root: Hash256, store: &Store) -> Option<Hash256> {
-    let mut a = store.get(&a_root)?;
-    let mut b = store.get(&b_root)?;
+    /// Returns the total of `weights` over the subtree of each node in the subtree of `root`.
+    fn subtree_scores(&self, root: H, weights: &HashMap<H, u64>) -> HashMap<H, u64> {
+        let mut order = vec![root];
+        order.extend(self.descendants(root));
 
-    if a.slot > b.slot {
-        a = store.get(&get_ancestor_hash_at_slot(b.slot, a_root, store)?)?;
-    } else if b.slot > a.slot {
-        b = store.get(&get_ancestor_hash_at_slot(a.slot, b_root, store)?)?;
+        // Every node comes after its parent, so visiting in reverse sees children first.
+        let mut scores = HashMap::with_capacity(order.len());
+        for hash in order.into_iter().rev() {
+            let own = weights.get(&hash).copied().unwrap_or(0);
+            let score = self.nodes[&hash]
+                .children
+                .iter()
+                .filter_map(|child| scores.get(child))
+                .fold(own, |total: u64, score| total.saturating_add(*score));
+            scores.insert(hash, score);
+        }
+        scores
     }
 
-    loop {
-        if a.ancestor_skip_list[0] == b.ancestor_skip_list[0] {
-            break Some(a.ancestor_skip_list[0]);
-        } else if a.slot == 0 || b.slot == 0 {
-            break None;
-        } else {
-            a = store.get(&a.ancestor_skip_list[0])?;
-            b = store.get(&b.ancestor_skip_list[0])?;
+    fn set_scores(&mut self, scores: HashMap<H, u64>) {
+        for (hash, node) in Arc::make_mut(&mut self.nodes).iter_mut() {
+            node.score = scores.get(hash).copied().unwrap_or(0);
         }
+        self.update_all_best_descendants();
     }
-}
 
-pub type Store = HashMap<Hash256, Block>;
+    /// Adds `delta` to the score of `block` and every one of its ancestors in the tree, so that
+    /// each node's `score` is the total weight of its subtree.
+    ///
+    /// A negative `delta` removes weight (e.g., when a validator moves its vote). Returns an error
+    /// if `block` is not in the tree, or `Error::ScoreUnderflow` (or `Error::ScoreOverflow`) if
+    /// any score would leave the range of `u64`, in which case no score is changed.
+    pub fn apply_score_change(&mut self, block: H, delta: i64) -> Result<(), H> {
+        let mut scores = vec![];
+        let mut next = Some(block);
+        while let Some(hash) = next {
+            scores.push((hash, self.adjusted_score(hash, delta)?));
+            next = self.get_node(hash)?.parent_hash;
+        }
 
-pub struct SortedList<K>(BTreeMap<K, ()>);
+        for (hash, score) in scores {
+            self.get_mut_node(hash)?.score = score;
+        }
 
-impl<K: Ord> SortedList<K> {
-    pub fn new() -> Self {
-        SortedList(BTreeMap::new())
+        self.update_best_descendants(block)
     }
 
-    pub fn insert(&mut self, key: K) {
-        self.0.insert(key, ());
+    /// Recomputes `Node::best_child` and `Node::best_descendant` of `hash` and each of its
+    /// ancestors, from the bottom up, after the scores or children below them change.
+    ///
+    /// Only the nodes on this path can be affected, and each depends only on its children, so
+    /// this costs no more than the walk that propagated the change.
+    fn update_best_descendants(&mut self, hash: H) -> Result<(), H> {
+        let mut next = Some(hash);
+        while let Some(hash) = next {
+            self.update_best_child(hash)?;
+            next = self.get_node(hash)?.parent_hash;
+        }
+        Ok(())
     }
 
-    pub fn len(&self) -> usize {
-        self.0.len()
-    }
+    /// Updates `Node::best_child` and `Node::best_descendant` after `hash` is added to the tree
+    /// beneath `prev_hash`, whose best child was `old_best_child`. No score changes.
+    ///
+    /// Only `prev_hash` and the nodes below it have new children. The best descendant of an
+    /// ancestor that leads to `prev_hash` is on its path to `prev_hash` or below `prev_hash`, so
+    /// it stays on the best path unless the best child of `prev_hash` is replaced. Then the
+    /// ancestors are pointed at the best descendant of `prev_hash` instead. A block that extends
+    /// a leaf (or loses to its siblings) changes nothing above `prev_hash`.
+    fn update_best_descendants_after_insert(
+        &mut self,
+        hash: H,
+        prev_hash: H,
+        old_best_child: Option<H>,
+    ) -> Result<(), H> {
+        // If the block forks from a child of `prev_hash`, their common ancestor is also new.
+        self.update_best_child(hash)?;
+        if let Some(parent) = self.get_node(hash)?.parent_hash.filter(|p| *p != prev_hash) {
+            self.update_best_child(parent)?;
+        }
+        self.update_best_child(prev_hash)?;
 
-    pub fn nth(&self, n: usize) -> Option<&K> {
-        self.0.iter().nth(n).and_then(|(k, _v)| Some(k))
+        let prev_in_tree = self.get_node(prev_hash)?;
+        if old_best_child.is_none() || prev_in_tree.best_child == old_best_child {
+            return Ok(());
+        }
+
+        let best_descendant = prev_in_tree.best_descendant;
+        let (mut below, mut next) = (prev_hash, prev_in_tree.parent_hash);
+        while let Some(hash) = next {
+            let node = self.get_mut_node(hash)?;
+            if node.best_child != Some(below) {
+                break;
+            }
+            node.best_descendant = best_descendant;
+            below = hash;
+            next = node.parent_hash;
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use ethereum_types::H256 as Hash256;
+    /// Recomputes `Node::best_child` and `Node::best_descendant` of every node, e.g., after every
+    /// score is replaced.
+    fn update_all_best_descendants(&mut self) {
+        let mut order = vec![self.root];
+        order.extend(self.descendants(self.root));
 
-    #[test]
+        // Every node comes after its parent, so visiting in reverse sees children first.
+        for hash in order.into_iter().rev() {
+            let _ = self.update_best_child(hash);
+        }
+    }
+
+    /// Sets `Node::best_child` of `hash` to its child with the greatest score (or, on a tie, as
+    /// in `find_head`), and `Node::best_descendant` to the best descendant of that child, or to
+    /// the child if it has no children.
+    fn update_best_child(&mut self, hash: H) -> Result<(), H> {
+        let best_child = self.best_child_by(hash, |child| Some(self.nodes.get(child)?.score));
+        let best_descendant = match best_child {
+            Some(child) => Some(self.get_node(child)?.best_descendant.unwrap_or(child)),
+            None => None,
+        };
+
+        let node = self.get_mut_node(hash)?;
+        node.best_child = best_child;
+        node.best_descendant = best_descendant;
+        Ok(())
+    }
+
+    /// Returns the score of the node `hash` after adding `delta`, without changing it.
+    ///
+    /// Every change to a score is checked here, so that removing more weight than was added
+    /// (e.g., by counting the removal of a vote twice) is an error rather than a wrapped score.
+    fn adjusted_score(&self, hash: H, delta: i64) -> Result<u64, H> {
+        let score = self.get_node(hash)?.score;
+        if delta < 0 {
+            score
+                .checked_sub(delta.unsigned_abs())
+                .ok_or(Error::ScoreUnderflow(hash))
+        } else {
+            score
+                .checked_add(delta as u64)
+                .ok_or(Error::ScoreOverflow(hash))
+        }
+    }
+
+    /// Records an attestation from `validator` to `block` at `slot`, moving the validator's
+    /// weight of `1` from its previous latest message (if any) to `block`.
+    ///
+    /// The weight is applied to `block` if it is in the tree, otherwise to its most recent
+    /// ancestor in the tree. Attestations with a slot that is not greater than the validator's
+    /// latest message are ignored. An attestation to a different block at the same slot as the
+    /// latest message is an equivocation: the validator's weight is removed and all of its later
+    /// attestations are ignored, see `Tree::is_equivocating`.
+    ///
+    /// Returns an error if `block` is not in the store or does not descend from the root.
+    pub fn process_attestation(
+        &mut self,
+        validator: ValidatorIndex,
+        block: H,
+        slot: Slot,
+    ) -> Result<(), H> {
+        self.process_weighted_attestation(validator, block, slot, 1)
+    }
+
+    /// Equivalent to `process_attestation`, except the validator's weight is `balance`.
+    ///
+    /// The balance counted for the validator's previous latest message is removed from the old
+    /// block's chain and `balance` is added to the new one, so a balance that has changed since
+    /// the previous attestation is accounted for. If either change fails, neither is applied and
+    /// the latest message is not updated.
+    pub fn process_weighted_attestation(
+        &mut self,
+        validator: ValidatorIndex,
+        block: H,
+        slot: Slot,
+        balance: u64,
+    ) -> Result<(), H> {
+        if !self.screen_attestation(validator, block, slot, true)? {
+            return Ok(());
+        }
+
+        let node = self.find_node_for_block(block)?;
+        let delta = i64::try_from(balance).map_err(|_| Error::ScoreOverflow(node))?;
+
+        // The previous vote may have been pruned from the tree along with its weight.
+        let old_node = self
+            .latest_messages
+            .get(&validator)
+            .and_then(|(old_block, _slot)| self.find_node_for_block(*old_block).ok());
+        let old_delta = i64::try_from(self.message_balance(validator)).unwrap_or(i64::MAX);
+
+        if let Some(old_node) = old_node {
+            self.apply_score_change(old_node, -old_delta)?;
+        }
+        if let Err(e) = self.apply_score_change(node, delta) {
+            if let Some(old_node) = old_node {
+                self.apply_score_change(old_node, old_delta)?;
+            }
+            return Err(e);
+        }
+
+        Arc::make_mut(&mut self.latest_messages).insert(validator, (block, slot));
+        Arc::make_mut(&mut self.message_balances).insert(validator, balance);
+        self.index_vote(validator, block, node);
+        Ok(())
+    }
+
+    /// Equivalent to `process_weighted_attestation` for every validator `i` whose bit is set in
+    /// `participants`, with a balance of `balances[i]`, but cheaper for large aggregates.
+    ///
+    /// `participants` is a bitfield with the bit of validator `i` at bit `i % 8` of byte `i / 8`,
+    /// i.e., least significant bit first, as in SSZ bitfields. A bitlist must have its length bit
+    /// cleared first, as any bit set beyond the last validator has no balance.
+    ///
+    /// The changes of weight of all participants are summed per node first, so that each node
+    /// whose weight changes is updated once, rather than the ancestors of `target` being updated
+    /// once per participant. A participant whose latest message already counts its balance
+    /// towards the node of `target` contributes nothing. Equivocations are handled as by
+    /// `process_attestation`.
+    ///
+    /// Returns an error, without changing any score or latest message, if `target` is not in the
+    /// store or does not descend from the root, if a participant has no balance, or if a score
+    /// would underflow or overflow.
+    pub fn process_aggregate(
+        &mut self,
+        participants: &[u8],
+        target: H,
+        slot: Slot,
+        balances: &[u64],
+    ) -> Result<(), H> {
+        let node = self.find_node_for_block(target)?;
+
+        let mut deltas = HashMap::<H, i128>::new();
+        let mut messages = Vec::new();
+        let mut equivocators = Vec::new();
+        let indices = participants
+            .iter()
+            .enumerate()
+            .flat_map(|(byte_index, byte)| {
+                (0..8)
+                    .filter(move |bit| byte >> bit & 1 == 1)
+                    .map(move |bit| byte_index * 8 + bit)
+            });
+        for i in indices {
+            let validator = i as ValidatorIndex;
+            if self.equivocating.contains(&validator) {
+                continue;
+            }
+            let latest = self.latest_messages.get(&validator).copied();
+            let equivocation =
+                latest.is_some_and(|(block, latest_slot)| slot == latest_slot && block != target);
+            if latest.is_some_and(|(_block, latest_slot)| slot <= latest_slot) && !equivocation {
+                continue;
+            }
+
+            // The previous vote may have been pruned from the tree along with its weight.
+            let old_node =
+                latest.and_then(|(old_block, _slot)| self.find_node_for_block(old_block).ok());
+            if let Some(old_node) = old_node {
+                *deltas.entry(old_node).or_default() -= i128::from(self.message_balance(validator));
+            }
+            if equivocation {
+                equivocators.push(validator);
+                continue;
+            }
+
+            let balance = *balances.get(i).ok_or(Error::MissingBalance(validator))?;
+            *deltas.entry(node).or_default() += i128::from(balance);
+            messages.push((validator, balance));
+        }
+
+        // Removing weight first means no score overflows on the way to its final value.
+        let mut deltas = deltas
+            .into_iter()
+            .filter(|(_hash, delta)| *delta != 0)
+            .map(|(hash, delta)| match i64::try_from(delta) {
+                Ok(delta) => Ok((hash, delta)),
+                Err(_) if delta < 0 => Err(Error::ScoreUnderflow(hash)),
+                Err(_) => Err(Error::ScoreOverflow(hash)),
+            })
+            .collect::<Result<Vec<_>, H>>()?;
+        deltas.sort_by_key(|(_hash, delta)| *delta);
+
+        for (applied, &(hash, delta)) in deltas.iter().enumerate() {
+            if let Err(e) = self.apply_score_change(hash, delta) {
+                for &(hash, delta) in deltas[..applied].iter().rev() {
+                    self.apply_score_change(hash, -delta)?;
+                }
+                return Err(e);
+            }
+        }
+
+        for (validator, balance) in messages {
+            Arc::make_mut(&mut self.latest_messages).insert(validator, (target, slot));
+            Arc::make_mut(&mut self.message_balances).insert(validator, balance);
+            self.index_vote(validator, target, node);
+        }
+        for validator in equivocators {
+            self.forget_latest_message(validator);
+            Arc::make_mut(&mut self.equivocating).insert(validator);
+        }
+        Ok(())
+    }
+
+    /// Validates an attestation from `validator` to `target` at `slot` and records it with
+    /// `process_attestation`.
+    ///
+    /// Unlike `process_attestation`, an invalid attestation is an error rather than a no-op: the
+    /// attestation must not be from a later slot than the current slot (see `Tree::on_tick`), the
+    /// target must be in the store and descend from the root, must not be from a later slot than
+    /// the attestation, and the attestation must be newer than the validator's latest message or
+    /// equivocate with it. Attestations from equivocating validators are ignored, as by
+    /// `process_attestation`.
+    pub fn on_attestation(
+        &mut self,
+        validator: ValidatorIndex,
+        target: H,
+        slot: Slot,
+    ) -> Result<(), H> {
+        if slot > self.current_slot {
+            return Err(Error::FutureSlot {
+                slot,
+                current_slot: self.current_slot,
+            });
+        }
+        let target_slot = self.get_block(target)?.slot;
+        self.find_node_for_block(target)?;
+
+        if target_slot > slot {
+            return Err(Error::FutureTarget { slot, target_slot });
+        }
+        if let Some(&(block, latest_slot)) = self.latest_messages.get(&validator) {
+            if slot < latest_slot || (slot == latest_slot && target == block) {
+                return Err(Error::StaleAttestation { slot, latest_slot });
+            }
+        }
+
+        self.process_attestation(validator, target, slot)?;
+        self.notify_head_change();
+
+        Ok(())
+    }
+
+    /// Advances the clock to `slot`, so that `on_attestation` accepts attestations up to `slot`.
+    ///
+    /// The clock never moves backwards: a `slot` before the current slot is ignored. A new tree
+    /// starts at the slot of its root.
+    pub fn on_tick(&mut self, slot: Slot) {
+        self.current_slot = self.current_slot.max(slot);
+    }
+
+    /// Returns the slot of the clock, see `Tree::on_tick`.
+    pub fn current_slot(&self) -> Slot {
+        self.current_slot
+    }
+
+    /// Records an attestation from `validator` to `block` at `slot` with weight `balance` as the
+    /// validator's latest message, without updating any `Node::score`.
+    ///
+    /// This takes time independent of the depth of the tree, unlike `process_weighted_attestation`
+    /// which adds the weight to every ancestor. Use `find_head_lazy` to find the head from the
+    /// latest messages, or `recompute_scores` to bring the scores up to date before using
+    /// `find_head` or `process_attestation` again, since the latter removes weight that was never
+    /// added. Stale attestations and equivocations are handled and errors are returned as for
+    /// `process_attestation`.
+    pub fn record_attestation(
+        &mut self,
+        validator: ValidatorIndex,
+        block: H,
+        slot: Slot,
+        balance: u64,
+    ) -> Result<(), H> {
+        if !self.screen_attestation(validator, block, slot, false)? {
+            return Ok(());
+        }
+        let node = self.find_node_for_block(block)?;
+
+        Arc::make_mut(&mut self.latest_messages).insert(validator, (block, slot));
+        Arc::make_mut(&mut self.message_balances).insert(validator, balance);
+        self.index_vote(validator, block, node);
+        Ok(())
+    }
+
+    /// Removes the weight of `validator` from the tree and forgets its latest message, e.g., when
+    /// the validator exits or is slashed.
+    ///
+    /// Unlike a vote switch, the balance is not added anywhere else. Does nothing if there is no
+    /// latest message for `validator`. A later attestation from `validator` is counted again, so
+    /// callers must not process attestations from slashed validators.
+    pub fn remove_validator(&mut self, validator: ValidatorIndex) -> Result<(), H> {
+        let block = match self.latest_messages.get(&validator) {
+            Some(&(block, _slot)) => block,
+            None => return Ok(()),
+        };
+
+        // The vote may have been pruned from the tree along with its weight.
+        if let Ok(node) = self.find_node_for_block(block) {
+            let delta = i64::try_from(self.message_balance(validator)).unwrap_or(i64::MAX);
+            self.apply_score_change(node, -delta)?;
+        }
+
+        self.forget_latest_message(validator);
+        Ok(())
+    }
+
+    /// Returns `true` if `validator` attested to two different blocks at the same slot, see
+    /// `process_attestation`.
+    ///
+    /// Only an attestation at the same slot as the latest message can be detected, since earlier
+    /// attestations are not kept. The weight of an equivocating validator is not counted.
+    pub fn is_equivocating(&self, validator: ValidatorIndex) -> bool {
+        self.equivocating.contains(&validator)
+    }
+
+    /// Returns `false` if an attestation from `validator` to `block` at `slot` is to be ignored,
+    /// because the validator is equivocating or the attestation is not newer than its latest
+    /// message.
+    ///
+    /// An attestation to a different block at the same slot as the latest message marks the
+    /// validator as equivocating and forgets its latest message, also removing its weight from
+    /// the scores if `counted`.
+    fn screen_attestation(
+        &mut self,
+        validator: ValidatorIndex,
+        block: H,
+        slot: Slot,
+        counted: bool,
+    ) -> Result<bool, H> {
+        if self.equivocating.contains(&validator) {
+            return Ok(false);
+        }
+        let (latest_block, latest_slot) = match self.latest_messages.get(&validator) {
+            Some(&message) => message,
+            None => return Ok(true),
+        };
+
+        if slot == latest_slot && block != latest_block {
+            if counted {
+                self.remove_validator(validator)?;
+            } else {
+                self.forget_latest_message(validator);
+            }
+            Arc::make_mut(&mut self.equivocating).insert(validator);
+        }
+        Ok(slot > latest_slot)
+    }
+
+    fn forget_latest_message(&mut self, validator: ValidatorIndex) {
+        Arc::make_mut(&mut self.latest_messages).remove(&validator);
+        Arc::make_mut(&mut self.message_balances).remove(&validator);
+    }
+
+    /// Returns the balance counted for the latest message of `validator`.
+    ///
+    /// This is `1` for messages recorded with `process_attestation`, or for states saved before
+    /// balances were recorded.
+    fn message_balance(&self, validator: ValidatorIndex) -> u64 {
+        self.message_balances.get(&validator).copied().unwrap_or(1)
+    }
+
+    /// Returns the block of the most recent attestation processed for `validator`.
+    pub fn latest_message(&self, validator: ValidatorIndex) -> Option<H> {
+        self.latest_messages
+            .get(&validator)
+            .map(|(block, _slot)| *block)
+    }
+
+    /// Returns the block that was canonical at `slot` on the chain of `block`, according to the
+    /// store: the most recent block of the chain at or before `slot`.
+    ///
+    /// If `slot` was skipped, this is the last block before the gap, and `block` itself is
+    /// returned for its own slot and any later slot. Returns `None` if `slot` is before the
+    /// genesis of the chain, or if `block` or one of its ancestors is not in the store.
+    pub fn ancestor_at_slot(&self, block: H, slot: Slot) -> Option<H> {
+        self.find_ancestor_at_slot(block, slot).ok().flatten()
+    }
+
+    /// Returns the block that is canonical at `slot` on the chain of the head, as selected by
+    /// `update_head`, in the sense of `Tree::ancestor_at_slot`.
+    ///
+    /// Returns `None` if `slot` is after the slot of the head or before the slot of the root, or if
+    /// there is no viable head.
+    pub fn canonical_at_slot(&self, slot: Slot) -> Option<H> {
+        let head = self.select_head().ok()?;
+        let head_slot = self.store.get(&head)?.slot;
+        let root_slot = self.store.get(&self.root)?.slot;
+        if slot > head_slot || slot < root_slot {
+            return None;
+        }
+
+        self.ancestor_at_slot(head, slot)
+    }
+
+    /// Returns `true` if `block` is on the chain of the head, as selected by `update_head`, e.g.,
+    /// to decide whether a stored block is canonical.
+    ///
+    /// Blocks before the root are on the chain of every head, so are canonical. Returns `None` if
+    /// `block` is not in the store, or if there is no viable head.
+    pub fn is_canonical(&self, block: H) -> Option<bool> {
+        let head = self.select_head().ok()?;
+        self.is_ancestor(block, head)
+    }
+
+    /// Returns `true` if `ancestor` is on the chain of `descendant`, according to the store.
+    ///
+    /// A block is considered to be its own ancestor. Returns `None` if either block is not in the
+    /// store.
+    pub fn is_ancestor(&self, ancestor: H, descendant: H) -> Option<bool> {
+        let ancestor_slot = self.store.get(&ancestor)?.slot;
+        self.store.get(&descendant)?;
+
+        let hash = self.find_ancestor_at_slot(descendant, ancestor_slot).ok()?;
+        Some(hash == Some(ancestor))
+    }
+
+    /// Returns every block in the store from `descendant` back to `ancestor`, inclusive, starting
+    /// with `descendant`.
+    ///
+    /// Returns `None` if either block is not in the store or `ancestor` is not on the chain of
+    /// `descendant`.
+    pub fn path_to_ancestor(&self, descendant: H, ancestor: H) -> Option<Vec<H>> {
+        if !self.is_ancestor(ancestor, descendant)? {
+            return None;
+        }
+
+        let mut path = vec![descendant];
+        let mut hash = descendant;
+        while hash != ancestor {
+            hash = self.store.get(&hash)?.ancestor_skip_list[0];
+            path.push(hash);
+        }
+
+        Some(path)
+    }
+
+    /// Returns the number of slots from `to_head` back to its common ancestor with `from_head`,
+    /// i.e., how far back a switch from `from_head` to `to_head` re-applies blocks.
+    ///
+    /// Returns `Some(0)` if either head is an ancestor of the other, since switching between them
+    /// is not a reorg. Returns `None` if either head is not in the store or they do not share
+    /// history.
+    pub fn reorg_distance(&self, from_head: H, to_head: H) -> Option<usize> {
+        if self.is_ancestor(from_head, to_head)? || self.is_ancestor(to_head, from_head)? {
+            return Some(0);
+        }
+
+        let ancestor = self.find_least_common_ancestor(from_head, to_head).ok()??;
+        let to_slot = self.store.get(&to_head)?.slot;
+        let ancestor_slot = self.store.get(&ancestor)?.slot;
+        Some((to_slot - ancestor_slot) as usize)
+    }
+
+    /// Finds the head of the tree from the root and records it as the current head, returning
+    /// how it changed since the last call.
+    ///
+    /// Once the justified checkpoint is above the root, the head is found with
+    /// `find_head_filtered`, so it always descends from the justified root. Before the first
+    /// call, the head is taken to be the root. Returns an error if the root or either head is not
+    /// in the store, or if no node descends from the justified root (e.g., it was removed).
+    pub fn update_head(&mut self) -> Result<HeadUpdate<H>, H> {
+        let start = Instant::now();
+        let new_head = self.select_head()?;
+        let duration = start.elapsed();
+        let old_head = std::mem::replace(&mut self.head, new_head);
+
+        let reorg_depth = if self.is_ancestor(old_head, new_head) == Some(true) {
+            None
+        } else {
+            let old_slot = self.get_block(old_head)?.slot;
+            let ancestor = self
+                .find_least_common_ancestor(old_head, new_head)?
+                .ok_or(Error::InconsistentStore)?;
+            let ancestor_slot = self.get_block(ancestor)?.slot;
+            Some((old_slot - ancestor_slot) as usize)
+        };
+        self.metrics.on_find_head(duration, reorg_depth.is_some());
+
+        Ok(HeadUpdate {
+            new_head,
+            old_head,
+            reorg_depth,
+        })
+    }
+
+    /// Returns the head that `update_head` selects, without recording it.
+    fn select_head(&self) -> Result<H, H> {
+        if self.justified.root == self.root {
+            self.find_head(self.root)
+                .ok_or(Error::UnknownBlock(self.root))
+        } else {
+            self.find_head_filtered(self.root, self.justified)
+                .ok_or(Error::UnknownBlock(self.justified.root))
+        }
+    }
+
+    /// Returns the head of the tree, beginning the search at `start`.
+    ///
+    /// Repeatedly descends into the child with the greatest `score` until a node without children
+    /// is reached. When two children have the same `score`, the child whose chain has the greater
+    /// hash (by `Ord`, which for `H256` compares the bytes lexicographically) at its first block
+    /// after the node is chosen. As in the consensus spec, this compares the children of the
+    /// branch point among all blocks, so the head does not depend on which blocks are nodes.
+    ///
+    /// The path is kept up to date in `Node::best_descendant` as votes and blocks arrive, so this
+    /// follows those pointers rather than comparing children. Each block added since the path was
+    /// last updated by a score change can add one more lookup.
+    ///
+    /// Returns `None` if `start` is not in the tree.
+    pub fn find_head(&self, start: H) -> Option<H> {
+        let mut head = start;
+        let mut node = self.nodes.get(&head)?;
+        while let Some(best_descendant) = node.best_descendant {
+            head = best_descendant;
+            node = self.nodes.get(&head)?;
+        }
+        Some(head)
+    }
+
+    /// Equivalent to `find_head`, except a child is only descended into if its `score` is at least
+    /// `min_score`, e.g., so that the head does not flap between forks with almost no weight
+    /// during sync.
+    ///
+    /// If no child of a node meets `min_score`, that node is the head. Ties are broken as in
+    /// `find_head`. Returns `None` if `start` is not in the tree.
+    pub fn find_head_with_threshold(&self, start: H, min_score: u64) -> Option<H> {
+        self.find_head_by(start, |hash| {
+            let score = self.nodes.get(hash)?.score;
+            Some(score).filter(|score| *score >= min_score)
+        })
+    }
+
+    /// Returns the head of the tree, beginning the search at `start` and weighing each subtree
+    /// by the latest messages instead of by `Node::score`.
+    ///
+    /// The weight of every subtree below `start` is summed once per call, which takes time linear
+    /// in the number of nodes and latest messages. This is cheaper than keeping every score up to
+    /// date with `process_attestation` when many votes arrive between head queries (each of which
+    /// costs time linear in the depth of the tree) and is the head to use with
+    /// `record_attestation`. When the head is queried after every vote, `find_head` is cheaper.
+    /// Ties are broken as in `find_head`.
+    ///
+    /// Returns `None` if `start` is not in the tree.
+    pub fn find_head_lazy(&self, start: H) -> Option<H> {
+        self.nodes.get(&start)?;
+        let weights = self.latest_message_weights();
+        let scores = self.subtree_scores(start, &weights);
+
+        self.find_head_by(start, |hash| scores.get(hash).copied())
+    }
+
+    /// Returns the head of the tree, beginning the search at `start` and weighing each subtree
+    /// by `balances` instead of by `Node::score`.
+    ///
+    /// `balances` maps block hashes to the total balance of the validators whose latest message
+    /// is that block. The blocks need not be in the tree; a balance counts towards the most recent
+    /// ancestor in the tree and all of its ancestors. Blocks that are not in the store or do not
+    /// descend from the root are ignored. Ties are broken as in `find_head`.
+    ///
+    /// Returns `None` if `start` is not in the tree.
+    pub fn find_head_weighted(&self, start: H, balances: &HashMap<H, u64>) -> Option<H> {
+        let mut weights = HashMap::<H, u64>::new();
+        for (&block, &balance) in balances {
+            let mut next = self.find_node_for_block(block).ok();
+
+            while let Some(hash) = next {
+                let weight = weights.entry(hash).or_default();
+                *weight = weight.saturating_add(balance);
+                next = self.nodes.get(&hash).and_then(|node| node.parent_hash);
+            }
+        }
+
+        self.find_head_by(start, |hash| {
+            self.nodes.get(hash)?;
+            Some(weights.get(hash).copied().unwrap_or(0))
+        })
+    }
+
+    /// Equivalent to `find_head`, except only nodes whose subtree contains a viable leaf are
+    /// viable, so the head never lies on a fork that abandons the justified checkpoint.
+    ///
+    /// A leaf is viable if it descends from the root of `justified`. Once the leaf is from an
+    /// epoch before the current slot (see `on_tick` and `SLOTS_PER_EPOCH`), its justification is
+    /// as good as realized, so if the leaf has an unrealized justified epoch (see
+    /// `set_unrealized`) it must also be at least the epoch of `justified`. Leaves of the current
+    /// epoch, and leaves without an unrealized justified epoch, are not held to it.
+    ///
+    /// The root of `justified` is a block in the store; it need not be in the tree. Leaves are
+    /// compared with it by their chains in the store, so this takes time linear in the number of
+    /// nodes. `update_head` passes the justified checkpoint of the tree.
+    ///
+    /// Returns `None` if `start` is not in the tree or no leaf below `start` is viable.
+    pub fn find_head_filtered(&self, start: H, justified: Checkpoint<H>) -> Option<H> {
+        let current_epoch = self.current_slot / SLOTS_PER_EPOCH;
+
+        let mut viable = HashSet::new();
+        for (&hash, node) in self.nodes.iter() {
+            if !node.does_not_have_children()
+                || self.is_ancestor(justified.root, hash) != Some(true)
+            {
+                continue;
+            }
+            if let Some(epoch) = node.unrealized_justified_epoch {
+                let leaf_epoch = self.store.get(&hash)?.slot / SLOTS_PER_EPOCH;
+                if leaf_epoch < current_epoch && epoch < justified.epoch {
+                    continue;
+                }
+            }
+
+            let mut next = Some(hash);
+            while let Some(hash) = next {
+                if !viable.insert(hash) {
+                    break;
+                }
+                next = self.nodes.get(&hash).and_then(|node| node.parent_hash);
+            }
+        }
+
+        if !viable.contains(&start) {
+            return None;
+        }
+        self.find_head_by(start, |hash| {
+            let node = self.nodes.get(hash).filter(|_| viable.contains(hash))?;
+            Some(node.score)
+        })
+    }
+
+    /// Returns the head of the tree, beginning the search at `start` and descending into the
+    /// child with the greatest `weight` (or, on a tie, as in `find_head`). Children for which
+    /// `weight` returns `None` are skipped.
+    fn find_head_by(&self, start: H, weight: impl Fn(&H) -> Option<u64>) -> Option<H> {
+        let mut head = start;
+        self.nodes.get(&head)?;
+
+        while let Some(child) = self.best_child_by(head, &weight) {
+            head = child;
+        }
+        Some(head)
+    }
+
+    /// Returns the child of `hash` with the greatest `weight`, or `None` if it has no children
+    /// for which `weight` returns `Some`.
+    ///
+    /// Children with the same weight are compared by their first block after `hash`, found
+    /// only on a tie so that the common case costs no lookups in the store.
+    fn best_child_by(&self, hash: H, weight: impl Fn(&H) -> Option<u64>) -> Option<H> {
+        let mut best = None::<(u64, H)>;
+        let mut tied = vec![];
+        for &child in &self.nodes.get(&hash)?.children {
+            let weight = match weight(&child) {
+                Some(weight) => weight,
+                None => continue,
+            };
+            match best {
+                Some((best_weight, _)) if weight < best_weight => {}
+                Some((best_weight, _)) if weight == best_weight => tied.push(child),
+                _ => {
+                    best = Some((weight, child));
+                    tied.clear();
+                    tied.push(child);
+                }
+            }
+        }
+        if tied.len() <= 1 {
+            return best.map(|(_weight, child)| child);
+        }
+
+        let slot = self.store.get(&hash).map(|block| block.slot);
+        tied.into_iter().max_by_key(|child| {
+            let first = slot
+                .and_then(|slot| get_oldest_ancestor_after_slot(slot, *child, &*self.store).ok());
+            (first.unwrap_or(*child), *child)
+        })
+    }
+
+    /// Removes every node that is not `finalized_root` or one of its descendants and makes
+    /// `finalized_root` the new root of the tree.
+    ///
+    /// If `finalized_root` is in the store but not yet in the tree, it is added before pruning.
+    /// Pruning to the current root is a no-op. Blocks are not removed from the store.
+    ///
+    /// The justified and finalized checkpoints keep their epochs, but a checkpoint whose root is
+    /// pruned moves to `finalized_root`, so that both always refer to blocks in the tree.
+    pub fn prune(&mut self, finalized_root: H) -> Result<(), H> {
+        if finalized_root == self.root {
+            return Ok(());
+        }
+
+        if !self.nodes.contains_key(&finalized_root) {
+            self.add_node(finalized_root, finalized_root)?;
+        }
+
+        let mut keep = self.descendants(finalized_root).collect::<HashSet<_>>();
+        keep.insert(finalized_root);
+
+        let len = self.nodes.len();
+        Arc::make_mut(&mut self.nodes).retain(|hash, _| keep.contains(hash));
+        Arc::make_mut(&mut self.votes_off_tree).retain(|hash, _| keep.contains(hash));
+        self.get_mut_node(finalized_root)?.parent_hash = None;
+        self.root = finalized_root;
+        for checkpoint in [&mut self.justified, &mut self.finalized] {
+            if !keep.contains(&checkpoint.root) {
+                checkpoint.root = finalized_root;
+            }
+        }
+        self.retain_heights();
+        self.clear_ancestor_cache();
+        self.metrics.on_prune(len - self.nodes.len());
+
+        Ok(())
+    }
+
+    /// Records that the votes in the chain of `block` would justify `epoch` if they were counted
+    /// at the end of the epoch, so that `find_head_filtered` can rule out its fork once the epoch
+    /// is over, see `Node::unrealized_justified_epoch`.
+    ///
+    /// Returns `Error::UnknownBlock` if `block` is not in the tree.
+    pub fn set_unrealized(&mut self, block: H, epoch: Epoch) -> Result<(), H> {
+        self.get_mut_node(block)?.unrealized_justified_epoch = Some(epoch);
+        Ok(())
+    }
+
+    /// Makes `checkpoint` the justified checkpoint if its epoch is greater than that of the
+    /// current one, so that `update_head` only selects heads that descend from its root.
+    ///
+    /// If the root is in the store but not yet in the tree, it is added. Checkpoints that are not
+    /// newer are ignored. Returns `Error::NotDescendant` if the root does not descend from the
+    /// finalized root.
+    pub fn update_justified(&mut self, checkpoint: Checkpoint<H>) -> Result<(), H> {
+        if checkpoint.epoch <= self.justified.epoch {
+            return Ok(());
+        }
+        self.get_block(checkpoint.root)?;
+        if self.is_ancestor(self.finalized.root, checkpoint.root) != Some(true) {
+            return Err(Error::NotDescendant(checkpoint.root));
+        }
+        if !self.nodes.contains_key(&checkpoint.root) {
+            self.add_node(checkpoint.root, checkpoint.root)?;
+        }
+
+        self.justified = checkpoint;
+        Ok(())
+    }
+
+    /// Makes `checkpoint` the finalized checkpoint if its epoch is greater than that of the
+    /// current one, and prunes the tree to its root, see `prune`.
+    ///
+    /// If the justified checkpoint is older than `checkpoint`, it becomes `checkpoint` too, as a
+    /// finalized block is also justified. Checkpoints that are not newer are ignored. Returns an
+    /// error, leaving the checkpoints unchanged, if pruning fails.
+    pub fn update_finalized(&mut self, checkpoint: Checkpoint<H>) -> Result<(), H> {
+        if checkpoint.epoch <= self.finalized.epoch {
+            return Ok(());
+        }
+        self.prune(checkpoint.root)?;
+
+        self.finalized = checkpoint;
+        if self.justified.epoch < checkpoint.epoch {
+            self.justified = checkpoint;
+        }
+        Ok(())
+    }
+
+    /// Removes `hash` and all of its descendants from the tree, e.g., when the block is found to
+    /// be invalid.
+    ///
+    /// The score of the removed subtree is subtracted from the remaining ancestors, and the latest
+    /// messages of validators that voted for the subtree are forgotten. Blocks are not removed
+    /// from the store. Returns an error if `hash` is not in the tree or is the root.
+    pub fn remove_node(&mut self, hash: H) -> Result<(), H> {
+        if hash == self.root {
+            return Err(Error::RemoveRoot);
+        }
+        let node = self.get_node(hash)?;
+        let (parent_hash, score) = (node.parent_hash, node.score);
+
+        let mut removed = self.descendants(hash).collect::<HashSet<_>>();
+        removed.insert(hash);
+
+        // The weight of these messages is removed with the subtree, so it must not be removed
+        // again when the validators next vote.
+        let removed_votes = self
+            .latest_messages
+            .iter()
+            .filter(|(_validator, (block, _slot))| {
+                self.find_node_for_block(*block)
+                    .is_ok_and(|node| removed.contains(&node))
+            })
+            .map(|(validator, _message)| *validator)
+            .collect::<Vec<_>>();
+        for validator in removed_votes {
+            Arc::make_mut(&mut self.latest_messages).remove(&validator);
+            Arc::make_mut(&mut self.message_balances).remove(&validator);
+        }
+
+        if let Some(parent_hash) = parent_hash {
+            self.get_mut_node(parent_hash)?
+                .children
+                .retain(|child| *child != hash);
+            self.apply_score_change(parent_hash, -i64::try_from(score).unwrap_or(i64::MAX))?;
+        }
+
+        Arc::make_mut(&mut self.nodes).retain(|hash, _| !removed.contains(hash));
+        Arc::make_mut(&mut self.votes_off_tree).retain(|hash, _| !removed.contains(hash));
+        self.retain_heights();
+        self.clear_ancestor_cache();
+
+        Ok(())
+    }
+
+    /// Removes every node other than the root that has exactly one child, linking the child to
+    /// the node's parent instead, so that only the root, branch points and leaves remain.
+    ///
+    /// Such nodes are left behind by `prune` and `remove_node` when all but one branch below a
+    /// branch point is removed. No score changes: the score of the child and of the parent
+    /// already include the subtree, and weight for a removed node now counts towards its parent,
+    /// as for any block that is not in the tree. Blocks are not removed from the store.
+    pub fn collapse(&mut self) {
+        let root = self.root;
+        let collapsible = self
+            .nodes
+            .iter()
+            .filter(|(hash, node)| **hash != root && node.children.len() == 1)
+            .map(|(hash, _node)| *hash)
+            .collect::<Vec<_>>();
+        if collapsible.is_empty() {
+            return;
+        }
+
+        let nodes = Arc::make_mut(&mut self.nodes);
+        for hash in collapsible {
+            // Neighbouring nodes may have been collapsed already, so follow the current links.
+            let node = match nodes.remove(&hash) {
+                Some(node) => node,
+                None => continue,
+            };
+            let child = node.children[0];
+
+            if let Some(parent) = node.parent_hash.and_then(|parent| nodes.get_mut(&parent)) {
+                parent.replace_child(hash, child);
+            }
+            if let Some(child) = nodes.get_mut(&child) {
+                child.parent_hash = node.parent_hash;
+            }
+        }
+
+        self.retain_heights();
+        self.update_all_best_descendants();
+        self.index_votes_off_tree();
+    }
+
+    /// Makes `new_root` the root of the tree, removing every node that is not `new_root` or one
+    /// of its descendants.
+    ///
+    /// Unlike `prune`, `new_root` must already be in the tree and descend from the current root.
+    pub fn set_root(&mut self, new_root: H) -> Result<(), H> {
+        self.get_node(new_root)?;
+
+        let mut next = Some(new_root);
+        while let Some(hash) = next {
+            if hash == self.root {
+                return self.prune(new_root);
+            }
+            next = self.get_node(hash)?.parent_hash;
+        }
+
+        Err(Error::NotDescendant(new_root))
+    }
+
+    /// Removes blocks that are no longer in the tree from `blocks_at_height`, dropping any
+    /// heights left empty and shifting the remaining heights down to fill the gaps.
+    fn retain_heights(&mut self) {
+        let nodes = &self.nodes;
+        for blocks in Arc::make_mut(&mut self.blocks_at_height).values_mut() {
+            blocks.retain(|hash| nodes.contains_key(hash));
+        }
+
+        let empty_heights = (0..self.slots_at_height.len())
+            .filter(|height| self.blocks_at_height.get(height).is_none_or(Vec::is_empty))
+            .collect::<Vec<_>>();
+
+        if empty_heights.is_empty() {
+            return;
+        }
+
+        for &height in empty_heights.iter().rev() {
+            if let Some(slot) = self.slot_at_height(height) {
+                Arc::make_mut(&mut self.slots_at_height).remove(&slot);
+            }
+        }
+
+        let blocks_at_height = Arc::make_mut(&mut self.blocks_at_height);
+        *blocks_at_height = blocks_at_height
+            .drain()
+            .filter(|(_height, blocks)| !blocks.is_empty())
+            .map(|(height, blocks)| {
+                let removed_below = empty_heights.iter().filter(|h| **h < height).count();
+                (height - removed_below, blocks)
+            })
+            .collect();
+        self.update_node_heights();
+    }
+
+    /// Checks that the internal structure of the tree is consistent, returning an error that
+    /// identifies the first violation found.
+    ///
+    /// Verifies that:
+    ///
+    /// - Every `parent_hash` refers to a node in the tree.
+    /// - Every child of a node is in the tree and refers back to that node as its parent.
+    /// - The children of every node are sorted by hash, without duplicates.
+    /// - `blocks_at_height` lists every node exactly at the height of its slot, and nothing else.
+    /// - The `height` of every node is the height it is listed at.
+    /// - No node is reachable from the root more than once.
+    pub fn check_invariants(&self) -> Result<(), H> {
+        for (&hash, node) in self.nodes.iter() {
+            if let Some(parent) = node.parent_hash {
+                if !self.nodes.contains_key(&parent) {
+                    return Err(Error::DanglingParent { node: hash, parent });
+                }
+            }
+
+            if node.children.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(Error::UnsortedChildren(hash));
+            }
+            for &child in &node.children {
+                match self.nodes.get(&child) {
+                    Some(child_node) if child_node.parent_hash == Some(hash) => {}
+                    _ => {
+                        return Err(Error::UnlinkedChild {
+                            parent: hash,
+                            child,
+                        })
+                    }
+                }
+            }
+        }
+
+        let mut listed = HashSet::new();
+        for (&height, blocks) in self.blocks_at_height.iter() {
+            for &hash in blocks {
+                if self.nodes.get(&hash).map(|node| node.height) != Some(height)
+                    || self.slot_at_height(height) != Some(self.get_block(hash)?.slot)
+                    || !listed.insert(hash)
+                {
+                    return Err(Error::InconsistentHeight(hash));
+                }
+            }
+        }
+        if let Some(hash) = self.nodes.keys().find(|hash| !listed.contains(*hash)) {
+            return Err(Error::InconsistentHeight(*hash));
+        }
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.root];
+        while let Some(hash) = stack.pop() {
+            if !visited.insert(hash) {
+                return Err(Error::Cycle(hash));
+            }
+            stack.extend(self.get_node(hash)?.children.iter().copied());
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over every descendant of `root` in the tree (excluding `root` itself),
+    /// in breadth-first order.
+    ///
+    /// The iterator is empty if `root` is not in the tree.
+    pub fn descendants(&self, root: H) -> impl Iterator<Item = H> + '_ {
+        let queue = self
+            .nodes
+            .get(&root)
+            .map(|node| node.children.iter().copied().collect())
+            .unwrap_or_default();
+
+        Descendants {
+            nodes: &self.nodes,
+            queue,
+        }
+    }
+
+    /// Returns an iterator over `block` and then each of its ancestors in the store, from parent
+    /// to genesis, including blocks that are not in the tree (e.g., before the root).
+    ///
+    /// The iterator ends after the genesis block, or early if a block is missing from the store.
+    /// It yields at most as many blocks as the store holds, so it ends even if the store is
+    /// corrupt.
+    pub fn ancestors(&self, block: H) -> impl Iterator<Item = H> + '_ {
+        Ancestors {
+            store: &*self.store,
+            next: Some(block),
+            remaining: self.store.len(),
+        }
+    }
+
+    /// Returns the tree as a Graphviz DOT digraph, with an edge from each node to each of its
+    /// children.
+    ///
+    /// Each node is labelled with the first 8 hex digits of its hash, its slot, height and score.
+    /// The head, as selected by `update_head`, is filled.
+    pub fn to_dot(&self) -> String
+    where
+        H: std::fmt::LowerHex,
+    {
+        use std::fmt::Write;
+
+        let head = self.select_head().ok();
+        let mut hashes = vec![self.root];
+        hashes.extend(self.descendants(self.root));
+
+        let mut dot = String::from("digraph tree {\n");
+        for hash in &hashes {
+            let node = &self.nodes[hash];
+            let id = format!("{:x}", hash);
+            let slot = self.store.get(hash).map(|block| block.slot);
+            let height = slot.and_then(|slot| self.slots_at_height.index_of(&slot));
+            let label = format!(
+                "{}\\nslot {} height {}\\nscore {}",
+                &id[..id.len().min(8)],
+                slot.map_or("?".to_string(), |slot| slot.to_string()),
+                height.map_or("?".to_string(), |height| height.to_string()),
+                node.score
+            );
+            let style = if Some(*hash) == head {
+                ", style=filled"
+            } else {
+                ""
+            };
+            let _ = writeln!(dot, "    \"{}\" [label=\"{}\"{}];", id, label, style);
+        }
+        for hash in &hashes {
+            for child in &self.nodes[hash].children {
+                let _ = writeln!(dot, "    \"{:x}\" -> \"{:x}\";", hash, child);
+            }
+        }
+        dot.push_str("}\n");
+
+        dot
+    }
+
+    /// Returns every block from the root to the leaves of the tree, linked to its parent, e.g., to
+    /// see the shape of the chain that the tree reduces.
+    ///
+    /// The blocks between each node and its parent in the tree are found by following parents in
+    /// the store, so this takes time linear in the number of blocks from the root to the leaves.
+    pub fn expand(&self) -> FullTree<H> {
+        fn link<H: BlockHash>(blocks: &mut HashMap<H, FullNode<H>>, child: H, parent: H) {
+            if let Some(block) = blocks.get_mut(&child) {
+                block.parent = Some(parent);
+            }
+            if let Some(block) = blocks.get_mut(&parent) {
+                block.children.push(child);
+            }
+        }
+
+        let full_node = |hash: &H, in_tree| FullNode {
+            parent: None,
+            children: vec![],
+            slot: self.store.get(hash).map_or(0, |block| block.slot),
+            in_tree,
+        };
+        let mut blocks = self
+            .nodes
+            .keys()
+            .map(|hash| (*hash, full_node(hash, true)))
+            .collect::<HashMap<_, _>>();
+
+        for (&hash, node) in self.nodes.iter() {
+            let tree_parent = match node.parent_hash {
+                Some(parent) => parent,
+                None => continue,
+            };
+
+            let mut child = hash;
+            for parent in self
+                .ancestors(hash)
+                .skip(1)
+                .take_while(|parent| *parent != tree_parent)
+            {
+                blocks.insert(parent, full_node(&parent, false));
+                link(&mut blocks, child, parent);
+                child = parent;
+            }
+            link(&mut blocks, child, tree_parent);
+        }
+
+        for block in blocks.values_mut() {
+            block.children.sort();
+        }
+
+        FullTree {
+            root: self.root,
+            blocks,
+        }
+    }
+
+    /// Calls `f` with `root` and each of its descendants in the tree, in the given `order`.
+    /// Children are visited in the order they are listed in `Node::children`.
+    ///
+    /// Does nothing if `root` is not in the tree.
+    pub fn walk_subtree<F: FnMut(&Node<H>)>(&self, root: H, order: TraversalOrder, mut f: F) {
+        let root = match self.nodes.get(&root) {
+            Some(node) => node,
+            None => return,
+        };
+        let children = |node: &Node<H>| {
+            node.children
+                .iter()
+                .filter_map(|hash| self.nodes.get(hash))
+                .collect::<Vec<_>>()
+        };
+
+        match order {
+            TraversalOrder::PreOrder => {
+                let mut stack = vec![root];
+                while let Some(node) = stack.pop() {
+                    f(node);
+                    stack.extend(children(node).into_iter().rev());
+                }
+            }
+            TraversalOrder::PostOrder => {
+                // Each node is pushed again, marked as expanded, beneath its children.
+                let mut stack = vec![(root, false)];
+                while let Some((node, expanded)) = stack.pop() {
+                    if expanded {
+                        f(node);
+                    } else {
+                        stack.push((node, true));
+                        stack.extend(children(node).into_iter().rev().map(|child| (child, false)));
+                    }
+                }
+            }
+            TraversalOrder::BreadthFirst => {
+                let mut queue = VecDeque::from(vec![root]);
+                while let Some(node) = queue.pop_front() {
+                    f(node);
+                    queue.extend(children(node));
+                }
+            }
+        }
+    }
+
+    /// Records that `hash` (a block at `slot`) is now in the tree.
+    ///
+    /// Adds `slot` to `slots_at_height` and `hash` to `blocks_at_height`. If `slot` is new and
+    /// lands below existing heights, those heights are shifted up by one.
+    fn register_height(&mut self, hash: H, slot: Slot) {
+        let mut shifted = false;
+        if !self.slots_at_height.contains(&slot) {
+            Arc::make_mut(&mut self.slots_at_height).insert(slot);
+            let height = self.slots_at_height.index_of(&slot).unwrap_or(0);
+
+            if height + 1 < self.slots_at_height.len() {
+                let blocks_at_height = Arc::make_mut(&mut self.blocks_at_height);
+                *blocks_at_height = blocks_at_height
+                    .drain()
+                    .map(|(h, blocks)| {
+                        if h >= height {
+                            (h + 1, blocks)
+                        } else {
+                            (h, blocks)
+                        }
+                    })
+                    .collect();
+                shifted = true;
+            }
+        }
+
+        if let Some(height) = self.slots_at_height.index_of(&slot) {
+            let blocks = Arc::make_mut(&mut self.blocks_at_height)
+                .entry(height)
+                .or_default();
+            if !blocks.contains(&hash) {
+                blocks.push(hash);
+            }
+            if let Some(node) = Arc::make_mut(&mut self.nodes).get_mut(&hash) {
+                node.height = height;
+            }
+        }
+
+        if shifted {
+            self.update_node_heights();
+        }
+    }
+
+    /// Adds each of `slots` that is new to `slots_at_height`, shifting the existing heights up
+    /// once for all of them, and returns the new slots in ascending order.
+    ///
+    /// The new heights have no blocks until they are registered with `register_height`.
+    fn register_slots(&mut self, slots: impl IntoIterator<Item = Slot>) -> Vec<Slot> {
+        let mut new_slots = slots
+            .into_iter()
+            .filter(|slot| !self.slots_at_height.contains(slot))
+            .collect::<Vec<_>>();
+        new_slots.sort_unstable();
+        new_slots.dedup();
+
+        let shifted = match (new_slots.first(), self.slots_at_height.last()) {
+            (Some(first), Some(last)) => first < last,
+            _ => false,
+        };
+        if shifted {
+            let slots_at_height = &self.slots_at_height;
+            let blocks_at_height = Arc::make_mut(&mut self.blocks_at_height);
+            *blocks_at_height = blocks_at_height
+                .drain()
+                .map(|(height, blocks)| {
+                    let below = slots_at_height
+                        .nth(height)
+                        .map_or(0, |slot| new_slots.partition_point(|new| new < slot));
+                    (height + below, blocks)
+                })
+                .collect();
+        }
+        if !new_slots.is_empty() {
+            Arc::make_mut(&mut self.slots_at_height).extend(new_slots.iter().copied());
+        }
+        if shifted {
+            self.update_node_heights();
+        }
+
+        new_slots
+    }
+
+    /// Sets `Node::height` of every node to the height it is listed at in `blocks_at_height`,
+    /// after the heights have been shifted.
+    fn update_node_heights(&mut self) {
+        let nodes = Arc::make_mut(&mut self.nodes);
+        for (&height, blocks) in self.blocks_at_height.iter() {
+            for hash in blocks {
+                if let Some(node) = nodes.get_mut(hash) {
+                    node.height = height;
+                }
+            }
+        }
+    }
+
+    /// Returns the hash of the highest block in the tree that is an ancestor of `hash`, searching
+    /// only the heights in `range`.
+    ///
+    /// Heights are searched from the top of `range` down. Whether a height holds an ancestor is
+    /// not monotonic in the height (a fork or a skipped slot may leave a gap below an ancestor in
+    /// the tree), so a binary search could miss the highest one. Instead, every height above the
+    /// most recent ancestor at or before the slot of a height is skipped, so a block built on the
+    /// highest height is found with a single lookup. Lookups go through the ancestor cache, if
+    /// enabled.
+    ///
+    /// This is a loop rather than a recursion, so a deep tree cannot overflow the stack. Every
+    /// iteration lowers `height` by at least one, so it ends after at most `range.len()` of them.
+    fn find_prev_in_tree(&self, hash: H, range: Range<Height>) -> Result<H, H> {
+        if range.end > self.slots_at_height.len() {
+            return Err(Error::HeightOutOfRange);
+        }
+
+        let mut height = range.end;
+        while height > range.start {
+            height -= 1;
+            let slot = self.slot_at_height(height).ok_or(Error::HeightOutOfRange)?;
+            let ancestor = match self.find_ancestor_at_slot(hash, slot)? {
+                Some(ancestor) => ancestor,
+                // `hash` has no ancestors this early.
+                None => break,
+            };
+            let ancestor_slot = self.get_block(ancestor)?.slot;
+
+            if ancestor_slot < slot {
+                height = height.min(self.slots_at_height.count_up_to(&ancestor_slot));
+            } else if self
+                .blocks_at_height
+                .get(&height)
+                .is_some_and(|blocks| blocks.contains(&ancestor))
+            {
+                return Ok(ancestor);
+            }
+        }
+
+        Err(Error::MissingParent(hash))
+    }
+
+    /// Returns `block` if it is in the tree, otherwise its most recent ancestor in the tree.
+    fn find_node_for_block(&self, block: H) -> Result<H, H> {
+        if self.nodes.contains_key(&block) {
+            Ok(block)
+        } else {
+            self.find_prev_in_tree(block, 0..self.slots_at_height.len())
+        }
+    }
+
+    fn find_ancestor_at_slot(&self, child: H, slot: Slot) -> Result<Option<H>, H> {
+        let cache = match &self.ancestor_cache {
+            Some(cache) => cache,
+            None => return get_ancestor_hash_at_slot(slot, child, &*self.store),
+        };
+
+        let key = (child, slot);
+        if let Some(ancestor) = cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(ancestor);
+        }
+
+        let ancestor = get_ancestor_hash_at_slot(slot, child, &*self.store)?;
+        cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, ancestor);
+        Ok(ancestor)
+    }
+
+    fn clear_ancestor_cache(&mut self) {
+        if let Some(cache) = &mut self.ancestor_cache {
+            cache
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    fn find_least_common_ancestor(&self, a: H, b: H) -> Result<Option<H>, H> {
+        find_least_common_ancestor(a, b, &*self.store)
+    }
+
+    fn get_node(&self, hash: H) -> Result<&Node<H>, H> {
+        self.nodes.get(&hash).ok_or(Error::UnknownBlock(hash))
+    }
+
+    fn get_mut_node(&mut self, hash: H) -> Result<&mut Node<H>, H> {
+        Arc::make_mut(&mut self.nodes)
+            .get_mut(&hash)
+            .ok_or(Error::UnknownBlock(hash))
+    }
+
+    fn get_block(&self, hash: H) -> Result<Cow<'_, Block<H, N>>, H> {
+        self.store.get(&hash).ok_or(Error::UnknownBlock(hash))
+    }
+
+    fn slot_at_height(&self, height: Height) -> Option<Slot> {
+        self.slots_at_height.nth(height).cloned()
+    }
+}
+
+/// A block of the chain description read by `Tree::from_chain_json`.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ChainEntry<H> {
+    hash: H,
+    parent: Option<H>,
+    slot: Slot,
+}
+
+#[cfg(feature = "serde")]
+impl<H: BlockHash + serde::de::DeserializeOwned> Tree<H> {
+    /// Returns a tree built from a JSON array of blocks, e.g., to reproduce a bug report.
+    ///
+    /// Each block is an object `{"hash": ..., "parent": ..., "slot": ...}`, where the hash and
+    /// parent are serialized as `H` is (a hex string for `H256`). The block with the lowest slot
+    /// becomes the root and must have a `null` parent. The blocks may be listed in any order:
+    /// they are imported with `on_block` in order of slot, which builds their skip lists.
+    ///
+    /// Returns `Error::InvalidJson` if `json` is not such an array, `Error::MissingRoot` if it is
+    /// empty, `Error::DuplicateBlock` if a hash is listed twice, `Error::Cycle` if the parents of a
+    /// block lead back to it, `Error::MissingParent` if a parent is not listed, and
+    /// `Error::NonMonotonicSlot` if a block is not at a later slot than its parent.
+    pub fn from_chain_json(json: &str) -> Result<Self, H> {
+        let mut entries: Vec<ChainEntry<H>> =
+            serde_json::from_str(json).map_err(|e| Error::InvalidJson(e.to_string()))?;
+
+        let mut described = HashMap::with_capacity(entries.len());
+        for entry in &entries {
+            if described
+                .insert(entry.hash, (entry.parent, entry.slot))
+                .is_some()
+            {
+                return Err(Error::DuplicateBlock(entry.hash));
+            }
+        }
+
+        // Blocks whose parents are known to end at a block without a listed parent.
+        let mut acyclic = HashSet::with_capacity(entries.len());
+        for entry in &entries {
+            let mut path = HashSet::new();
+            let mut next = Some(entry.hash);
+            while let Some(hash) = next.filter(|hash| !acyclic.contains(hash)) {
+                if !path.insert(hash) {
+                    return Err(Error::Cycle(hash));
+                }
+                next = described.get(&hash).and_then(|(parent, _slot)| *parent);
+            }
+            acyclic.extend(path);
+        }
+
+        // The parent of a block that is not yet imported is either not listed or not at an
+        // earlier slot.
+        let unimported_parent = |entry: &ChainEntry<H>, parent: H| match described.get(&parent) {
+            Some(&(_parent, parent_slot)) => Error::NonMonotonicSlot {
+                slot: entry.slot,
+                parent_slot,
+            },
+            None => Error::MissingParent(entry.hash),
+        };
+
+        // A root at the same slot as a block that claims it as a parent must still come first.
+        entries.sort_by_key(|entry| (entry.slot, entry.parent.is_some()));
+        let mut entries = entries.into_iter();
+        let genesis = entries.next().ok_or(Error::MissingRoot)?;
+        if let Some(parent) = genesis.parent {
+            return Err(unimported_parent(&genesis, parent));
+        }
+
+        let mut tree = Tree::new(genesis.hash, genesis.slot);
+        for entry in entries {
+            let parent = entry.parent.ok_or(Error::MissingParent(entry.hash))?;
+            if !tree.store.contains_key(&parent) {
+                return Err(unimported_parent(&entry, parent));
+            }
+            tree.on_block(entry.hash, Block::with_parent(entry.slot, parent))?;
+        }
+
+        Ok(tree)
+    }
+}
+
+/// Builds a `Tree`, checking that its root, genesis slot and store agree.
+///
+/// Unlike `Tree::new`, the slot of the genesis block is named as such, rather than as a height.
+/// The tree has skip lists of `N` entries, see `TreeBuilder::skip_list_len`, and keeps its blocks
+/// in `S`, see `TreeBuilder::with_store`.
+pub struct TreeBuilder<H = Hash256, const N: usize = SKIP_LIST_LEN, S = Store<H, N>> {
+    root: Option<H>,
+    genesis_slot: Option<Slot>,
+    store: S,
+    /// The first error from `skip_list_len`, returned by `build`.
+    error: Option<Error<H>>,
+}
+
+impl<H: BlockHash, const N: usize> Default for TreeBuilder<H, N> {
+    fn default() -> Self {
+        Self {
+            root: None,
+            genesis_slot: None,
+            store: Store::default(),
+            error: None,
+        }
+    }
+}
+
+impl<H: BlockHash> TreeBuilder<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<H: BlockHash, const N: usize> TreeBuilder<H, N> {
+    /// Builds a tree whose blocks have skip lists of `M` entries rather than `N`, e.g.,
+    /// `TreeBuilder::new().skip_list_len::<32>()` for chains with long gaps between blocks.
+    ///
+    /// A store already given with `with_store` has the skip list of each of its blocks built
+    /// again with `M` entries, which takes time linear in the number of blocks. `build` returns
+    /// an error if a block of that store does not fit onto the others, as for
+    /// `StoreExt::insert_block`.
+    pub fn skip_list_len<const M: usize>(self) -> TreeBuilder<H, M> {
+        let (store, error) = match relink_store(self.store) {
+            Ok(store) => (store, self.error),
+            Err(e) => (Store::default(), self.error.or(Some(e))),
+        };
+        TreeBuilder {
+            root: self.root,
+            genesis_slot: self.genesis_slot,
+            store,
+            error,
+        }
+    }
+}
+
+impl<H: BlockHash, const N: usize, S: BlockStore<H, N> + Clone> TreeBuilder<H, N, S> {
+    /// Sets the hash of the genesis block, which becomes the root of the tree. Required.
+    pub fn root(mut self, root: H) -> Self {
+        self.root = Some(root);
+        self
+    }
+
+    /// Sets the slot of the genesis block. Defaults to the slot of the root in the store if one is
+    /// given, otherwise `0`.
+    pub fn genesis_slot(mut self, slot: Slot) -> Self {
+        self.genesis_slot = Some(slot);
+        self
+    }
+
+    /// Uses `store` as the block store of the tree, see `Tree::with_store`, e.g., a `FileStore` to
+    /// keep the blocks on disk. Without a store, or with an empty one, the tree starts with a
+    /// store holding only the genesis block.
+    pub fn with_store<T: BlockStore<H, N> + Clone>(self, store: T) -> TreeBuilder<H, N, T> {
+        TreeBuilder {
+            root: self.root,
+            genesis_slot: self.genesis_slot,
+            store,
+            error: self.error,
+        }
+    }
+
+    /// Returns the tree, or an error if no root was given, if the store does not contain the root,
+    /// if the root in the store is not at the genesis slot, or if `skip_list_len` failed.
+    pub fn build(self) -> Result<Tree<H, N, S>, H> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let root = self.root.ok_or(Error::MissingRoot)?;
+
+        let mut store = self.store;
+        if store.is_empty() {
+            let slot = self.genesis_slot.unwrap_or(0);
+            store.insert(root, Block::genesis(root, slot));
+            return Ok(Tree::from_store(root, slot, store));
+        }
+        let slot = store.get(&root).ok_or(Error::UnknownBlock(root))?.slot;
+        if self
+            .genesis_slot
+            .is_some_and(|genesis_slot| genesis_slot != slot)
+        {
+            return Err(Error::InconsistentStore);
+        }
+
+        Ok(Tree::from_store(root, slot, store))
+    }
+}
+
+/// Returns the blocks of `store` with skip lists of `M` entries, built again from the parent of
+/// each block in order of slot.
+fn relink_store<H: BlockHash, const N: usize, const M: usize>(
+    store: Store<H, N>,
+) -> Result<Store<H, M>, H> {
+    let mut blocks: Vec<(H, Block<H, N>)> = store.into_iter().collect();
+    blocks.sort_by_key(|(hash, block)| (block.slot, *hash));
+
+    let mut relinked = Store::with_capacity(blocks.len());
+    for (hash, block) in blocks {
+        let parent = block.ancestor_skip_list[0];
+        let block = if parent == hash {
+            Block::genesis(hash, block.slot)
+        } else {
+            Block::new(block.slot, parent, &relinked)?
+        };
+        relinked.insert_block(hash, block)?;
+    }
+
+    Ok(relinked)
+}
+
+/// A set of disconnected trees, keyed by their roots, e.g., for candidate subtrees held before
+/// the anchor is known.
+pub struct Forest<H = Hash256> {
+    trees: HashMap<H, Tree<H>>,
+}
+
+impl<H: BlockHash> Default for Forest<H> {
+    fn default() -> Self {
+        Self {
+            trees: HashMap::new(),
+        }
+    }
+}
+
+impl<H: BlockHash> Forest<H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `tree` to the forest, replacing any tree with the same root.
+    pub fn insert(&mut self, tree: Tree<H>) {
+        self.trees.insert(tree.root(), tree);
+    }
+
+    pub fn tree(&self, root: &H) -> Option<&Tree<H>> {
+        self.trees.get(root)
+    }
+
+    pub fn tree_mut(&mut self, root: &H) -> Option<&mut Tree<H>> {
+        self.trees.get_mut(root)
+    }
+
+    /// Returns the roots of the trees in the forest, in no particular order.
+    pub fn roots(&self) -> impl Iterator<Item = H> + '_ {
+        self.trees.keys().copied()
+    }
+
+    /// Returns the head of the tree whose root has the greatest `score`, breaking ties by the
+    /// greater root hash as in `Tree::find_head`.
+    ///
+    /// Returns `None` if the forest is empty.
+    pub fn find_global_head(&self) -> Option<H> {
+        let (_score, root) = self
+            .trees
+            .iter()
+            .filter_map(|(root, tree)| Some((tree.nodes.get(root)?.score, *root)))
+            .max()?;
+        self.trees[&root].find_head(root)
+    }
+
+    /// Moves every node of the tree rooted at `root_b` into the tree rooted at `root_a`, then
+    /// removes the tree rooted at `root_b` from the forest.
+    ///
+    /// The parent of `root_b` (i.e., the connecting block) must be in the store of `root_a`'s
+    /// tree and descend from `root_a`. The blocks of `root_b`'s store are added to `root_a`'s
+    /// store and its nodes are inserted with `Tree::add_nodes`, so `root_b` is reparented beneath
+    /// its most recent ancestor in `root_a`'s tree, as found by `find_prev_in_tree`. The weight
+    /// that each node holds in its own right (its score, less the scores of its children) beyond
+    /// the latest messages counted towards it is then applied again. Latest messages are
+    /// combined, keeping the newer message for any validator with one in both trees: only the
+    /// weight of that message is counted.
+    ///
+    /// Returns an error, leaving the forest unchanged, if either root is unknown, if `root_b`
+    /// cannot be connected to `root_a`'s tree, or if any step of the merge fails.
+    pub fn merge(&mut self, root_a: H, root_b: H) -> Result<(), H> {
+        if root_a == root_b || !self.trees.contains_key(&root_a) {
+            return Err(Error::UnknownBlock(root_a));
+        }
+        let b = self
+            .trees
+            .remove(&root_b)
+            .ok_or(Error::UnknownBlock(root_b))?;
+        let a = self
+            .trees
+            .get_mut(&root_a)
+            .ok_or(Error::UnknownBlock(root_a))?;
+
+        let connection = b
+            .get_block(root_b)
+            .map(|block| block.ancestor_skip_list[0])
+            .and_then(|parent| {
+                if parent == root_b {
+                    Err(Error::MissingParent(root_b))
+                } else {
+                    a.find_node_for_block(parent)
+                }
+            });
+        if let Err(e) = connection {
+            self.trees.insert(root_b, b);
+            return Err(e);
+        }
+
+        // Nothing has changed yet, so on error `a` is rolled back to this snapshot.
+        let backup = a.snapshot();
+        if let Err(e) = Self::merge_into(a, &b) {
+            a.restore_in_place(backup);
+            self.trees.insert(root_b, b);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the blocks, nodes and latest messages of `b` to `a`, see `merge`. Returns an error
+    /// after changing `a` only partially.
+    fn merge_into(a: &mut Tree<H>, b: &Tree<H>) -> Result<(), H> {
+        // The weight that each node of `b` holds beyond the latest messages counted towards it,
+        // e.g., from `apply_score_change`. The messages are counted again where they land in `a`.
+        let mut residuals = b
+            .nodes
+            .iter()
+            .map(|(hash, node)| (*hash, b.own_weight(node)))
+            .collect::<HashMap<_, _>>();
+        for (&validator, (block, _slot)) in b.latest_messages.iter() {
+            if let Some(residual) = b
+                .find_node_for_block(*block)
+                .ok()
+                .and_then(|node| residuals.get_mut(&node))
+            {
+                *residual = residual.saturating_sub(b.message_balance(validator));
+            }
+        }
+
+        for (hash, block) in b.store.iter() {
+            Arc::make_mut(&mut a.store)
+                .entry(*hash)
+                .or_insert_with(|| block.clone());
+        }
+        a.add_nodes(
+            b.nodes
+                .iter()
+                .filter(|(hash, _node)| !a.nodes.contains_key(*hash))
+                .map(|(hash, node)| (*hash, node.block_hash))
+                .collect::<Vec<_>>(),
+        )?;
+        for (hash, residual) in residuals {
+            if residual > 0 {
+                a.apply_score_change(hash, i64::try_from(residual).unwrap_or(i64::MAX))?;
+            }
+        }
+
+        // The weight of validators that equivocated in `b` has already been removed from `b`.
+        for &validator in b.equivocating.iter() {
+            a.remove_validator(validator)?;
+            Arc::make_mut(&mut a.equivocating).insert(validator);
+        }
+        for (&validator, &(block, slot)) in b.latest_messages.iter() {
+            let newer = !a.equivocating.contains(&validator)
+                && a.latest_messages
+                    .get(&validator)
+                    .is_none_or(|(_block, latest_slot)| slot > *latest_slot);
+            if !newer {
+                continue;
+            }
+
+            // The older message of `a` loses its weight, as for a vote switch.
+            a.remove_validator(validator)?;
+            let balance = b.message_balance(validator);
+            let node = a.find_node_for_block(block)?;
+            a.apply_score_change(node, i64::try_from(balance).unwrap_or(i64::MAX))?;
+            Arc::make_mut(&mut a.latest_messages).insert(validator, (block, slot));
+            Arc::make_mut(&mut a.message_balances).insert(validator, balance);
+            a.index_vote(validator, block, node);
+        }
+        a.current_slot = a.current_slot.max(b.current_slot);
+
+        Ok(())
+    }
+}
+
+/// A `Tree` that can be shared between threads, e.g., so that many tasks can read the head while
+/// one task imports blocks.
+///
+/// Cloning a `SharedTree` gives another handle to the same tree. The tree is behind a `RwLock`:
+/// any number of `with_read` (and `read_head`) calls may run at once, while `with_write` waits for
+/// exclusive access. The lock is held for the duration of the closure, so keep closures short and
+/// never use a handle to the same tree inside them, which would deadlock. A panic inside a closure
+/// does not poison the tree for other callers, although a panicking write may leave it partially
+/// updated.
+pub struct SharedTree<H = Hash256>(pub Arc<RwLock<Tree<H>>>);
+
+impl<H> Clone for SharedTree<H> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<H> From<Tree<H>> for SharedTree<H> {
+    fn from(tree: Tree<H>) -> Self {
+        Self(Arc::new(RwLock::new(tree)))
+    }
+}
+
+impl<H: BlockHash> SharedTree<H> {
+    /// Returns the head of the tree, as selected by `Tree::update_head`.
+    pub fn read_head(&self) -> Option<H> {
+        self.with_read(|tree| tree.select_head().ok())
+    }
+
+    /// Calls `f` with shared access to the tree.
+    pub fn with_read<T>(&self, f: impl FnOnce(&Tree<H>) -> T) -> T {
+        f(&self.0.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Calls `f` with exclusive access to the tree.
+    pub fn with_write<T>(&self, f: impl FnOnce(&mut Tree<H>) -> T) -> T {
+        f(&mut self.0.write().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// A block in the store, with a skip list of `N` ancestors.
+///
+/// Larger values of `N` allow ancestor queries to jump further back (up to `2^(N - 1)` slots) in
+/// a single hop. `N` must be at least `1`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(serialize = "H: Serialize", deserialize = "H: Deserialize<'de>"))
+)]
+pub struct Block<H = Hash256, const N: usize = SKIP_LIST_LEN> {
+    pub slot: Slot,
+    #[cfg_attr(feature = "serde", serde(with = "serde_skip_list"))]
+    ancestor_skip_list: [H; N],
+}
+
+/// Shows only the first few entries of the skip list, which are the nearest ancestors.
+impl<H: std::fmt::Debug, const N: usize> std::fmt::Debug for Block<H, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const SHOWN: usize = 3;
+
+        struct Truncated<'a, H>(&'a [H]);
+
+        impl<H: std::fmt::Debug> std::fmt::Debug for Truncated<'_, H> {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                let mut list = f.debug_list();
+                list.entries(self.0.iter().take(SHOWN));
+                if self.0.len() > SHOWN {
+                    list.finish_non_exhaustive()
+                } else {
+                    list.finish()
+                }
+            }
+        }
+
+        f.debug_struct("Block")
+            .field("slot", &self.slot)
+            .field("ancestor_skip_list", &Truncated(&self.ancestor_skip_list))
+            .finish()
+    }
+}
+
+impl<H: BlockHash, const N: usize> Block<H, N> {
+    /// Returns a genesis block at `slot`.
+    ///
+    /// A genesis block has no ancestors, so every entry of its skip list points to itself.
+    pub fn genesis(hash: H, slot: Slot) -> Self {
+        Self {
+            slot,
+            ancestor_skip_list: [hash; N],
+        }
+    }
+
+    /// Returns a block at `slot` that is a child of `parent`.
+    ///
+    /// Entry `i` of the skip list is the most recent ancestor at or before `slot - 2^i`, found by
+    /// walking the skip lists of `parent` and its ancestors. Entries that would reach past
+    /// genesis point to genesis.
+    ///
+    /// Returns an error if `parent` (or any ancestor visited) is not in `store`.
+    pub fn new<S: BlockStore<H, N>>(slot: Slot, parent: H, store: &S) -> Result<Self, H> {
+        let mut ancestor_skip_list = [parent; N];
+
+        for i in 1..N {
+            let jump = 1_u64.checked_shl(i as u32).unwrap_or(u64::MAX);
+            let target = slot.saturating_sub(jump);
+            // Entry `i - 1` is never older than entry `i`, so start walking from there.
+            let start = ancestor_skip_list[i - 1];
+            ancestor_skip_list[i] = get_ancestor_hash_at_or_before_slot(target, start, store)?;
+        }
+
+        Ok(Self {
+            slot,
+            ancestor_skip_list,
+        })
+    }
+
+    /// Returns `true` if every entry of the skip list is the ancestor that walking back from the
+    /// parent one block at a time would find, as described for `Block::new`.
+    ///
+    /// This takes time linear in the number of blocks back to the last entry, so it is meant for
+    /// checking blocks from an untrusted source or in tests. Returns `false` if the parent or any
+    /// ancestor visited is not in `store`.
+    pub fn verify_skip_list<S: BlockStore<H, N>>(&self, store: &S) -> bool {
+        let mut hash = self.ancestor_skip_list[0];
+
+        for (i, entry) in self.ancestor_skip_list.iter().enumerate() {
+            let jump = 1_u64.checked_shl(i as u32).unwrap_or(u64::MAX);
+            let target = self.slot.saturating_sub(jump);
+
+            // Targets only get older, so keep walking from the previous entry.
+            loop {
+                let block = match store.get(&hash) {
+                    Some(block) => block,
+                    None => return false,
+                };
+                let parent = block.ancestor_skip_list[0];
+                if block.slot <= target || parent == hash {
+                    break;
+                }
+                hash = parent;
+            }
+
+            if *entry != hash {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// Returns the skip list, where entry `i` is the most recent ancestor at or before
+    /// `slot - 2^i` and entry `0` is the parent.
+    pub fn ancestor_skip_list(&self) -> &[H] {
+        &self.ancestor_skip_list
+    }
+
+    /// Returns a block at `slot` that is a child of `parent`, without building the rest of its
+    /// skip list.
+    ///
+    /// Every entry of the skip list points to `parent`. The skip list is built when the block is
+    /// imported with `Tree::on_block`.
+    pub fn with_parent(slot: Slot, parent: H) -> Self {
+        Self {
+            slot,
+            ancestor_skip_list: [parent; N],
+        }
+    }
+}
+
+/// Serializes the skip list as a sequence, since serde only implements its traits for arrays of
+/// certain fixed lengths.
+#[cfg(feature = "serde")]
+mod serde_skip_list {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: Serializer, H: Serialize, const N: usize>(
+        list: &[H; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, H: Deserialize<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[H; N], D::Error> {
+        let list = Vec::<H>::deserialize(deserializer)?;
+        let len = list.len();
+        list.try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a skip list of the configured length"))
+    }
+}
+
+/// The length of an SSZ-encoded `Block<H256, N>`: a `u64` slot followed by a fixed-length vector of
+/// `N` hashes.
+#[cfg(feature = "ssz")]
+const fn block_ssz_len(n: usize) -> usize {
+    8 + 32 * n
+}
+
+#[cfg(feature = "ssz")]
+impl<const N: usize> ssz::Encode for Block<Hash256, N> {
+    fn is_ssz_fixed_len() -> bool {
+        true
+    }
+
+    fn ssz_fixed_len() -> usize {
+        block_ssz_len(N)
+    }
+
+    fn ssz_append(&self, buf: &mut Vec<u8>) {
+        self.slot.ssz_append(buf);
+        for hash in self.ancestor_skip_list.iter() {
+            buf.extend_from_slice(hash.as_bytes());
+        }
+    }
+}
+
+#[cfg(feature = "ssz")]
+impl<const N: usize> ssz::Decode for Block<Hash256, N> {
+    fn is_ssz_fixed_len() -> bool {
+        true
+    }
+
+    fn ssz_fixed_len() -> usize {
+        block_ssz_len(N)
+    }
+
+    fn from_ssz_bytes(bytes: &[u8]) -> core::result::Result<Self, ssz::DecodeError> {
+        if bytes.len() != block_ssz_len(N) {
+            return Err(ssz::DecodeError::InvalidByteLength {
+                len: bytes.len(),
+                expected: block_ssz_len(N),
+            });
+        }
+
+        let (slot_bytes, list_bytes) = bytes.split_at(8);
+        let mut ancestor_skip_list = [Hash256::zero(); N];
+        for (entry, chunk) in ancestor_skip_list.iter_mut().zip(list_bytes.chunks(32)) {
+            *entry = Hash256::from_slice(chunk);
+        }
+
+        Ok(Self {
+            slot: Slot::from_ssz_bytes(slot_bytes)?,
+            ancestor_skip_list,
+        })
+    }
+}
+
+/// Returns the contents of `arc` for the caller to clear, replacing them with a default instead
+/// if they are shared with a snapshot, so that they are not copied only to be cleared.
+fn unshared_for_clear<T: Default>(arc: &mut Arc<T>) -> &mut T {
+    if Arc::get_mut(arc).is_none() {
+        *arc = Arc::default();
+    }
+    Arc::get_mut(arc).expect("a new Arc is not shared")
+}
+
+/// Returns the block that was canonical at `slot` on the chain of `start`, i.e., the most recent
+/// block of the chain (including `start` itself) with a slot less than or equal to `slot`.
+///
+/// If the chain has no block at `slot` because the slot was skipped, this is the last block
+/// before the gap, which remained the head of the chain throughout it. For the same reason,
+/// `start` is returned for its own slot and every slot after it. Returns `None` only if `slot` is
+/// before the genesis of the chain.
+///
+/// Returns an error if `start` or any block visited along the way is missing from `store`.
+pub fn get_ancestor_hash_at_slot<H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    slot: Slot,
+    start: H,
+    store: &S,
+) -> Result<Option<H>, H> {
+    let hash = get_ancestor_hash_at_or_before_slot(slot, start, store)?;
+    let block = store.get(&hash).ok_or(Error::UnknownBlock(hash))?;
+
+    Ok(Some(hash).filter(|_| block.slot <= slot))
+}
+
+/// Returns the most recent block in the chain of `start` (including `start` itself) with a slot
+/// less than or equal to `slot`.
+///
+/// If the whole chain is newer than `slot`, the oldest block (i.e., genesis) is returned.
+///
+/// Each hop takes the longest skip that cannot pass `slot`: entry `i` for the largest `2^i` that
+/// is at most the remaining distance. On a chain without skipped slots this is one hop per set
+/// bit of the distance; e.g., going back 13 (`0b1101`) slots from slot 100 hops to 92, 88 and
+/// then 87, fetching four blocks including `start`. Skipped slots can only shorten the walk, as an
+/// entry may land further back than `2^i` (but never past `slot`). Distances beyond
+/// `2^(N - 1)` take one extra hop per `2^(N - 1)` slots.
+fn get_ancestor_hash_at_or_before_slot<H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    slot: Slot,
+    start: H,
+    store: &S,
+) -> Result<H, H> {
+    let mut hash = start;
+    let mut block = store.get(&start).ok_or(Error::UnknownBlock(start))?;
+
+    while block.slot > slot {
+        let delta = block.slot - slot;
+        let i = std::cmp::min(63 - delta.leading_zeros() as usize, N - 1);
+        let next = block.ancestor_skip_list[i];
+
+        if next == hash {
+            break;
+        }
+
+        hash = next;
+        block = get_ancestor(&block, i, store)?;
+    }
+
+    Ok(hash)
+}
+
+/// Returns the oldest block in the chain of `start` (including `start` itself) with a slot
+/// greater than `slot`, e.g., the first block of a fork after the block it branches off from.
+///
+/// Returns `start` if it is not after `slot`. Each hop takes the longest skip that stays after
+/// `slot`, so this fetches a number of blocks logarithmic in the distance, as for
+/// `get_ancestor_hash_at_or_before_slot`.
+fn get_oldest_ancestor_after_slot<H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    slot: Slot,
+    start: H,
+    store: &S,
+) -> Result<H, H> {
+    let mut hash = start;
+    let mut block = store.get(&start).ok_or(Error::UnknownBlock(start))?;
+
+    for i in (0..N).rev() {
+        loop {
+            let next = block.ancestor_skip_list[i];
+            if next == hash {
+                break;
+            }
+            let next_block = get_ancestor(&block, i, store)?;
+            if next_block.slot <= slot {
+                break;
+            }
+
+            hash = next;
+            block = next_block;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Returns the most recent block that is an ancestor of both `a_root` and `b_root`, or `None` if
+/// they do not share history.
+///
+/// The walk ends when a genesis block (one that is its own parent) would have to be stepped past,
+/// so two chains descending from the same genesis always find at least that genesis.
+pub fn find_least_common_ancestor<H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    a_root: H,
+    b_root: H,
+    store: &S,
+) -> Result<Option<H>, H> {
+    let a_slot = store.get(&a_root).ok_or(Error::UnknownBlock(a_root))?.slot;
+    let b_slot = store.get(&b_root).ok_or(Error::UnknownBlock(b_root))?.slot;
+
+    // Use the skip lists to bring the higher block down to (at most) the slot of the other.
+    let mut a_hash = get_ancestor_hash_at_or_before_slot(b_slot, a_root, store)?;
+    let mut b_hash = get_ancestor_hash_at_or_before_slot(a_slot, b_root, store)?;
+    let mut a = store.get(&a_hash).ok_or(Error::InconsistentStore)?;
+    let mut b = store.get(&b_hash).ok_or(Error::InconsistentStore)?;
+
+    loop {
+        if a_hash == b_hash {
+            break Ok(Some(a_hash));
+        }
+
+        // Step back whichever block is higher, or both if they are at the same slot.
+        let (a_slot, b_slot) = (a.slot, b.slot);
+        if a_slot >= b_slot {
+            if a.ancestor_skip_list[0] == a_hash {
+                break Ok(None);
+            }
+            a_hash = a.ancestor_skip_list[0];
+            a = get_ancestor(&a, 0, store)?;
+        }
+        if b_slot >= a_slot {
+            if b.ancestor_skip_list[0] == b_hash {
+                break Ok(None);
+            }
+            b_hash = b.ancestor_skip_list[0];
+            b = get_ancestor(&b, 0, store)?;
+        }
+    }
+}
+
+/// Returns the most recent block that is an ancestor of every block in `roots`.
+///
+/// A single root is its own common ancestor. Returns `None` if `roots` is empty, if any root is
+/// not in `store`, or if the roots do not all share history.
+pub fn find_least_common_ancestor_many<H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    roots: &[H],
+    store: &S,
+) -> Option<H> {
+    let (first, rest) = roots.split_first()?;
+    store.get(first)?;
+
+    rest.iter().try_fold(*first, |ancestor, root| {
+        find_least_common_ancestor(ancestor, *root, store).ok()?
+    })
+}
+
+/// Returns entry `i` of the skip list of `block`, resolved against `store`.
+fn get_ancestor<'a, H: BlockHash, S: BlockStore<H, N>, const N: usize>(
+    block: &Block<H, N>,
+    i: usize,
+    store: &'a S,
+) -> Result<Cow<'a, Block<H, N>>, H> {
+    store
+        .get(&block.ancestor_skip_list[i])
+        .ok_or(Error::InconsistentStore)
+}
+
+pub type Store<H = Hash256, const N: usize = SKIP_LIST_LEN> = HashMap<H, Block<H, N>>;
+
+/// A backend that blocks can be read from and written to by hash, e.g., the store of a `Tree`.
+///
+/// The skip-list walks (`get_ancestor_hash_at_slot`, `find_least_common_ancestor` and
+/// `find_least_common_ancestor_many`) only need `get`, so they can run against blocks that are
+/// not held in memory. A backend that has to decode its blocks returns them owned; the in-memory
+/// `Store` lends them out without copying. `FileStore` (with the `file-store` feature) keeps its
+/// blocks on disk.
+pub trait BlockStore<H: BlockHash, const N: usize> {
+    /// Returns the block stored under `hash`, if any.
+    fn get(&self, hash: &H) -> Option<Cow<'_, Block<H, N>>>;
+
+    /// Stores `block` under `hash`, replacing any block already stored there.
+    fn insert(&mut self, hash: H, block: Block<H, N>);
+
+    /// Removes the block stored under `hash`, if any.
+    fn remove(&mut self, hash: &H);
+
+    /// Removes every block.
+    fn clear(&mut self);
+
+    /// Returns the number of blocks stored.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if no blocks are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if a block is stored under `hash`.
+    fn contains(&self, hash: &H) -> bool {
+        self.get(hash).is_some()
+    }
+
+    /// Returns an estimate of the heap memory held by the backend, in bytes, for
+    /// `Tree::approx_memory_bytes`. Defaults to `0`, e.g., for blocks that are not held in memory.
+    fn approx_memory_bytes(&self) -> usize {
+        0
+    }
+}
+
+impl<H: BlockHash, const N: usize> BlockStore<H, N> for Store<H, N> {
+    fn get(&self, hash: &H) -> Option<Cow<'_, Block<H, N>>> {
+        HashMap::get(self, hash).map(Cow::Borrowed)
+    }
+
+    fn insert(&mut self, hash: H, block: Block<H, N>) {
+        HashMap::insert(self, hash, block);
+    }
+
+    fn remove(&mut self, hash: &H) {
+        HashMap::remove(self, hash);
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn contains(&self, hash: &H) -> bool {
+        self.contains_key(hash)
+    }
+
+    fn approx_memory_bytes(&self) -> usize {
+        self.capacity() * std::mem::size_of::<(H, Block<H, N>)>()
+    }
+}
+
+pub trait StoreExt<H, const N: usize> {
+    /// Inserts `block` under `hash`, after checking that it fits onto the blocks already stored.
+    ///
+    /// Every entry of the skip list must be a known block (entries equal to `hash`, as used by
+    /// genesis blocks, are exempt) and `block.slot` must be greater than its parent's slot.
+    fn insert_block(&mut self, hash: H, block: Block<H, N>) -> Result<(), H>;
+}
+
+impl<H: BlockHash, const N: usize, S: BlockStore<H, N>> StoreExt<H, N> for S {
+    fn insert_block(&mut self, hash: H, block: Block<H, N>) -> Result<(), H> {
+        for entry in block
+            .ancestor_skip_list
+            .iter()
+            .filter(|entry| **entry != hash)
+        {
+            if !self.contains(entry) {
+                return Err(Error::UnknownBlock(*entry));
+            }
+        }
+
+        let parent_hash = block.ancestor_skip_list[0];
+        if parent_hash != hash {
+            let parent_slot = self
+                .get(&parent_hash)
+                .ok_or(Error::UnknownBlock(parent_hash))?
+                .slot;
+            if block.slot <= parent_slot {
+                return Err(Error::NonMonotonicSlot {
+                    slot: block.slot,
+                    parent_slot,
+                });
+            }
+        }
+
+        self.insert(hash, block);
+
+        Ok(())
+    }
+}
+
+/// A `BlockStore` that keeps its blocks in a file rather than in memory, e.g., for the full
+/// history of a chain. Only the position of each block in the file is held in memory, and `get`
+/// reads and decodes the block (with `ssz::Decode`) on every call.
+///
+/// The file starts with `N` as eight little-endian bytes and is followed by a log of writes,
+/// each a tag byte and then:
+///
+/// - `0`: the 32-byte hash and the SSZ encoding of a block stored under it.
+/// - `1`: the 32-byte hash of a block removed.
+/// - `2`: nothing, for `clear`.
+///
+/// Writes are only ever appended, which makes a clone a cheap copy-on-write snapshot: clones
+/// share the file but each has its own index, so a clone does not see the blocks written through
+/// another after it was cloned (e.g., the store of a `Tree::snapshot`). `FileStore::open` replays
+/// the writes of every clone in the order in which they were made.
+///
+/// # Panics
+///
+/// `BlockStore` cannot report errors, so `insert`, `remove` and `clear` panic if the file cannot
+/// be written, and `get` returns `None` if a block cannot be read.
+#[cfg(feature = "file-store")]
+#[derive(Debug, Clone)]
+pub struct FileStore<const N: usize = SKIP_LIST_LEN> {
+    file: Arc<Mutex<std::fs::File>>,
+    /// The position in the file of the encoding of the block stored under each hash.
+    offsets: HashMap<Hash256, u64>,
+}
+
+#[cfg(feature = "file-store")]
+impl<const N: usize> FileStore<N> {
+    const INSERT: u8 = 0;
+    const REMOVE: u8 = 1;
+    const CLEAR: u8 = 2;
+
+    /// Opens the store kept in the file at `path`, which is created if it does not exist.
+    ///
+    /// Returns an error if the file cannot be read or written, if it ends part way through a
+    /// write, or if it holds something other than a store with skip lists of `N` entries.
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        use std::io::{BufReader, ErrorKind, Read, Write};
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let header = (N as u64).to_le_bytes();
+        let mut offsets = HashMap::new();
+
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header)?;
+        } else {
+            let mut reader = BufReader::new(&file);
+            let mut found = [0; 8];
+            reader.read_exact(&mut found)?;
+            if found != header {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "the file is not a store with skip lists of this length",
+                ));
+            }
+
+            let mut offset = header.len() as u64;
+            let mut tag = [0];
+            let mut hash = [0; 32];
+            let mut block = vec![0; block_ssz_len(N)];
+            while reader.read(&mut tag)? == 1 {
+                offset += 1;
+                match tag[0] {
+                    Self::INSERT => {
+                        reader.read_exact(&mut hash)?;
+                        offset += 32;
+                        offsets.insert(Hash256::from(hash), offset);
+                        // Check that the block is all there, without decoding it.
+                        reader.read_exact(&mut block)?;
+                        offset += block.len() as u64;
+                    }
+                    Self::REMOVE => {
+                        reader.read_exact(&mut hash)?;
+                        offset += 32;
+                        offsets.remove(&Hash256::from(hash));
+                    }
+                    Self::CLEAR => offsets.clear(),
+                    _ => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "the file has a write of an unknown kind",
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            offsets,
+        })
+    }
+
+    /// Flushes every write to the disk.
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.lock_file().sync_data()
+    }
+
+    fn lock_file(&self) -> std::sync::MutexGuard<'_, std::fs::File> {
+        self.file.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Appends `record` to the file and returns the position at which it starts.
+    fn append(&self, record: &[u8]) -> u64 {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut file = self.lock_file();
+        let offset = file
+            .seek(SeekFrom::End(0))
+            .expect("should seek to the end of the store");
+        file.write_all(record).expect("should write to the store");
+        offset
+    }
+}
+
+#[cfg(feature = "file-store")]
+impl<const N: usize> BlockStore<Hash256, N> for FileStore<N> {
+    fn get(&self, hash: &Hash256) -> Option<Cow<'_, Block<Hash256, N>>> {
+        use ssz::Decode;
+        use std::io::{Read, Seek, SeekFrom};
+
+        let offset = *self.offsets.get(hash)?;
+        let mut bytes = vec![0; block_ssz_len(N)];
+        {
+            let mut file = self.lock_file();
+            file.seek(SeekFrom::Start(offset)).ok()?;
+            file.read_exact(&mut bytes).ok()?;
+        }
+        Block::from_ssz_bytes(&bytes).ok().map(Cow::Owned)
+    }
+
+    fn insert(&mut self, hash: Hash256, block: Block<Hash256, N>) {
+        use ssz::Encode;
+
+        let mut record = Vec::with_capacity(1 + 32 + block_ssz_len(N));
+        record.push(Self::INSERT);
+        record.extend_from_slice(hash.as_bytes());
+        block.ssz_append(&mut record);
+        let offset = self.append(&record);
+        self.offsets.insert(hash, offset + 1 + 32);
+    }
+
+    fn remove(&mut self, hash: &Hash256) {
+        if self.offsets.remove(hash).is_some() {
+            let mut record = vec![Self::REMOVE];
+            record.extend_from_slice(hash.as_bytes());
+            self.append(&record);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.offsets.clear();
+        self.append(&[Self::CLEAR]);
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    fn contains(&self, hash: &Hash256) -> bool {
+        self.offsets.contains_key(hash)
+    }
+
+    fn approx_memory_bytes(&self) -> usize {
+        self.offsets.capacity() * std::mem::size_of::<(Hash256, u64)>()
+    }
+}
+
+/// Builds a `Store` one block at a time, building the skip list of each block from the blocks
+/// already added, e.g., to set up chains in tests.
+///
+/// The first error (e.g., a parent that has not been added) is kept and returned by `build`; any
+/// blocks after it are ignored.
+pub struct StoreBuilder<H = Hash256, const N: usize = SKIP_LIST_LEN> {
+    store: Store<H, N>,
+    error: Option<Error<H>>,
+}
+
+impl<H: BlockHash, const N: usize> Default for StoreBuilder<H, N> {
+    fn default() -> Self {
+        Self {
+            store: Store::default(),
+            error: None,
+        }
+    }
+}
+
+impl<H: BlockHash, const N: usize> StoreBuilder<H, N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `hash` as a genesis block at `slot`, see `Block::genesis`.
+    pub fn genesis(self, hash: H, slot: Slot) -> Self {
+        self.insert(hash, |_store| Ok(Block::genesis(hash, slot)))
+    }
+
+    /// Adds `hash` as a block at `slot` that is a child of `parent`, see `Block::new`.
+    ///
+    /// `build` returns `Error::UnknownBlock` if `parent` has not been added, and
+    /// `Error::NonMonotonicSlot` if `slot` is not greater than the slot of `parent`.
+    pub fn block(self, hash: H, parent: H, slot: Slot) -> Self {
+        self.insert(hash, |store| Block::new(slot, parent, store))
+    }
+
+    fn insert(
+        mut self,
+        hash: H,
+        block: impl FnOnce(&Store<H, N>) -> Result<Block<H, N>, H>,
+    ) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+
+        let result = if self.store.contains_key(&hash) {
+            Err(Error::DuplicateBlock(hash))
+        } else {
+            block(&self.store).and_then(|block| self.store.insert_block(hash, block))
+        };
+        self.error = result.err();
+        self
+    }
+
+    /// Returns the store, or the first error from adding a block.
+    pub fn build(self) -> Result<Store<H, N>, H> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.store),
+        }
+    }
+}
+
+/// A set of keys kept in ascending order, supporting lookup by index.
+///
+/// Backed by a sorted `Vec` so that `nth` is a direct index rather than a scan. Insertion is
+/// `O(n)` in the worst case, however new keys are typically the highest slot seen so far and are
+/// appended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedList<K>(Vec<K>);
+
+impl<K: Ord> Default for SortedList<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord> FromIterator<K> for SortedList<K> {
+    fn from_iter<I: IntoIterator<Item = K>>(keys: I) -> Self {
+        let mut list = Self::new();
+        list.extend(keys);
+        list
+    }
+}
+
+impl<K: Ord> Extend<K> for SortedList<K> {
+    /// Inserts every key in `keys`, sorting once rather than searching for each key.
+    fn extend<I: IntoIterator<Item = K>>(&mut self, keys: I) {
+        self.0.extend(keys);
+        self.0.sort_unstable();
+        self.0.dedup();
+    }
+}
+
+/// Serializes the keys as an ascending sequence.
+#[cfg(feature = "serde")]
+impl<K: Serialize> Serialize for SortedList<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+/// Deserializes a sequence of keys, which are sorted and deduplicated if they are not already.
+#[cfg(feature = "serde")]
+impl<'de, K: Ord + Deserialize<'de>> Deserialize<'de> for SortedList<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Vec::<K>::deserialize(deserializer).map(|keys| keys.into_iter().collect())
+    }
+}
+
+impl<K: Ord> SortedList<K> {
+    pub fn new() -> Self {
+        SortedList(Vec::new())
+    }
+
+    pub fn insert(&mut self, key: K) {
+        if let Err(i) = self.0.binary_search(&key) {
+            self.0.insert(i, key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn nth(&self, n: usize) -> Option<&K> {
+        self.0.get(n)
+    }
+
+    pub fn first(&self) -> Option<&K> {
+        self.0.first()
+    }
+
+    pub fn last(&self) -> Option<&K> {
+        self.0.last()
+    }
+
+    /// Removes `key`, returning `true` if it was present.
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.0.binary_search(key) {
+            Ok(i) => {
+                self.0.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.0.binary_search(key).is_ok()
+    }
+
+    /// Returns the keys in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = &K> + '_ {
+        self.0.iter()
+    }
+
+    /// Returns the keys in `range` in ascending order, e.g., the slots of every height between two
+    /// slots.
+    pub fn range(&self, range: Range<K>) -> impl Iterator<Item = &K> + '_ {
+        let start = self.0.partition_point(|k| *k < range.start);
+        let end = self.0.partition_point(|k| *k < range.end).max(start);
+        self.0[start..end].iter()
+    }
+
+    fn index_of(&self, key: &K) -> Option<usize> {
+        self.0.binary_search(key).ok()
+    }
+
+    /// Returns the number of keys less than or equal to `key`.
+    fn count_up_to(&self, key: &K) -> usize {
+        self.0.partition_point(|k| k <= key)
+    }
+}
+
+/// A least-recently-used cache of ancestor lookups, mapping `(start, slot)` to the ancestor of
+/// `start` at `slot`.
+pub struct AncestorCache<H> {
+    capacity: usize,
+    /// Each entry and the tick at which it was last used.
+    entries: HashMap<(H, Slot), (Option<H>, u64)>,
+    /// The key of each entry, ordered by the tick at which it was last used.
+    recency: BTreeMap<u64, (H, Slot)>,
+    tick: u64,
+}
+
+impl<H: BlockHash> AncestorCache<H> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the cached ancestor for `key`, marking it as the most recently used.
+    pub fn get(&mut self, key: &(H, Slot)) -> Option<Option<H>> {
+        let tick = self.next_tick();
+        let (ancestor, last_used) = self.entries.get_mut(key)?;
+        self.recency.remove(last_used);
+        self.recency.insert(tick, *key);
+        *last_used = tick;
+        Some(*ancestor)
+    }
+
+    /// Caches `ancestor` for `key`, evicting the least recently used entry if the cache is full.
+    pub fn insert(&mut self, key: (H, Slot), ancestor: Option<H>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+        if let Some((_ancestor, last_used)) = self.entries.insert(key, (ancestor, tick)) {
+            self.recency.remove(&last_used);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_tick, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.recency.insert(tick, key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethereum_types::H256 as Hash256;
+
+    /// Builds the skip list for a block at `slot` whose parent is `parent` by walking back
+    /// through parents one at a time.
+    fn skip_list(slot: Slot, parent: Hash256, store: &Store) -> [Hash256; SKIP_LIST_LEN] {
+        let mut list = [parent; SKIP_LIST_LEN];
+        for (i, entry) in list.iter_mut().enumerate() {
+            let target = slot.saturating_sub(1 << i);
+            let mut hash = parent;
+            while let Some(block) = store.get(&hash) {
+                let parent = block.ancestor_skip_list[0];
+                if block.slot <= target || parent == hash {
+                    break;
+                }
+                hash = parent;
+            }
+            *entry = hash;
+        }
+        list
+    }
+
+    /// Returns a tree containing only a genesis block at slot `0`, with the genesis block in the
+    /// store.
+    fn genesis() -> (Tree, Hash256) {
+        let genesis = Hash256::random();
+        (Tree::new(genesis, 0), genesis)
+    }
+
+    /// Adds a block at `slot` with the given `parent` to the store (but not to the tree).
+    fn add_block(tree: &mut Tree, parent: Hash256, slot: Slot) -> Hash256 {
+        let hash = Hash256::random();
+        let block = Block::new(slot, parent, &*tree.store).expect("parent should be in store");
+        Arc::make_mut(&mut tree.store).insert(hash, block);
+        hash
+    }
+
+    /// Returns a tree with a genesis block at slot `0` and a single chain of `len` blocks at
+    /// slots `1..=len` in the store (but not in the tree).
+    fn chain(len: usize) -> (Tree, Vec<Hash256>) {
+        let (mut tree, genesis) = genesis();
+
+        let mut hashes = vec![genesis];
+        for slot in 1..=len as Slot {
+            let hash = add_block(&mut tree, *hashes.last().unwrap(), slot);
+            hashes.push(hash);
+        }
+
+        (tree, hashes)
+    }
+
+    #[test]
+    fn sorted_list_nth() {
+        // Every key is distinct since 10_007 is prime, however they arrive out of order.
+        let keys = (0..10_000_u64)
+            .map(|i| (i * 7_919) % 10_007)
+            .collect::<Vec<_>>();
+
+        let mut list = SortedList::new();
+        for key in &keys {
+            list.insert(*key);
+        }
+        list.insert(keys[42]);
+
+        let mut expected = keys.clone();
+        expected.sort_unstable();
+
+        assert_eq!(list.len(), 10_000);
+        for (n, key) in expected.iter().enumerate() {
+            assert_eq!(list.nth(n), Some(key));
+            assert_eq!(list.index_of(key), Some(n));
+        }
+        assert_eq!(list.nth(10_000), None);
+    }
+
+    #[test]
+    fn sorted_list_remove() {
+        let mut list = SortedList::new();
+        list.insert(30_u64);
+        list.insert(10);
+        list.insert(20);
+
+        assert!(list.remove(&20));
+        assert!(!list.remove(&20));
+        assert!(!list.contains(&20));
+        assert!(list.contains(&10));
+
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.nth(0), Some(&10));
+        assert_eq!(list.nth(1), Some(&30));
+        assert_eq!(list.nth(2), None);
+    }
+
+    #[test]
+    fn sorted_list_iter_and_range() {
+        let mut list = SortedList::new();
+        for key in &[40_u64, 10, 30, 20, 50] {
+            list.insert(*key);
+        }
+
+        assert_eq!(
+            list.iter().copied().collect::<Vec<_>>(),
+            vec![10, 20, 30, 40, 50]
+        );
+        assert_eq!(
+            list.range(20..40).copied().collect::<Vec<_>>(),
+            vec![20, 30]
+        );
+        assert_eq!(
+            list.range(15..41).copied().collect::<Vec<_>>(),
+            vec![20, 30, 40]
+        );
+        assert_eq!(list.range(0..100).count(), 5);
+        assert_eq!(list.range(30..30).count(), 0);
+        assert_eq!(list.range(Range { start: 40, end: 20 }).count(), 0);
+        assert_eq!(list.range(60..70).count(), 0);
+    }
+
+    #[test]
+    fn sorted_list_from_iter_and_extend() {
+        let mut list = [30_u64, 10, 20, 10, 30]
+            .iter()
+            .copied()
+            .collect::<SortedList<_>>();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), vec![10, 20, 30]);
+
+        list.extend(vec![25, 5, 20, 40]);
+        assert_eq!(
+            list.iter().copied().collect::<Vec<_>>(),
+            vec![5, 10, 20, 25, 30, 40]
+        );
+        assert_eq!(list.len(), 6);
+        assert_eq!(list.index_of(&25), Some(3));
+    }
+
+    #[test]
+    fn block_new_builds_skip_list() {
+        let (tree, hashes) = chain(20);
+
+        for (slot, hash) in hashes.iter().enumerate().skip(1) {
+            let block = &tree.store[hash];
+            assert_eq!(block.slot, slot as Slot);
+            assert_eq!(block.ancestor_skip_list[0], hashes[slot - 1]);
+            assert_eq!(
+                block.ancestor_skip_list,
+                skip_list(block.slot, hashes[slot - 1], &tree.store)
+            );
+        }
+
+        let tip = hashes[20];
+        for &slot in &[19, 18, 16, 13, 12, 7, 4, 1] {
+            assert_eq!(
+                get_ancestor_hash_at_slot(slot, tip, &*tree.store),
+                Ok(Some(hashes[slot as usize]))
+            );
+        }
+        assert_eq!(
+            get_ancestor_hash_at_slot(3, hashes[10], &*tree.store),
+            Ok(Some(hashes[3]))
+        );
+        assert_eq!(
+            get_ancestor_hash_at_slot(0, tip, &*tree.store),
+            Ok(Some(hashes[0]))
+        );
+    }
+
+    #[test]
+    fn block_eq_and_hash() {
+        let (tree, hashes) = chain(5);
+        let block = tree.store[&hashes[5]].clone();
+        assert_eq!(block, tree.store[&hashes[5]]);
+
+        let mut changed = block.clone();
+        changed.ancestor_skip_list[2] = hashes[3];
+        assert_ne!(changed, block);
+
+        let set = [block.clone(), changed, block]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn verify_skip_list_with_gaps() {
+        let (mut tree, genesis) = genesis();
+        assert!(tree.store[&genesis].verify_skip_list(&*tree.store));
+
+        let mut hashes = vec![genesis];
+        for &slot in &[1, 5, 6, 13, 20, 21, 40, 100] {
+            let hash = add_block(&mut tree, *hashes.last().unwrap(), slot);
+            let block = &tree.store[&hash];
+            assert!(block.verify_skip_list(&*tree.store));
+            assert_eq!(
+                block.ancestor_skip_list,
+                skip_list(slot, block.ancestor_skip_list[0], &tree.store)
+            );
+            hashes.push(hash);
+        }
+
+        // Naive doubling takes entry `i` to be entry `i - 1` of entry `i - 1`, which is only
+        // right when no slots are skipped.
+        let tip = *hashes.last().unwrap();
+        let mut doubled = Block::new(101, tip, &*tree.store).expect("should build block");
+        for i in 1..SKIP_LIST_LEN {
+            let previous = doubled.ancestor_skip_list[i - 1];
+            doubled.ancestor_skip_list[i] = tree.store[&previous].ancestor_skip_list[i - 1];
+        }
+        assert!(!doubled.verify_skip_list(&*tree.store));
+
+        let block = Block::with_parent(101, tip);
+        assert!(!block.verify_skip_list(&*tree.store));
+        assert!(!Block::with_parent(1, Hash256::random()).verify_skip_list(&*tree.store));
+    }
+
+    /// Builds a chain of `len` blocks after genesis using skip lists of length `N`.
+    fn chain_with_skip_list_len<const N: usize>(len: usize) -> (Store<Hash256, N>, Vec<Hash256>) {
+        let mut store = Store::<Hash256, N>::new();
+        let genesis = Hash256::random();
+        store.insert(genesis, Block::genesis(genesis, 0));
+
+        let mut hashes = vec![genesis];
+        for slot in 1..=len as Slot {
+            let hash = Hash256::random();
+            let block = Block::new(slot, *hashes.last().unwrap(), &store)
+                .expect("parent should be in store");
+            store.insert(hash, block);
+            hashes.push(hash);
+        }
+
+        (store, hashes)
+    }
+
+    fn check_ancestors_with_skip_list_len<const N: usize>() {
+        let (store, hashes) = chain_with_skip_list_len::<N>(600);
+        let tip = hashes[600];
+
+        for &slot in &[599, 512, 300, 257, 100, 1, 0] {
+            assert_eq!(
+                get_ancestor_hash_at_slot(slot, tip, &store),
+                Ok(Some(hashes[slot as usize]))
+            );
+        }
+        assert_eq!(
+            find_least_common_ancestor(tip, hashes[77], &store),
+            Ok(Some(hashes[77]))
+        );
+    }
+
+    #[test]
+    fn skip_list_len_8() {
+        check_ancestors_with_skip_list_len::<8>();
+    }
+
+    #[test]
+    fn skip_list_len_24() {
+        check_ancestors_with_skip_list_len::<24>();
+    }
+
+    /// A `BlockStore` that hands out copies of its blocks, as a backend that decodes them from
+    /// disk would.
+    #[derive(Clone)]
+    struct OwnedStore(Store);
+
+    impl BlockStore<Hash256, SKIP_LIST_LEN> for OwnedStore {
+        fn get(&self, hash: &Hash256) -> Option<Cow<'_, Block>> {
+            self.0.get(hash).cloned().map(Cow::Owned)
+        }
+
+        fn insert(&mut self, hash: Hash256, block: Block) {
+            self.0.insert(hash, block);
+        }
+
+        fn remove(&mut self, hash: &Hash256) {
+            self.0.remove(hash);
+        }
+
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    /// Checks that the ancestor walks over `backend` agree with those over the store of `tree`,
+    /// after copying the blocks of `hashes` into `backend`.
+    fn check_backend_agrees<S: BlockStore<Hash256, SKIP_LIST_LEN>>(
+        mut backend: S,
+        tree: &Tree,
+        hashes: &[Hash256],
+    ) {
+        for hash in hashes {
+            backend.insert(*hash, tree.store[hash].clone());
+        }
+
+        for &start in hashes {
+            for slot in 0..=31 {
+                assert_eq!(
+                    get_ancestor_hash_at_slot(slot, start, &backend),
+                    get_ancestor_hash_at_slot(slot, start, &*tree.store)
+                );
+            }
+            for &other in hashes {
+                assert_eq!(
+                    find_least_common_ancestor(start, other, &backend),
+                    find_least_common_ancestor(start, other, &*tree.store)
+                );
+            }
+        }
+        assert_eq!(
+            get_ancestor_hash_at_slot(1, Hash256::zero(), &backend),
+            Err(Error::UnknownBlock(Hash256::zero()))
+        );
+    }
+
+    #[test]
+    fn block_store_backends_agree() {
+        let (mut tree, mut hashes) = chain(20);
+        let mut parent = hashes[9];
+        for slot in &[12, 13, 17, 30] {
+            parent = add_block(&mut tree, parent, *slot);
+            hashes.push(parent);
+        }
+
+        check_backend_agrees(OwnedStore(Store::new()), &tree, &hashes);
+        #[cfg(feature = "file-store")]
+        {
+            let dir = tempfile::tempdir().expect("should create directory");
+            let file = FileStore::open(dir.path().join("blocks")).expect("should open store");
+            check_backend_agrees(file, &tree, &hashes);
+        }
+    }
+
+    /// Imports two forks into a tree backed by `store` and checks the head before and after
+    /// pruning one of them, so that each backend is run through the same imports and queries.
+    fn check_tree_with_store<S: BlockStore<Hash256, SKIP_LIST_LEN> + Clone>(
+        store: S,
+    ) -> Tree<Hash256, SKIP_LIST_LEN, S> {
+        let hash = Hash256::from_low_u64_be;
+        let mut tree = TreeBuilder::new()
+            .root(hash(1))
+            .with_store(store)
+            .build()
+            .expect("should build tree");
+
+        // Block `5` is the tip of a fork that branches off at block `2`, and block `6` of another.
+        for &(block, parent, slot) in &[(2, 1, 2), (3, 2, 3), (4, 3, 5), (5, 4, 9), (6, 2, 12)] {
+            tree.on_block(hash(block), Block::with_parent(slot, hash(parent)))
+                .expect("should import block");
+        }
+        assert_eq!(tree.store.len(), 6);
+        assert_eq!(tree.ancestor_at_slot(hash(5), 4), Some(hash(3)));
+        assert_eq!(
+            find_least_common_ancestor(hash(5), hash(6), &*tree.store),
+            Ok(Some(hash(2)))
+        );
+
+        tree.process_attestation(0, hash(5), 9)
+            .expect("should process attestation");
+        tree.process_attestation(1, hash(5), 9)
+            .expect("should process attestation");
+        tree.process_attestation(2, hash(6), 12)
+            .expect("should process attestation");
+        assert_eq!(tree.find_head(hash(1)), Some(hash(5)));
+
+        tree.prune(hash(3)).expect("should prune");
+        assert_eq!(tree.find_head(tree.root()), Some(hash(5)));
+        assert!(!tree.contains_block(&hash(6)));
+        // Pruning leaves the blocks in the store.
+        assert_eq!(
+            tree.ancestors(hash(5)).collect::<Vec<_>>(),
+            vec![hash(5), hash(4), hash(3), hash(2), hash(1)]
+        );
+
+        // A snapshot does not see the blocks imported after it was taken.
+        let snapshot = tree.snapshot_cow();
+        tree.on_block(hash(7), Block::with_parent(10, hash(5)))
+            .expect("should import block");
+        assert_eq!(tree.find_head(tree.root()), Some(hash(7)));
+        assert_eq!(snapshot.ancestors(hash(7)).count(), 0);
+        assert_eq!(snapshot.find_head(snapshot.root()), Some(hash(5)));
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        tree
+    }
+
+    #[test]
+    fn tree_with_in_memory_store() {
+        check_tree_with_store(Store::new());
+        check_tree_with_store(OwnedStore(Store::new()));
+    }
+
+    #[cfg(feature = "file-store")]
+    #[test]
+    fn tree_with_file_store() {
+        let dir = tempfile::tempdir().expect("should create directory");
+        let path = dir.path().join("blocks");
+        let in_memory = check_tree_with_store(Store::new());
+        let on_disk = check_tree_with_store(FileStore::open(&path).expect("should open store"));
+        on_disk.store.sync().expect("should sync store");
+        assert!(on_disk.store.approx_memory_bytes() < in_memory.store.approx_memory_bytes());
+
+        // Reopening the file finds every block that was imported.
+        let reopened = FileStore::<SKIP_LIST_LEN>::open(&path).expect("should reopen store");
+        assert_eq!(reopened.len(), in_memory.store.len());
+        for (hash, block) in in_memory.store.iter() {
+            assert_eq!(reopened.get(hash).as_deref(), Some(block));
+        }
+    }
+
+    #[cfg(feature = "file-store")]
+    #[test]
+    fn file_store_reopen() {
+        let dir = tempfile::tempdir().expect("should create directory");
+        let path = dir.path().join("blocks");
+        let (tree, hashes) = chain(5);
+
+        let mut store = FileStore::open(&path).expect("should open store");
+        for hash in &hashes {
+            store
+                .insert_block(*hash, tree.store[hash].clone())
+                .expect("should insert block");
+        }
+        store.remove(&hashes[5]);
+        // A replaced block is read back as the last one written.
+        let moved = Block::with_parent(9, hashes[3]);
+        store.insert(hashes[4], moved.clone());
+
+        let mut reopened = FileStore::<SKIP_LIST_LEN>::open(&path).expect("should reopen store");
+        assert_eq!(reopened.len(), 5);
+        assert!(!reopened.contains(&hashes[5]));
+        assert_eq!(reopened.get(&hashes[4]).as_deref(), Some(&moved));
+        assert_eq!(
+            reopened.get(&hashes[3]).as_deref(),
+            Some(&tree.store[&hashes[3]])
+        );
+
+        reopened.clear();
+        assert!(FileStore::<SKIP_LIST_LEN>::open(&path)
+            .expect("should reopen store")
+            .is_empty());
+
+        // The skip lists of the file are not of this length.
+        assert_eq!(
+            FileStore::<8>::open(&path)
+                .map(|_| ())
+                .map_err(|e| e.kind()),
+            Err(std::io::ErrorKind::InvalidData)
+        );
+    }
+
+    /// A `BlockStore` that counts the calls to `get`.
+    struct CountingStore {
+        store: Store,
+        gets: std::cell::Cell<usize>,
+    }
+
+    impl CountingStore {
+        fn new(store: Store) -> Self {
+            Self {
+                store,
+                gets: std::cell::Cell::new(0),
+            }
+        }
+
+        /// Returns the number of calls to `get` since the last call to this.
+        fn take_gets(&self) -> usize {
+            self.gets.replace(0)
+        }
+    }
+
+    impl BlockStore<Hash256, SKIP_LIST_LEN> for CountingStore {
+        fn get(&self, hash: &Hash256) -> Option<Cow<'_, Block>> {
+            self.gets.set(self.gets.get() + 1);
+            BlockStore::get(&self.store, hash)
+        }
+
+        fn insert(&mut self, hash: Hash256, block: Block) {
+            self.store.insert(hash, block);
+        }
+
+        fn remove(&mut self, hash: &Hash256) {
+            self.store.remove(hash);
+        }
+
+        fn clear(&mut self) {
+            self.store.clear();
+        }
+
+        fn len(&self) -> usize {
+            self.store.len()
+        }
+    }
+
+    #[test]
+    fn ancestor_query_gets_are_logarithmic() {
+        let (tree, hashes) = chain(1000);
+        let tip = hashes[1000];
+        let store = CountingStore::new((*tree.store).clone());
+
+        for distance in 1..=1000_u64 {
+            let slot = 1000 - distance;
+            assert_eq!(
+                get_ancestor_hash_at_slot(slot, tip, &store),
+                Ok(Some(hashes[slot as usize]))
+            );
+            // One hop per set bit of the distance, plus the start and the final lookup.
+            let gets = store.take_gets();
+            assert_eq!(
+                gets,
+                distance.count_ones() as usize + 2,
+                "distance {}",
+                distance
+            );
+        }
+
+        // Walking back one parent at a time would take a thousand.
+        get_ancestor_hash_at_slot(0, tip, &store).unwrap();
+        assert!(store.take_gets() <= 12);
+
+        // The worked example of `get_ancestor_hash_at_or_before_slot`: hops of 8, 4 and 1.
+        get_ancestor_hash_at_slot(87, hashes[100], &store).unwrap();
+        assert_eq!(store.take_gets(), 5);
+    }
+
+    #[test]
+    fn ancestor_query_gets_are_bounded_by_log_distance() {
+        let len = 1 << 16;
+        let (tree, hashes) = chain(len);
+        let tip = hashes[len];
+        let store = CountingStore::new((*tree.store).clone());
+        let bound = |distance: u64| 63 - distance.leading_zeros() as usize + 3;
+
+        // Distances on either side of each power of two reachable by a single skip.
+        for k in 0..SKIP_LIST_LEN as u32 {
+            for &distance in &[(1 << k) - 1, 1 << k, (1 << k) + 1] {
+                if distance == 0 {
+                    continue;
+                }
+                let slot = len as Slot - distance;
+                assert_eq!(
+                    get_ancestor_hash_at_slot(slot, tip, &store),
+                    Ok(Some(hashes[slot as usize]))
+                );
+                let gets = store.take_gets();
+                assert!(
+                    gets <= bound(distance),
+                    "distance {}: {} gets",
+                    distance,
+                    gets
+                );
+            }
+        }
+
+        // Skipped slots only shorten the walk.
+        let (mut tree, genesis) = genesis();
+        let mut parent = genesis;
+        for slot in (3..=3000).step_by(3) {
+            parent = add_block(&mut tree, parent, slot);
+        }
+        let store = CountingStore::new((*tree.store).clone());
+        for distance in 1..3000 {
+            get_ancestor_hash_at_slot(3000 - distance, parent, &store).unwrap();
+            let gets = store.take_gets();
+            assert!(
+                gets <= bound(distance),
+                "distance {}: {} gets",
+                distance,
+                gets
+            );
+        }
+    }
+
+    #[test]
+    fn get_ancestor_hash_at_slot_boundaries() {
+        let (tree, hashes) = chain(40);
+        let tip = hashes[40];
+
+        // A block is its own ancestor at its own slot.
+        assert_eq!(
+            get_ancestor_hash_at_slot(40, tip, &*tree.store),
+            Ok(Some(tip))
+        );
+        assert_eq!(
+            get_ancestor_hash_at_slot(0, hashes[0], &*tree.store),
+            Ok(Some(hashes[0]))
+        );
+        // A block is canonical on its own chain for every slot after it.
+        assert_eq!(
+            get_ancestor_hash_at_slot(41, tip, &*tree.store),
+            Ok(Some(tip))
+        );
+
+        assert_eq!(
+            get_ancestor_hash_at_slot(39, tip, &*tree.store),
+            Ok(Some(hashes[39]))
+        );
+        // `40 - 3 = 37` requires hops of 32, 4 and 1 slots.
+        assert_eq!(
+            get_ancestor_hash_at_slot(3, tip, &*tree.store),
+            Ok(Some(hashes[3]))
+        );
+    }
+
+    #[test]
+    fn tree_builder() {
+        let root = Hash256::random();
+        let built = TreeBuilder::new()
+            .root(root)
+            .genesis_slot(0)
+            .build()
+            .expect("should build tree");
+        let new = Tree::new(root, 0);
+        assert_eq!(built.root, new.root);
+        assert_eq!(built.store, new.store);
+        assert_eq!(built.nodes, new.nodes);
+        assert_eq!(built.slots_at_height, new.slots_at_height);
+        assert_eq!(built.blocks_at_height, new.blocks_at_height);
+        assert_eq!(built.head, new.head);
+        assert_eq!(built.current_slot(), new.current_slot());
+
+        // The genesis slot is a slot, not a height: the root is still at height `0`.
+        let late = TreeBuilder::new()
+            .root(root)
+            .genesis_slot(5)
+            .build()
+            .expect("should build tree");
+        assert_eq!(late.store.get(&root), Some(&Block::genesis(root, 5)));
+        assert_eq!(late.blocks_at_height(0), &[root]);
+        assert_eq!(late.min_slot(), Some(5));
+        assert_eq!(late.check_invariants(), Ok(()));
+
+        let (tree, hashes) = chain(3);
+        let store = (*tree.store).clone();
+        let mut from_store = TreeBuilder::new()
+            .root(hashes[0])
+            .with_store(store.clone())
+            .build()
+            .expect("should build tree");
+        from_store
+            .add_node(hashes[3], hashes[3])
+            .expect("should add block");
+        assert_eq!(from_store.find_head(hashes[0]), Some(hashes[3]));
+
+        assert_eq!(
+            TreeBuilder::<Hash256>::new().build().map(|_| ()),
+            Err(Error::MissingRoot)
+        );
+        assert_eq!(
+            TreeBuilder::new()
+                .root(root)
+                .with_store(store.clone())
+                .build()
+                .map(|_| ()),
+            Err(Error::UnknownBlock(root))
+        );
+        assert_eq!(
+            TreeBuilder::new()
+                .root(hashes[1])
+                .genesis_slot(0)
+                .with_store(store)
+                .build()
+                .map(|_| ()),
+            Err(Error::InconsistentStore)
+        );
+    }
+
+    #[test]
+    fn tree_builder_skip_list_len() {
+        let root = Hash256::random();
+        let short = TreeBuilder::new()
+            .root(root)
+            .genesis_slot(3)
+            .skip_list_len::<4>()
+            .build()
+            .expect("should build tree");
+        assert_eq!(
+            short.store.get(&root),
+            Some(&Block::<_, 4>::genesis(root, 3))
+        );
+        assert_eq!(short.check_invariants(), Ok(()));
+
+        // The skip lists of a given store are built again, and agree with the original on the
+        // entries that both have.
+        let (tree, hashes) = chain(40);
+        let mut relinked = TreeBuilder::new()
+            .root(hashes[0])
+            .with_store((*tree.store).clone())
+            .skip_list_len::<4>()
+            .build()
+            .expect("should build tree");
+        for hash in &hashes {
+            let block = &relinked.store[hash];
+            assert_eq!(block.ancestor_skip_list().len(), 4);
+            assert_eq!(
+                block.ancestor_skip_list(),
+                &tree.store[hash].ancestor_skip_list()[..4]
+            );
+            assert!(block.verify_skip_list(&*relinked.store));
+        }
+        relinked
+            .add_node(hashes[40], hashes[40])
+            .expect("should add block");
+        assert_eq!(relinked.find_head(hashes[0]), Some(hashes[40]));
+        assert_eq!(
+            get_ancestor_hash_at_slot(3, hashes[40], &*relinked.store),
+            Ok(Some(hashes[3]))
+        );
+
+        let mut broken = (*tree.store).clone();
+        broken.remove(&hashes[20]);
+        assert_eq!(
+            TreeBuilder::new()
+                .root(hashes[0])
+                .with_store(broken)
+                .skip_list_len::<4>()
+                .build()
+                .map(|_| ()),
+            Err(Error::UnknownBlock(hashes[20]))
+        );
+    }
+
+    #[test]
+    fn default() {
+        let mut tree = Tree::default();
+        let sentinel = Hash256::zero();
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.root(), sentinel);
+        assert_eq!(tree.find_head(sentinel), Some(sentinel));
+        assert_eq!(
+            tree.update_head().map(|update| update.new_head),
+            Ok(sentinel)
+        );
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let genesis = Hash256::random();
+        tree.reset(genesis, 4);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.find_head(genesis), Some(genesis));
+        assert!(!tree.store.contains_key(&sentinel));
+    }
+
+    #[test]
+    fn reset() {
+        let (mut tree, hashes, [_a, a_tip, _b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.update_head().expect("should update head");
+
+        let root = Hash256::random();
+        tree.reset(root, 7);
+
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.find_head(root), Some(root));
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(root));
+        assert_eq!(tree.store.get(&root), Some(&Block::genesis(root, 7)));
+        assert_eq!(tree.store.get(&hashes[0]), None);
+        assert_eq!(tree.latest_message(0), None);
+        assert_eq!(tree.height_of(root), Some(0));
+        assert_eq!(tree.current_slot(), 7);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let child = add_block(&mut tree, root, 8);
+        tree.add_node(child, child).expect("should add block");
+        assert_eq!(tree.find_head(root), Some(child));
+    }
+
+    #[test]
+    fn new_stores_genesis() {
+        let root = Hash256::random();
+        let tree = Tree::new(root, 0);
+
+        assert_eq!(tree.store.get(&root), Some(&Block::genesis(root, 0)));
+        assert_eq!(
+            get_ancestor_hash_at_slot(0, root, &*tree.store),
+            Ok(Some(root))
+        );
+        assert_eq!(
+            get_ancestor_hash_at_slot(1, root, &*tree.store),
+            Ok(Some(root))
+        );
+
+        // A genesis block after slot `0` has no ancestors before its slot.
+        let late = Hash256::random();
+        let tree = Tree::new(late, 3);
+        assert_eq!(
+            get_ancestor_hash_at_slot(3, late, &*tree.store),
+            Ok(Some(late))
+        );
+        assert_eq!(get_ancestor_hash_at_slot(2, late, &*tree.store), Ok(None));
+        assert_eq!(get_ancestor_hash_at_slot(0, late, &*tree.store), Ok(None));
+    }
+
+    #[test]
+    fn ancestor_at_slot_with_gaps() {
+        let (mut tree, genesis) = genesis();
+        // Blocks at slots 1, 2, 5, 6, 9 and 17.
+        let mut hashes = vec![genesis];
+        for &slot in &[1, 2, 5, 6, 9, 17] {
+            let parent = *hashes.last().unwrap();
+            hashes.push(add_block(&mut tree, parent, slot));
+        }
+        let tip = hashes[6];
+
+        assert_eq!(tree.ancestor_at_slot(tip, 17), Some(tip));
+        assert_eq!(tree.ancestor_at_slot(tip, 9), Some(hashes[5]));
+        assert_eq!(tree.ancestor_at_slot(tip, 6), Some(hashes[4]));
+        assert_eq!(tree.ancestor_at_slot(tip, 5), Some(hashes[3]));
+        assert_eq!(tree.ancestor_at_slot(tip, 1), Some(hashes[1]));
+        assert_eq!(tree.ancestor_at_slot(tip, 0), Some(genesis));
+        assert_eq!(tree.ancestor_at_slot(hashes[5], 5), Some(hashes[3]));
+
+        // A skipped slot returns the last block before the gap. Slots 13 and 16 are a power of two
+        // before the tip, so a single hop lands on that block.
+        for &(skipped, canonical) in &[(3, 2), (4, 2), (7, 4), (8, 4), (10, 5), (13, 5), (16, 5)] {
+            assert_eq!(tree.ancestor_at_slot(tip, skipped), Some(hashes[canonical]));
+        }
+        assert_eq!(tree.ancestor_at_slot(hashes[5], 7), Some(hashes[4]));
+        assert_eq!(tree.ancestor_at_slot(tip, 18), Some(tip));
+        assert_eq!(tree.ancestor_at_slot(Hash256::random(), 0), None);
+    }
+
+    #[test]
+    fn is_canonical() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+
+        for hash in &[hashes[0], hashes[1], hashes[2], a, a_tip] {
+            assert_eq!(tree.is_canonical(*hash), Some(true));
+        }
+        assert_eq!(tree.is_canonical(b), Some(false));
+        assert_eq!(tree.is_canonical(Hash256::random()), None);
+
+        // A block that is only in the store is canonical if the head builds on it.
+        let c = add_block(&mut tree, a_tip, 5);
+        assert_eq!(tree.is_canonical(c), Some(false));
+        tree.add_node(c, c).expect("should add block");
+        assert_eq!(tree.is_canonical(c), Some(true));
+
+        // The head must build on the justified checkpoint, even if another fork is heavier.
+        tree.update_justified(Checkpoint { root: b, epoch: 1 })
+            .expect("should update justified");
+        assert_eq!(tree.is_canonical(b), Some(true));
+        assert_eq!(tree.is_canonical(c), Some(false));
+
+        tree.prune(hashes[2]).expect("should prune");
+        assert_eq!(tree.is_canonical(hashes[0]), Some(true));
+    }
+
+    #[test]
+    fn canonical_at_slot() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+
+        for (slot, canonical) in [
+            (0, hashes[0]),
+            (1, hashes[1]),
+            (2, hashes[2]),
+            (3, a),
+            (4, a_tip),
+        ] {
+            assert_eq!(tree.canonical_at_slot(slot), Some(canonical));
+        }
+        assert_eq!(tree.canonical_at_slot(5), None);
+
+        // Once the fork wins, the canonical chain follows it and ends at its slot.
+        tree.process_attestation(1, b, 3)
+            .expect("should process vote");
+        tree.process_attestation(2, b, 3)
+            .expect("should process vote");
+        assert_eq!(tree.canonical_at_slot(3), Some(b));
+        assert_eq!(tree.canonical_at_slot(2), Some(hashes[2]));
+        assert_eq!(tree.canonical_at_slot(4), None);
+
+        // The head must build on the justified checkpoint, even if another fork is heavier.
+        tree.process_attestation(3, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(4, a_tip, 4)
+            .expect("should process vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+        tree.update_justified(Checkpoint { root: b, epoch: 1 })
+            .expect("should update justified");
+        assert_eq!(tree.canonical_at_slot(3), Some(b));
+        assert_eq!(tree.canonical_at_slot(4), None);
+
+        tree.prune(hashes[2]).expect("should prune");
+        assert_eq!(tree.canonical_at_slot(2), Some(hashes[2]));
+        assert_eq!(tree.canonical_at_slot(1), None);
+    }
+
+    #[test]
+    fn get_ancestor_hash_at_skipped_slots() {
+        let (mut tree, genesis) = genesis();
+        let b3 = add_block(&mut tree, genesis, 3);
+        let b7 = add_block(&mut tree, b3, 7);
+
+        for &(slot, canonical) in &[
+            (0, genesis),
+            (1, genesis),
+            (2, genesis),
+            (3, b3),
+            (4, b3),
+            (5, b3),
+            (6, b3),
+            (7, b7),
+            (8, b7),
+        ] {
+            assert_eq!(
+                get_ancestor_hash_at_slot(slot, b7, &*tree.store),
+                Ok(Some(canonical))
+            );
+        }
+        assert_eq!(
+            get_ancestor_hash_at_slot(2, b3, &*tree.store),
+            Ok(Some(genesis))
+        );
+
+        // A chain whose genesis is after slot `0` has no canonical block before it.
+        tree.reset(genesis, 2);
+        let b3 = add_block(&mut tree, genesis, 3);
+        assert_eq!(get_ancestor_hash_at_slot(1, b3, &*tree.store), Ok(None));
+    }
+
+    #[test]
+    fn find_least_common_ancestor_of_related_blocks() {
+        let (tree, hashes) = chain(5);
+
+        assert_eq!(
+            find_least_common_ancestor(hashes[5], hashes[2], &*tree.store),
+            Ok(Some(hashes[2]))
+        );
+        assert_eq!(
+            find_least_common_ancestor(hashes[3], hashes[3], &*tree.store),
+            Ok(Some(hashes[3]))
+        );
+    }
+
+    #[test]
+    fn find_least_common_ancestor_at_genesis() {
+        let (mut tree, hashes) = chain(4);
+        let fork = add_block(&mut tree, hashes[0], 4);
+        let fork_tip = add_block(&mut tree, fork, 6);
+
+        for &(a, b) in &[
+            (hashes[4], fork),
+            (fork_tip, hashes[3]),
+            (hashes[1], fork_tip),
+        ] {
+            assert_eq!(
+                find_least_common_ancestor(a, b, &*tree.store),
+                Ok(Some(hashes[0]))
+            );
+        }
+        assert_eq!(
+            find_least_common_ancestor(hashes[0], fork_tip, &*tree.store),
+            Ok(Some(hashes[0]))
+        );
+        assert_eq!(
+            find_least_common_ancestor(hashes[4], fork_tip, &*tree.store),
+            Ok(Some(hashes[0]))
+        );
+
+        let other_genesis = Hash256::random();
+        Arc::make_mut(&mut tree.store).insert(other_genesis, Block::genesis(other_genesis, 0));
+        let other = add_block(&mut tree, other_genesis, 2);
+        assert_eq!(
+            find_least_common_ancestor(hashes[4], other, &*tree.store),
+            Ok(None)
+        );
+    }
+
+    /// Returns `hash` and all of its ancestors, found by walking back one parent at a time.
+    fn all_ancestors(hash: Hash256, store: &Store) -> HashSet<Hash256> {
+        let mut ancestors = HashSet::new();
+        let mut next = hash;
+        while ancestors.insert(next) {
+            next = store[&next].ancestor_skip_list[0];
+        }
+        ancestors
+    }
+
+    /// Returns the common ancestor of `a` and `b` with the greatest slot, found by intersecting
+    /// `all_ancestors` of each.
+    fn naive_least_common_ancestor(a: Hash256, b: Hash256, store: &Store) -> Option<Hash256> {
+        all_ancestors(a, store)
+            .intersection(&all_ancestors(b, store))
+            .max_by_key(|hash| store[hash].slot)
+            .copied()
+    }
+
+    /// Adds a chain to the store beneath `parent`, each block `slots` after the block before it,
+    /// returning the last block of the chain (or `parent` if `slots` is empty).
+    fn add_chain(tree: &mut Tree, parent: Hash256, slots: &[Slot]) -> Hash256 {
+        slots.iter().fold(parent, |parent, slots| {
+            let slot = tree.store[&parent].slot + slots;
+            add_block(tree, parent, slot)
+        })
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn find_least_common_ancestor_matches_naive(
+            prefix in proptest::collection::vec(1..4_u64, 0..32),
+            a_branch in proptest::collection::vec(1..4_u64, 0..32),
+            b_branch in proptest::collection::vec(1..4_u64, 0..32),
+        ) {
+            let (mut tree, genesis) = genesis();
+            let prefix = add_chain(&mut tree, genesis, &prefix);
+            let a = add_chain(&mut tree, prefix, &a_branch);
+            let b = add_chain(&mut tree, prefix, &b_branch);
+
+            let expected = naive_least_common_ancestor(a, b, &tree.store);
+            proptest::prop_assert_eq!(expected, Some(prefix));
+            proptest::prop_assert_eq!(find_least_common_ancestor(a, b, &*tree.store), Ok(expected));
+            proptest::prop_assert_eq!(find_least_common_ancestor(b, a, &*tree.store), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn find_least_common_ancestor_many() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let c = add_block(&mut tree, hashes[1], 2);
+        let other_genesis = Hash256::random();
+        Arc::make_mut(&mut tree.store).insert(other_genesis, Block::genesis(other_genesis, 0));
+        let store = &*tree.store;
+
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[a_tip, b, a], store),
+            Some(hashes[2])
+        );
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[a_tip, b, a, c], store),
+            Some(hashes[1])
+        );
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[a_tip, a, other_genesis], store),
+            None
+        );
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[a_tip], store),
+            Some(a_tip)
+        );
+        assert_eq!(super::find_least_common_ancestor_many(&[], store), None);
+
+        let missing = Hash256::random();
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[missing], store),
+            None
+        );
+        assert_eq!(
+            super::find_least_common_ancestor_many(&[a, b, missing], store),
+            None
+        );
+    }
+
+    #[test]
+    fn block_new_unknown_parent() {
+        let parent = Hash256::random();
+
+        assert!(matches!(
+            Block::new(1, parent, &Store::<Hash256, SKIP_LIST_LEN>::new()),
+            Err(Error::UnknownBlock(hash)) if hash == parent
+        ));
+    }
+
+    #[test]
+    fn block_accessors() {
+        let (tree, hashes) = chain(5);
+        let block = &tree.store[&hashes[5]];
+
+        assert_eq!(block.slot(), 5);
+        assert_eq!(block.ancestor_skip_list().len(), SKIP_LIST_LEN);
+        assert_eq!(block.ancestor_skip_list()[0], hashes[4]);
+        assert_eq!(block.ancestor_skip_list()[2], hashes[1]);
+    }
+
+    #[test]
+    fn block_genesis_points_to_itself() {
+        let hash = Hash256::random();
+        let block: Block = Block::genesis(hash, 3);
+
+        assert_eq!(block.slot, 3);
+        assert!(block.ancestor_skip_list.iter().all(|entry| *entry == hash));
+    }
+
+    #[test]
+    fn store_insert_block() {
+        let mut store: Store = Store::new();
+        let genesis = Hash256::random();
+        store
+            .insert_block(genesis, Block::genesis(genesis, 0))
+            .expect("should insert genesis");
+
+        let child = Hash256::random();
+        let block = Block::new(1, genesis, &store).expect("should build block");
+        store
+            .insert_block(child, block)
+            .expect("should insert child");
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(store[&child].ancestor_skip_list[0], genesis);
+    }
+
+    #[test]
+    fn store_insert_block_missing_ancestor() {
+        let mut store: Store = Store::new();
+        let genesis = Hash256::random();
+        store
+            .insert_block(genesis, Block::genesis(genesis, 0))
+            .expect("should insert genesis");
+
+        let missing = Hash256::random();
+        let mut block = Block::new(4, genesis, &store).expect("should build block");
+        block.ancestor_skip_list[1] = missing;
+
+        let hash = Hash256::random();
+        assert_eq!(
+            store.insert_block(hash, block),
+            Err(Error::UnknownBlock(missing))
+        );
+        assert!(!store.contains_key(&hash));
+    }
+
+    #[test]
+    fn store_insert_block_non_monotonic_slot() {
+        let mut store: Store = Store::new();
+        let genesis = Hash256::random();
+        store
+            .insert_block(genesis, Block::genesis(genesis, 5))
+            .expect("should insert genesis");
+
+        let block = Block::new(5, genesis, &store).expect("should build block");
+        assert_eq!(
+            store.insert_block(Hash256::random(), block),
+            Err(Error::NonMonotonicSlot {
+                slot: 5,
+                parent_slot: 5
+            })
+        );
+    }
+
+    #[test]
+    fn store_builder() {
+        // A chain `0..=8` that skips slot 5, and a fork `9..=11` from `3`.
+        let store: Store<u64> = StoreBuilder::new()
+            .genesis(0, 0)
+            .block(1, 0, 1)
+            .block(2, 1, 2)
+            .block(3, 2, 3)
+            .block(4, 3, 4)
+            .block(5, 4, 6)
+            .block(6, 5, 7)
+            .block(7, 6, 8)
+            .block(8, 7, 9)
+            .block(9, 3, 4)
+            .block(10, 9, 5)
+            .block(11, 10, 7)
+            .build()
+            .expect("should build store");
+
+        assert_eq!(store.len(), 12);
+        assert_eq!(get_ancestor_hash_at_slot(2, 8, &store), Ok(Some(2)));
+        // Slot 5 was skipped, so `4` was still canonical.
+        assert_eq!(get_ancestor_hash_at_slot(5, 8, &store), Ok(Some(4)));
+        assert_eq!(get_ancestor_hash_at_slot(5, 11, &store), Ok(Some(10)));
+        assert_eq!(get_ancestor_hash_at_slot(0, 11, &store), Ok(Some(0)));
+        assert_eq!(find_least_common_ancestor(8, 11, &store), Ok(Some(3)));
+        assert_eq!(find_least_common_ancestor(7, 5, &store), Ok(Some(5)));
+        for hash in 1..12 {
+            let block = &store[&hash];
+            let parent = block.ancestor_skip_list[0];
+            assert_eq!(
+                block.ancestor_skip_list,
+                Block::<u64>::new(block.slot, parent, &store)
+                    .unwrap()
+                    .ancestor_skip_list
+            );
+        }
+
+        let builder = || StoreBuilder::<u64>::new().genesis(0, 0).block(1, 0, 1);
+        assert_eq!(
+            builder().block(3, 2, 3).block(2, 1, 2).build(),
+            Err(Error::UnknownBlock(2))
+        );
+        assert_eq!(
+            builder().block(2, 1, 1).build(),
+            Err(Error::NonMonotonicSlot {
+                slot: 1,
+                parent_slot: 1
+            })
+        );
+        assert_eq!(
+            builder().block(1, 0, 2).build(),
+            Err(Error::DuplicateBlock(1))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn sorted_list_serde_round_trip() {
+        let list = [40, 3, 17, 0].iter().copied().collect::<SortedList<Slot>>();
+
+        let json = serde_json::to_string(&list).expect("should serialize list");
+        assert_eq!(json, "[0,3,17,40]");
+        let restored: SortedList<Slot> =
+            serde_json::from_str(&json).expect("should deserialize list");
+        assert_eq!(restored, list);
+
+        let unsorted: SortedList<Slot> =
+            serde_json::from_str("[17,3,40,3,0]").expect("should deserialize list");
+        assert_eq!(unsorted, list);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_chain_json() {
+        // Listed out of order, with a fork at slot 2 and a skipped slot on one side.
+        let json = r#"[
+            {"hash": 4, "parent": 2, "slot": 5},
+            {"hash": 0, "parent": null, "slot": 0},
+            {"hash": 2, "parent": 1, "slot": 2},
+            {"hash": 1, "parent": 0, "slot": 1},
+            {"hash": 3, "parent": 2, "slot": 3}
+        ]"#;
+        let mut tree = Tree::<u64>::from_chain_json(json).expect("should build tree");
+
+        assert_eq!(tree.root(), 0);
+        assert_eq!(tree.store.len(), 5);
+        assert_eq!(tree.leaves(), vec![3, 4]);
+        assert_eq!(tree.parent_of(4), Some(Some(2)));
+        assert_eq!(tree.ancestor_at_slot(4, 3), Some(2));
+        assert_eq!(tree.ancestor_at_slot(4, 1), Some(1));
+        tree.apply_score_change(3, 1).expect("should apply vote");
+        assert_eq!(tree.find_head(0), Some(3));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_chain_json_rejects_malformed_chains() {
+        let from_json = Tree::<u64>::from_chain_json;
+
+        // The parent of `2` is not listed.
+        assert_eq!(
+            from_json(
+                r#"[{"hash": 0, "parent": null, "slot": 0}, {"hash": 2, "parent": 1, "slot": 2}]"#
+            )
+            .map(|tree| tree.root()),
+            Err(Error::MissingParent(2))
+        );
+        assert_eq!(
+            from_json(
+                r#"[{"hash": 0, "parent": null, "slot": 0}, {"hash": 0, "parent": 0, "slot": 1}]"#
+            )
+            .map(|tree| tree.root()),
+            Err(Error::DuplicateBlock(0))
+        );
+        assert_eq!(
+            from_json(
+                r#"[{"hash": 0, "parent": null, "slot": 0}, {"hash": 1, "parent": 2, "slot": 1},
+                    {"hash": 2, "parent": 1, "slot": 2}]"#
+            )
+            .map(|tree| tree.root()),
+            Err(Error::Cycle(1))
+        );
+        assert_eq!(
+            from_json(
+                r#"[{"hash": 0, "parent": null, "slot": 0}, {"hash": 1, "parent": 2, "slot": 1},
+                    {"hash": 2, "parent": 0, "slot": 2}]"#
+            )
+            .map(|tree| tree.root()),
+            Err(Error::NonMonotonicSlot {
+                slot: 1,
+                parent_slot: 2
+            })
+        );
+        assert_eq!(
+            from_json("[]").map(|tree| tree.root()),
+            Err(Error::MissingRoot)
+        );
+        assert!(matches!(
+            from_json(r#"[{"hash": 0}]"#).map(|tree| tree.root()),
+            Err(Error::InvalidJson(_))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 3)
+            .expect("should apply vote");
+        tree.apply_score_change(b, 2).expect("should apply vote");
+
+        let state = serde_json::to_string(&tree.to_state()).expect("should serialize tree");
+        let store = serde_json::to_string(&*tree.store).expect("should serialize store");
+
+        let state = serde_json::from_str(&state).expect("should deserialize tree");
+        let store: Store = serde_json::from_str(&store).expect("should deserialize store");
+        let mut restored = Tree::from_state(state, store);
+
+        assert_eq!(restored.root(), tree.root());
+        assert_eq!(restored.find_head(hashes[0]), Some(a_tip));
+        assert_eq!(restored.find_head(hashes[0]), tree.find_head(hashes[0]));
+        assert_eq!(restored.nodes[&a].score, 3);
+        assert_eq!(
+            restored.store[&a_tip].ancestor_skip_list,
+            tree.store[&a_tip].ancestor_skip_list
+        );
+
+        // The restored tree should remain usable.
+        let tip = add_block(&mut restored, b, 4);
+        restored.add_node(tip, tip).expect("should add block");
+        restored
+            .apply_score_change(tip, 2)
+            .expect("should apply vote");
+        assert_eq!(restored.find_head(hashes[0]), Some(tip));
+    }
+
+    #[cfg(feature = "ssz")]
+    #[test]
+    fn ssz_round_trip() {
+        use ssz::{Decode, Encode};
+
+        let (tree, hashes) = chain(20);
+        let block = &tree.store[&hashes[20]];
+
+        let bytes = block.as_ssz_bytes();
+        assert_eq!(bytes.len(), 8 + 32 * SKIP_LIST_LEN);
+        assert_eq!(bytes[..8], 20_u64.to_le_bytes());
+        assert_eq!(bytes[8..40], *hashes[19].as_bytes());
+
+        let decoded = Block::from_ssz_bytes(&bytes).expect("should decode block");
+        assert_eq!(decoded.slot, block.slot);
+        assert_eq!(decoded.ancestor_skip_list, block.ancestor_skip_list);
+    }
+
+    #[cfg(feature = "ssz")]
+    #[test]
+    fn ssz_decode_truncated() {
+        use ssz::{Decode, DecodeError, Encode};
+
+        let (tree, hashes) = chain(3);
+        let bytes = tree.store[&hashes[3]].as_ssz_bytes();
+
+        for len in &[0, 7, 8, bytes.len() - 1] {
+            assert_eq!(
+                Block::<Hash256, SKIP_LIST_LEN>::from_ssz_bytes(&bytes[..*len]).err(),
+                Some(DecodeError::InvalidByteLength {
+                    len: *len,
+                    expected: bytes.len()
+                })
+            );
+        }
+    }
+
+    #[test]
     fn new() {
         let genesis_root = Hash256::random();
         let genesis_slot = 0;
 
-        let _t = Tree::new(genesis_root, genesis_slot);
+        let _t = Tree::new(genesis_root, genesis_slot);
+    }
+
+    #[test]
+    fn new_stores_genesis_node() {
+        let root = Hash256::random();
+        let tree = Tree::new(root, 0);
+
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.nodes[&root].block_hash, root);
+        assert_eq!(tree.nodes[&root].height, 0);
+        assert_eq!(tree.nodes[&root].parent_hash, None);
+    }
+
+    #[test]
+    fn new_at_later_slot() {
+        let root = Hash256::random();
+        let mut tree = Tree::new(root, 5);
+
+        // The root is listed at height `0`, not at its slot.
+        assert_eq!(tree.blocks_at_height(0), &[root]);
+        assert_eq!(tree.blocks_at_height(5), &[] as &[Hash256]);
+        assert_eq!(tree.height_of(root), Some(0));
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let child = add_block(&mut tree, root, 6);
+        tree.add_node(child, child).expect("should add block");
+        assert_eq!(tree.nodes[&child].parent_hash, Some(root));
+        assert_eq!(tree.height_of(child), Some(1));
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn add_node_three_level_chain() {
+        let (mut tree, hashes) = chain(2);
+
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add first block");
+        tree.add_node(hashes[2], hashes[2])
+            .expect("should add second block");
+
+        assert_eq!(tree.nodes[&hashes[1]].parent_hash, Some(hashes[0]));
+        assert_eq!(tree.nodes[&hashes[2]].parent_hash, Some(hashes[1]));
+
+        assert_eq!(tree.slots_at_height.len(), 3);
+        for (height, hash) in hashes.iter().enumerate() {
+            assert_eq!(tree.slot_at_height(height), Some(height as Slot));
+            assert_eq!(tree.blocks_at_height[&height], vec![*hash]);
+        }
+    }
+
+    #[test]
+    fn find_prev_in_tree_deep_chain() {
+        let (mut tree, hashes) = chain(12);
+
+        for (i, hash) in hashes.iter().enumerate().skip(1).take(9) {
+            tree.add_node(*hash, *hash).expect("should add block");
+            if i > 1 {
+                assert_eq!(tree.nodes[hash].parent_hash, Some(hashes[i - 1]));
+            }
+        }
+        assert_eq!(tree.slots_at_height.len(), 10);
+
+        for hash in &hashes[10..] {
+            let heights = 0..tree.slots_at_height.len();
+            let prev = tree
+                .find_prev_in_tree(*hash, heights)
+                .expect("should find prev in tree");
+            assert_eq!(prev, hashes[9]);
+        }
+    }
+
+    #[test]
+    fn find_prev_in_tree_thousands_of_heights() {
+        let (mut tree, genesis) = genesis();
+        let len = 5_000;
+
+        // The tree holds a chain at even slots. Another chain from genesis, only in the store,
+        // has a block at each odd slot, so every height is searched on the way down to genesis.
+        let mut even = vec![];
+        let (mut even_parent, mut odd_parent) = (genesis, genesis);
+        for slot in 1..=2 * len {
+            if slot % 2 == 0 {
+                even_parent = add_block(&mut tree, even_parent, slot);
+                even.push(even_parent);
+            } else {
+                odd_parent = add_block(&mut tree, odd_parent, slot);
+            }
+        }
+        tree.add_nodes(even.iter().map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+        assert_eq!(tree.slots_at_height.len(), len as usize + 1);
+
+        let heights = 0..tree.slots_at_height.len();
+        assert_eq!(
+            tree.find_prev_in_tree(odd_parent, heights.clone()),
+            Ok(genesis)
+        );
+        let tip = add_block(&mut tree, even_parent, 2 * len + 1);
+        assert_eq!(tree.find_prev_in_tree(tip, heights), Ok(even_parent));
+    }
+
+    #[test]
+    fn add_node_records_siblings_on_parent() {
+        let (mut tree, hashes) = chain(1);
+        let parent = hashes[1];
+        tree.add_node(parent, parent).expect("should add parent");
+
+        let a = add_block(&mut tree, parent, 2);
+        let b = add_block(&mut tree, parent, 2);
+        tree.add_node(a, a).expect("should add first sibling");
+        tree.add_node(b, b).expect("should add second sibling");
+
+        assert_eq!(tree.nodes[&parent].children.len(), 2);
+        assert_eq!(tree.nodes[&a].parent_hash, Some(parent));
+        assert_eq!(tree.nodes[&b].parent_hash, Some(parent));
+    }
+
+    #[test]
+    fn height_of() {
+        let (mut tree, hashes) = chain(4);
+        for hash in &[hashes[2], hashes[4]] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+        assert_eq!(tree.height_of(hashes[0]), Some(0));
+        assert_eq!(tree.height_of(hashes[2]), Some(1));
+        assert_eq!(tree.height_of(hashes[4]), Some(2));
+        assert_eq!(tree.height_of(hashes[3]), None);
+
+        // Adding a node at an earlier slot moves the later nodes up.
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+        assert_eq!(tree.height_of(hashes[1]), Some(1));
+        assert_eq!(tree.height_of(hashes[2]), Some(2));
+        assert_eq!(tree.height_of(hashes[4]), Some(3));
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        tree.prune(hashes[2]).expect("should prune");
+        assert_eq!(tree.height_of(hashes[2]), Some(0));
+        assert_eq!(tree.height_of(hashes[4]), Some(1));
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn add_node_inserts_common_ancestor() {
+        let (mut tree, hashes) = chain(2);
+        let a = add_block(&mut tree, hashes[2], 3);
+        let b = add_block(&mut tree, hashes[2], 3);
+        tree.add_node(a, a).expect("should add first fork");
+        tree.add_node(b, b).expect("should add second fork");
+
+        assert_eq!(tree.nodes[&hashes[0]].children, vec![hashes[2]]);
+        assert_eq!(tree.nodes[&hashes[2]].parent_hash, Some(hashes[0]));
+        assert_eq!(tree.nodes[&hashes[2]].children, vec![a.min(b), a.max(b)]);
+        assert_eq!(tree.nodes[&a].parent_hash, Some(hashes[2]));
+        assert_eq!(tree.nodes[&b].parent_hash, Some(hashes[2]));
+    }
+
+    #[test]
+    fn add_node_moves_votes_to_new_node() {
+        let (mut tree, hashes) = chain(3);
+        tree.add_node(hashes[3], hashes[3])
+            .expect("should add block");
+
+        // Counted at the root, since `hashes[2]` is not yet in the tree.
+        tree.process_attestation(0, hashes[2], 5)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&hashes[0]].score, 1);
+
+        tree.add_node(hashes[2], hashes[2])
+            .expect("should add block");
+        assert_eq!(tree.nodes[&hashes[0]].score, 1);
+        assert_eq!(tree.nodes[&hashes[2]].score, 1);
+        assert_eq!(tree.nodes[&hashes[3]].score, 0);
+
+        tree.process_attestation(0, hashes[3], 6)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&hashes[0]].score, 1);
+        assert_eq!(tree.nodes[&hashes[2]].score, 1);
+        assert_eq!(tree.nodes[&hashes[3]].score, 1);
+
+        // A fork creates a common ancestor, which takes the votes for it and for the new block.
+        let (mut tree, hashes) = chain(3);
+        let fork = add_block(&mut tree, hashes[1], 2);
+        let fork_tip = add_block(&mut tree, fork, 3);
+        tree.add_node(hashes[3], hashes[3])
+            .expect("should add block");
+        for (validator, block) in [(0, hashes[1]), (1, fork), (2, fork_tip), (3, hashes[0])] {
+            tree.process_attestation(validator, block, 5)
+                .expect("should process vote");
+        }
+        assert_eq!(tree.nodes[&hashes[0]].score, 4);
+
+        tree.add_node(fork, fork).expect("should add block");
+        assert_eq!(tree.nodes[&hashes[0]].score, 4);
+        assert_eq!(tree.nodes[&hashes[1]].score, 3);
+        assert_eq!(tree.nodes[&fork].score, 2);
+        assert_eq!(tree.nodes[&hashes[3]].score, 0);
+        assert_eq!(tree.find_head(hashes[0]), Some(fork));
+
+        let mut recomputed = Tree::restore(tree.snapshot());
+        recomputed.recompute_scores();
+        assert!(tree.diff(&recomputed).is_empty());
+        for validator in 0..4 {
+            tree.process_attestation(validator, hashes[3], 6)
+                .expect("should process vote");
+        }
+        assert_eq!(tree.nodes[&hashes[3]].score, 4);
+        assert_eq!(tree.nodes[&fork].score, 0);
+    }
+
+    #[test]
+    fn votes_off_tree() {
+        let (mut tree, hashes) = chain(4);
+        tree.add_node(hashes[4], hashes[4])
+            .expect("should add block");
+        for (validator, block) in [(0, hashes[2]), (1, hashes[3]), (2, hashes[4])] {
+            tree.process_attestation(validator, block, 5)
+                .expect("should process vote");
+        }
+        let voters = |tree: &Tree, node| {
+            let mut voters = tree
+                .votes_off_tree
+                .get(&node)
+                .into_iter()
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>();
+            voters.sort();
+            voters
+        };
+        // A vote for a node is not indexed.
+        assert_eq!(voters(&tree, hashes[0]), vec![0, 1]);
+        assert!(voters(&tree, hashes[4]).is_empty());
+
+        // Only the votes below the new node move to it.
+        tree.process_attestation(0, hashes[1], 6)
+            .expect("should process vote");
+        tree.add_node(hashes[2], hashes[2])
+            .expect("should add block");
+        assert_eq!(voters(&tree, hashes[0]), vec![0]);
+        assert_eq!(voters(&tree, hashes[2]), vec![1]);
+        assert_eq!(tree.nodes[&hashes[2]].score, 2);
+
+        let mut rebuilt = Tree::restore(tree.snapshot());
+        rebuilt.index_votes_off_tree();
+        assert_eq!(rebuilt.votes_off_tree, tree.votes_off_tree);
+
+        tree.prune(hashes[2]).expect("should prune");
+        assert!(voters(&tree, hashes[0]).is_empty());
+        assert_eq!(voters(&tree, hashes[2]), vec![1]);
+    }
+
+    #[test]
+    fn add_node_twice() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(1, b, 3)
+            .expect("should process vote");
+        let (nodes, slots_at_height) = (tree.nodes.clone(), tree.slots_at_height.clone());
+        let blocks_at_height = tree.blocks_at_height.clone();
+
+        for hash in &[hashes[0], hashes[2], a, a_tip, b] {
+            let node = &nodes[hash];
+            assert_eq!(
+                tree.add_node(*hash, *hash),
+                Ok(NodePlacement {
+                    height: node.height,
+                    parent_hash: node.parent_hash
+                })
+            );
+        }
+
+        assert_eq!(tree.nodes, nodes);
+        assert_eq!(tree.slots_at_height, slots_at_height);
+        assert_eq!(tree.blocks_at_height, blocks_at_height);
+        assert_eq!(tree.nodes[&hashes[0]].score, 2);
+    }
+
+    #[test]
+    fn add_node_placement() {
+        let (mut tree, hashes) = chain(5);
+        let placement = |height, parent: Hash256| NodePlacement {
+            height,
+            parent_hash: Some(parent),
+        };
+
+        // Each block lands beneath its most recent ancestor in the tree, one height further up.
+        assert_eq!(
+            tree.add_node(hashes[1], hashes[1]),
+            Ok(placement(1, hashes[0]))
+        );
+        assert_eq!(
+            tree.add_node(hashes[3], hashes[3]),
+            Ok(placement(2, hashes[1]))
+        );
+        assert_eq!(
+            tree.add_node(hashes[5], hashes[5]),
+            Ok(placement(3, hashes[3]))
+        );
+
+        // A block between two nodes takes the height of its slot, shifting the nodes above it.
+        assert_eq!(
+            tree.add_node(hashes[2], hashes[2]),
+            Ok(placement(2, hashes[1]))
+        );
+        assert_eq!(tree.height_of(hashes[5]), Some(4));
+        assert_eq!(
+            tree.add_node(hashes[5], hashes[5]),
+            Ok(placement(4, hashes[3]))
+        );
+    }
+
+    #[test]
+    fn add_node_unknown_block() {
+        let (mut tree, _hashes) = chain(1);
+        let unknown = Hash256::random();
+
+        assert_eq!(
+            tree.add_node(unknown, unknown),
+            Err(Error::UnknownBlock(unknown))
+        );
+    }
+
+    #[test]
+    fn add_node_orphan() {
+        let (mut tree, _hashes) = chain(1);
+
+        // A block whose chain does not include the tree's root.
+        let orphan_genesis = Hash256::random();
+        Arc::make_mut(&mut tree.store).insert(orphan_genesis, Block::genesis(orphan_genesis, 0));
+        let orphan = add_block(&mut tree, orphan_genesis, 1);
+
+        assert_eq!(
+            tree.add_node(orphan, orphan),
+            Err(Error::MissingParent(orphan))
+        );
+        assert!(!tree.nodes.contains_key(&orphan));
+    }
+
+    #[test]
+    fn add_node_self_parent() {
+        let (mut tree, _genesis) = genesis();
+        let hash = Hash256::random();
+        Arc::make_mut(&mut tree.store).insert(hash, Block::genesis(hash, 1));
+
+        assert_eq!(tree.add_node(hash, hash), Err(Error::Cycle(hash)));
+        assert!(!tree.contains_block(&hash));
+    }
+
+    #[test]
+    fn add_node_parent_loop() {
+        let (mut tree, hashes) = chain(3);
+        tree.add_node(hashes[3], hashes[3]).expect("should add tip");
+
+        // Claim that the tip is the parent of one of its own ancestors.
+        Arc::make_mut(&mut tree.store)
+            .get_mut(&hashes[2])
+            .unwrap()
+            .ancestor_skip_list[0] = hashes[3];
+
+        assert_eq!(
+            tree.add_node(hashes[2], hashes[2]),
+            Err(Error::Cycle(hashes[2]))
+        );
+        assert!(!tree.contains_block(&hashes[2]));
+    }
+
+    #[test]
+    fn add_node_non_monotonic_slot() {
+        let (mut tree, hashes) = chain(3);
+        tree.add_node(hashes[2], hashes[2])
+            .expect("should add block");
+        let before = tree.snapshot_cow();
+
+        for slot in [1, 2] {
+            let hash = Hash256::random();
+            Arc::make_mut(&mut tree.store).insert(hash, Block::with_parent(slot, hashes[2]));
+            assert_eq!(
+                tree.add_node(hash, hash),
+                Err(Error::NonMonotonicSlot {
+                    slot,
+                    parent_slot: 2
+                })
+            );
+            assert!(before.diff(&tree).is_empty());
+            assert_eq!(tree.slots_at_height, before.slots_at_height);
+            assert_eq!(tree.blocks_at_height, before.blocks_at_height);
+        }
+    }
+
+    #[test]
+    fn add_node_unknown_parent() {
+        let (mut tree, hashes) = chain(1);
+        let parent = Hash256::random();
+        Arc::make_mut(&mut tree.store)
+            .get_mut(&hashes[1])
+            .unwrap()
+            .ancestor_skip_list[0] = parent;
+
+        assert_eq!(
+            tree.add_node(hashes[1], hashes[1]),
+            Err(Error::UnknownBlock(parent))
+        );
+    }
+
+    #[test]
+    fn add_node_out_of_order() {
+        let (mut tree, hashes) = chain(3);
+        tree.add_node(hashes[3], hashes[3]).expect("should add tip");
+        tree.apply_score_change(hashes[3], 7)
+            .expect("should apply vote");
+        tree.add_node(hashes[2], hashes[2])
+            .expect("should add ancestor");
+
+        assert_eq!(tree.nodes[&hashes[0]].children, vec![hashes[2]]);
+        assert_eq!(tree.nodes[&hashes[2]].children, vec![hashes[3]]);
+        assert_eq!(tree.nodes[&hashes[2]].parent_hash, Some(hashes[0]));
+        assert_eq!(tree.nodes[&hashes[3]].parent_hash, Some(hashes[2]));
+        assert_eq!(tree.nodes[&hashes[2]].score, 7);
+        assert_eq!(tree.slot_at_height(1), Some(2));
+        assert_eq!(tree.blocks_at_height[&2], vec![hashes[3]]);
+    }
+
+    #[test]
+    fn on_block() {
+        let (mut tree, genesis) = genesis();
+        let hashes = (0..4).map(|_| Hash256::random()).collect::<Vec<_>>();
+        let parents = [genesis, hashes[0], hashes[1], hashes[0]];
+        let slots = [1, 2, 3, 2];
+        let blocks = (0..4).map(|i| Block::with_parent(slots[i], parents[i]));
+        let blocks = hashes.iter().copied().zip(blocks).collect::<Vec<_>>();
+
+        // Importing a child before its parent is rejected.
+        let (hash, block) = blocks[2].clone();
+        assert_eq!(
+            tree.on_block(hash, block),
+            Err(Error::UnknownBlock(hashes[1]))
+        );
+        assert!(!tree.store.contains_key(&hashes[2]));
+        assert!(!tree.contains_block(&hashes[2]));
+
+        for i in [0, 3, 1, 2] {
+            let (hash, block) = blocks[i].clone();
+            tree.on_block(hash, block).expect("should import block");
+        }
+
+        assert_eq!(tree.len(), 5);
+        let mut children = vec![hashes[1], hashes[3]];
+        children.sort();
+        assert_eq!(tree.nodes[&hashes[0]].children, children);
+        assert_eq!(tree.nodes[&hashes[2]].parent_hash, Some(hashes[1]));
+        assert_eq!(
+            tree.store[&hashes[2]].ancestor_skip_list,
+            skip_list(3, hashes[1], &tree.store)
+        );
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let late = Hash256::random();
+        assert_eq!(
+            tree.on_block(late, Block::with_parent(2, hashes[2])),
+            Err(Error::NonMonotonicSlot {
+                slot: 2,
+                parent_slot: 3
+            })
+        );
+        assert!(!tree.store.contains_key(&late));
+    }
+
+    #[test]
+    fn on_block_already_stored() {
+        let (mut tree, genesis) = genesis();
+        let (a, b) = (Hash256::random(), Hash256::random());
+        tree.on_block(a, Block::with_parent(1, genesis))
+            .expect("should import block");
+        tree.on_block(b, Block::with_parent(1, genesis))
+            .expect("should import block");
+
+        // The same block again is a no-op, while a different block under its hash is rejected.
+        tree.on_block(a, Block::with_parent(1, genesis))
+            .expect("should import block");
+        assert_eq!(
+            tree.on_block(a, Block::with_parent(7, genesis)),
+            Err(Error::DuplicateBlock(a))
+        );
+        assert_eq!(tree.store[&a].slot, 1);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // A block of a pruned fork stays in the store when importing it again fails.
+        tree.prune(a).expect("should prune");
+        assert_eq!(
+            tree.on_block(b, Block::with_parent(1, genesis)),
+            Err(Error::MissingParent(b))
+        );
+        assert!(tree.store.contains_key(&b));
+    }
+
+    #[test]
+    fn on_head_change() {
+        let (mut tree, genesis) = genesis();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let recorded = changes.clone();
+        tree.on_head_change(Box::new(move |new, old| {
+            recorded.lock().unwrap().push((new, old));
+        }));
+        tree.on_tick(3);
+
+        let a = Hash256::random();
+        tree.on_block(a, Block::with_parent(1, genesis))
+            .expect("should import block");
+        tree.on_attestation(0, a, 1).expect("should process vote");
+        assert_eq!(*changes.lock().unwrap(), vec![(a, Some(genesis))]);
+
+        // Neither importing a block again nor importing a lighter fork changes the head.
+        tree.on_block(a, Block::with_parent(1, genesis))
+            .expect("should import block");
+        let b = Hash256::random();
+        tree.on_block(b, Block::with_parent(1, genesis))
+            .expect("should import block");
+        assert_eq!(changes.lock().unwrap().len(), 1);
+
+        tree.on_attestation(0, b, 2).expect("should process vote");
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(a, Some(genesis)), (b, Some(a))]
+        );
+
+        let c = Hash256::random();
+        tree.on_block(c, Block::with_parent(3, a))
+            .expect("should import block");
+        assert!(tree.on_attestation(0, Hash256::random(), 3).is_err());
+        assert_eq!(changes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn add_nodes_matches_add_node() {
+        let (mut one_by_one, hashes) = chain(4);
+        let a = add_block(&mut one_by_one, hashes[2], 3);
+        let a_tip = add_block(&mut one_by_one, a, 5);
+        let b = add_block(&mut one_by_one, hashes[1], 4);
+        let b_tip = add_block(&mut one_by_one, b, 6);
+
+        let mut batch = Tree::new(hashes[0], 0);
+        batch.store = one_by_one.store.clone();
+
+        let mut blocks = vec![b_tip, hashes[4], a, hashes[2], a_tip, b, hashes[3]];
+        batch
+            .add_nodes(blocks.iter().map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+
+        blocks.sort_by_key(|hash| one_by_one.store[hash].slot);
+        for hash in &blocks {
+            one_by_one.add_node(*hash, *hash).expect("should add block");
+        }
+
+        assert_eq!(batch.len(), one_by_one.len());
+        for (hash, node) in one_by_one.nodes.iter() {
+            let other = &batch.nodes[hash];
+            assert_eq!(other.parent_hash, node.parent_hash);
+            assert_eq!(
+                other.children.iter().collect::<HashSet<_>>(),
+                node.children.iter().collect::<HashSet<_>>()
+            );
+        }
+        assert_eq!(batch.slots_at_height.0, one_by_one.slots_at_height.0);
+        assert_eq!(batch.blocks_at_height, one_by_one.blocks_at_height);
+    }
+
+    #[test]
+    fn add_nodes_between_existing_heights() {
+        let (mut one_by_one, hashes) = chain(0);
+        let mut evens = vec![];
+        let mut odds = vec![];
+        for slot in 1..=20 {
+            let branch = if slot % 2 == 0 { &mut evens } else { &mut odds };
+            let parent = branch.last().copied().unwrap_or(hashes[0]);
+            branch.push(add_block(&mut one_by_one, parent, slot));
+        }
+        for hash in &evens {
+            one_by_one.add_node(*hash, *hash).expect("should add block");
+        }
+        let mut batch = Tree::restore(one_by_one.snapshot());
+
+        // Every odd slot lands between two existing heights.
+        batch
+            .add_nodes(odds.iter().rev().map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+        for hash in &odds {
+            one_by_one.add_node(*hash, *hash).expect("should add block");
+        }
+
+        assert!(batch.diff(&one_by_one).is_empty());
+        assert_eq!(batch.slots_at_height.0, one_by_one.slots_at_height.0);
+        assert_eq!(batch.blocks_at_height, one_by_one.blocks_at_height);
+        assert_eq!(batch.check_invariants(), Ok(()));
+
+        // The slots of blocks that fail to be added are dropped again.
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let late = add_block(&mut tree, b, 7);
+        let tip = add_block(&mut tree, a_tip, 6);
+        tree.prune(a).expect("should prune");
+        assert_eq!(
+            tree.add_nodes(vec![(tip, tip), (late, late)]),
+            Err(Error::MissingParent(late))
+        );
+        assert!(tree.contains_block(&tip));
+        assert!(!tree.contains_block(&hashes[0]));
+        assert_eq!(tree.slots_at_height.0, vec![3, 4, 6]);
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn add_nodes_unknown_block() {
+        let (mut tree, hashes) = chain(2);
+        let missing = Hash256::random();
+
+        assert_eq!(
+            tree.add_nodes(vec![(hashes[1], hashes[1]), (missing, missing)]),
+            Err(Error::UnknownBlock(missing))
+        );
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn find_head_single_chain() {
+        let (mut tree, hashes) = chain(4);
+        for hash in &hashes[1..] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+
+        assert_eq!(tree.find_head(hashes[0]), Some(hashes[4]));
+        assert_eq!(tree.find_head(hashes[2]), Some(hashes[4]));
+        assert_eq!(tree.find_head(hashes[4]), Some(hashes[4]));
+    }
+
+    #[test]
+    fn find_head_follows_heavier_fork() {
+        let (mut tree, hashes) = chain(2);
+        let a = add_block(&mut tree, hashes[2], 3);
+        let a_tip = add_block(&mut tree, a, 4);
+        let b = add_block(&mut tree, hashes[2], 3);
+        for hash in &[a, a_tip, b] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+
+        tree.apply_score_change(a_tip, 1)
+            .expect("should apply vote");
+        tree.apply_score_change(b, 2).expect("should apply vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+
+        tree.apply_score_change(a_tip, 2)
+            .expect("should apply vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+    }
+
+    #[test]
+    fn find_head_weighted() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let beyond_a_tip = add_block(&mut tree, a_tip, 5);
+
+        let mut balances = HashMap::new();
+        balances.insert(beyond_a_tip, 10);
+        balances.insert(b, 25);
+        balances.insert(hashes[1], 100);
+        assert_eq!(tree.find_head_weighted(hashes[0], &balances), Some(b));
+        assert_eq!(tree.find_head_weighted(a, &balances), Some(a_tip));
+
+        // A single heavy validator moves its vote from `b` to `a`.
+        *balances.get_mut(&b).unwrap() -= 20;
+        balances.insert(a, 20);
+        assert_eq!(tree.find_head_weighted(hashes[0], &balances), Some(a_tip));
+
+        // The scores in the tree are untouched.
+        assert!(tree.nodes.values().all(|node| node.score == 0));
+        assert_eq!(tree.find_head_weighted(Hash256::random(), &balances), None);
+    }
+
+    #[test]
+    fn process_attestation() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let beyond_b = add_block(&mut tree, b, 4);
+
+        // First votes.
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(1, beyond_b, 4)
+            .expect("should process vote");
+        tree.process_attestation(2, beyond_b, 5)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&a].score, 1);
+        assert_eq!(tree.nodes[&b].score, 2);
+        assert_eq!(tree.nodes[&hashes[0]].score, 3);
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+
+        // A newer vote moves weight.
+        tree.process_attestation(1, a, 6)
+            .expect("should process vote");
+        assert_eq!(tree.latest_message(1), Some(a));
+        assert_eq!(tree.nodes[&a].score, 2);
+        assert_eq!(tree.nodes[&b].score, 1);
+        assert_eq!(tree.nodes[&hashes[0]].score, 3);
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+
+        // Stale votes are dropped.
+        tree.process_attestation(1, b, 5)
+            .expect("should ignore vote");
+        assert_eq!(tree.latest_message(1), Some(a));
+        assert_eq!(tree.nodes[&a].score, 2);
+        assert_eq!(tree.nodes[&b].score, 1);
+
+        let missing = Hash256::random();
+        assert_eq!(
+            tree.process_attestation(3, missing, 7),
+            Err(Error::UnknownBlock(missing))
+        );
+        assert_eq!(tree.latest_message(3), None);
+    }
+
+    #[test]
+    fn equivocation() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_weighted_attestation(0, a_tip, 4, 10)
+            .expect("should process vote");
+        tree.process_weighted_attestation(1, b, 4, 5)
+            .expect("should process vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+
+        // A repeated vote is not an equivocation.
+        tree.process_weighted_attestation(1, b, 4, 5)
+            .expect("should ignore vote");
+        assert!(!tree.is_equivocating(1));
+
+        tree.process_weighted_attestation(0, b, 4, 10)
+            .expect("should process vote");
+        assert!(tree.is_equivocating(0));
+        assert_eq!(tree.latest_message(0), None);
+        assert_eq!(tree.score_of(a), Some(0));
+        assert_eq!(tree.score_of(b), Some(5));
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+
+        // Later votes from an equivocating validator are ignored, including by a recompute.
+        tree.process_weighted_attestation(0, a_tip, 5, 10)
+            .expect("should ignore vote");
+        tree.recompute_scores();
+        assert_eq!(tree.score_of(a), Some(0));
+        assert_eq!(tree.score_of(b), Some(5));
+
+        tree.on_tick(5);
+        tree.on_attestation(2, a, 5).expect("should accept vote");
+        tree.on_attestation(2, b, 5).expect("should accept vote");
+        assert!(tree.is_equivocating(2));
+        assert_eq!(tree.score_of(a), Some(0));
+
+        tree.process_attestation(3, a, 5)
+            .expect("should process vote");
+        tree.process_aggregate(&[0b0000_1010], b, 5, &[0, 5, 0, 1])
+            .expect("should process aggregate");
+        assert!(tree.is_equivocating(3));
+        assert!(!tree.is_equivocating(1));
+        assert_eq!(tree.score_of(a), Some(0));
+        assert_eq!(tree.score_of(b), Some(5));
+
+        let before = scores(&tree);
+        tree.record_attestation(4, a, 6, 1)
+            .expect("should record vote");
+        tree.record_attestation(4, b, 6, 1)
+            .expect("should record vote");
+        assert!(tree.is_equivocating(4));
+        assert_eq!(scores(&tree), before);
+        assert_eq!(tree.find_head_lazy(hashes[0]), Some(b));
+    }
+
+    #[test]
+    fn process_weighted_attestation() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let check_scores = |tree: &mut Tree| {
+            let incremental = scores(tree);
+            tree.recompute_scores();
+            assert_eq!(incremental, scores(tree));
+        };
+
+        tree.process_weighted_attestation(0, a_tip, 4, 32)
+            .expect("should process vote");
+        tree.process_weighted_attestation(1, b, 3, 20)
+            .expect("should process vote");
+        tree.process_attestation(2, b, 3)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&a].score, 32);
+        assert_eq!(tree.nodes[&b].score, 21);
+        check_scores(&mut tree);
+
+        // Switching removes the balance of the previous message, even if the balance changed.
+        tree.process_weighted_attestation(0, b, 5, 31)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&a].score, 0);
+        assert_eq!(tree.nodes[&b].score, 52);
+        assert_eq!(tree.nodes[&hashes[0]].score, 52);
+        check_scores(&mut tree);
+
+        tree.process_weighted_attestation(2, a, 5, 40)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&a].score, 40);
+        assert_eq!(tree.nodes[&b].score, 51);
+        check_scores(&mut tree);
+
+        // A switch that fails after removing the old balance leaves both branches and the latest
+        // message unchanged.
+        tree.process_weighted_attestation(3, a, 5, i64::MAX as u64)
+            .expect("should process vote");
+        let before = scores(&tree);
+        assert_eq!(
+            tree.process_weighted_attestation(1, a_tip, 6, i64::MAX as u64),
+            Err(Error::ScoreOverflow(a))
+        );
+        assert_eq!(scores(&tree), before);
+        assert_eq!(tree.latest_message(1), Some(b));
+        check_scores(&mut tree);
+    }
+
+    #[test]
+    fn find_head_filtered() {
+        let checkpoint = |root| Checkpoint { root, epoch: 0 };
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(1, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(2, b, 3)
+            .expect("should process vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+
+        // The heavier fork does not build on `b`.
+        assert_eq!(tree.find_head_filtered(hashes[0], checkpoint(b)), Some(b));
+        assert_eq!(
+            tree.find_head_filtered(hashes[0], checkpoint(a)),
+            Some(a_tip)
+        );
+        assert_eq!(
+            tree.find_head_filtered(hashes[0], checkpoint(hashes[1])),
+            Some(a_tip)
+        );
+
+        // A justified root that is in the store but not the tree filters by its descendants.
+        let c = add_block(&mut tree, b, 5);
+        assert_eq!(tree.find_head_filtered(hashes[0], checkpoint(c)), None);
+        let d = add_block(&mut tree, c, 6);
+        tree.add_node(d, d).expect("should add block");
+        assert_eq!(tree.find_head_filtered(hashes[0], checkpoint(c)), Some(d));
+
+        assert_eq!(tree.find_head_filtered(a, checkpoint(b)), None);
+        assert_eq!(
+            tree.find_head_filtered(Hash256::random(), checkpoint(b)),
+            None
+        );
+    }
+
+    #[test]
+    fn find_head_filtered_unrealized() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 10)
+            .expect("should apply vote");
+        tree.set_unrealized(a_tip, 0)
+            .expect("should set unrealized");
+        tree.set_unrealized(b, 1).expect("should set unrealized");
+        let justified = Checkpoint {
+            root: hashes[2],
+            epoch: 1,
+        };
+        tree.update_justified(justified)
+            .expect("should update justified");
+
+        // Both forks descend from the justified root, and the leaves are of the current epoch.
+        assert_eq!(tree.find_head_filtered(hashes[0], justified), Some(a_tip));
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(a_tip));
+
+        // Once the epoch is over, the heavier fork has not justified the justified epoch.
+        tree.on_tick(SLOTS_PER_EPOCH);
+        assert_eq!(tree.find_head_filtered(hashes[0], justified), Some(b));
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(b));
+
+        // The epoch is taken from the checkpoint passed in, not the one of the tree.
+        let earlier = Checkpoint {
+            root: hashes[2],
+            epoch: 0,
+        };
+        assert_eq!(tree.find_head_filtered(hashes[0], earlier), Some(a_tip));
+
+        assert_eq!(
+            tree.set_unrealized(hashes[1], 1),
+            Err(Error::UnknownBlock(hashes[1]))
+        );
+    }
+
+    #[test]
+    fn find_head_with_threshold() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 3)
+            .expect("should apply vote");
+        tree.apply_score_change(a, 2).expect("should apply vote");
+        tree.apply_score_change(b, 1).expect("should apply vote");
+
+        assert_eq!(tree.find_head_with_threshold(hashes[0], 0), Some(a_tip));
+        assert_eq!(tree.find_head_with_threshold(hashes[0], 3), Some(a_tip));
+        // `a` has a score of 5 but `a_tip` only 3.
+        assert_eq!(tree.find_head_with_threshold(hashes[0], 4), Some(a));
+        assert_eq!(tree.find_head_with_threshold(hashes[0], 7), Some(hashes[0]));
+        assert_eq!(tree.find_head_with_threshold(b, 7), Some(b));
+        assert_eq!(tree.find_head_with_threshold(Hash256::random(), 0), None);
+    }
+
+    #[test]
+    fn find_head_lazy() {
+        let tree = random_tree(300);
+        let head = tree.find_head(tree.root());
+        assert_eq!(tree.find_head_lazy(tree.root()), head);
+
+        // Votes recorded without updating the scores give the same head as eager votes.
+        let (mut eager, hashes, [a, a_tip, b]) = forked_tree();
+        let mut lazy = Tree::restore(eager.snapshot());
+        let votes = [(0, a_tip, 4, 10), (1, b, 3, 8), (2, a, 3, 5), (0, b, 5, 10)];
+        for &(validator, block, slot, balance) in &votes {
+            eager
+                .process_weighted_attestation(validator, block, slot, balance)
+                .expect("should process vote");
+            lazy.record_attestation(validator, block, slot, balance)
+                .expect("should record vote");
+
+            assert_eq!(lazy.find_head_lazy(hashes[0]), eager.find_head(hashes[0]));
+            assert_eq!(lazy.find_head_lazy(a), eager.find_head(a));
+        }
+        assert_eq!(lazy.find_head_lazy(hashes[0]), Some(b));
+        assert_eq!(lazy.nodes[&b].score, 0);
+
+        lazy.recompute_scores();
+        assert_eq!(scores(&lazy), scores(&eager));
+        assert_eq!(lazy.find_head_lazy(hashes[1]), None);
+    }
+
+    #[test]
+    fn on_attestation() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.on_tick(5);
+
+        tree.on_attestation(0, b, 3).expect("should accept vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+        tree.on_attestation(1, a_tip, 4)
+            .expect("should accept vote");
+        tree.on_attestation(2, a_tip, 4)
+            .expect("should accept vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+
+        assert_eq!(
+            tree.on_attestation(0, a_tip, 3),
+            Err(Error::FutureTarget {
+                slot: 3,
+                target_slot: 4
+            })
+        );
+        assert_eq!(
+            tree.on_attestation(1, a, 3),
+            Err(Error::StaleAttestation {
+                slot: 3,
+                latest_slot: 4
+            })
+        );
+        assert_eq!(
+            tree.on_attestation(1, a_tip, 4),
+            Err(Error::StaleAttestation {
+                slot: 4,
+                latest_slot: 4
+            })
+        );
+        let missing = Hash256::random();
+        assert_eq!(
+            tree.on_attestation(3, missing, 5),
+            Err(Error::UnknownBlock(missing))
+        );
+
+        tree.prune(a).expect("should prune");
+        assert_eq!(tree.on_attestation(3, b, 5), Err(Error::MissingParent(b)));
+        assert_eq!(tree.latest_message(3), None);
+    }
+
+    #[test]
+    fn on_tick() {
+        let (mut tree, _hashes, [_a, a_tip, _b]) = forked_tree();
+        assert_eq!(tree.current_slot(), 0);
+
+        assert_eq!(
+            tree.on_attestation(0, a_tip, 4),
+            Err(Error::FutureSlot {
+                slot: 4,
+                current_slot: 0
+            })
+        );
+        assert_eq!(tree.latest_message(0), None);
+
+        tree.on_tick(4);
+        tree.on_attestation(0, a_tip, 4)
+            .expect("should accept vote");
+        assert_eq!(tree.latest_message(0), Some(a_tip));
+
+        // The clock does not move backwards.
+        tree.on_tick(2);
+        assert_eq!(tree.current_slot(), 4);
+    }
+
+    #[test]
+    fn update_head_extends_chain() {
+        let (mut tree, hashes) = chain(3);
+        tree.add_nodes(hashes[1..3].iter().map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+
+        assert_eq!(
+            tree.update_head(),
+            Ok(HeadUpdate {
+                new_head: hashes[2],
+                old_head: hashes[0],
+                reorg_depth: None
+            })
+        );
+
+        tree.add_node(hashes[3], hashes[3])
+            .expect("should add block");
+        assert_eq!(
+            tree.update_head(),
+            Ok(HeadUpdate {
+                new_head: hashes[3],
+                old_head: hashes[2],
+                reorg_depth: None
+            })
+        );
+        assert_eq!(
+            tree.update_head().map(|update| update.reorg_depth),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn update_head_reorg() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 1)
+            .expect("should apply vote");
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(a_tip));
+
+        tree.apply_score_change(b, 2).expect("should apply vote");
+        assert_eq!(
+            tree.update_head(),
+            Ok(HeadUpdate {
+                new_head: b,
+                old_head: a_tip,
+                // From slot 4 back to `hashes[2]` at slot 2.
+                reorg_depth: Some(2)
+            })
+        );
+        assert_eq!(
+            tree.find_least_common_ancestor(a_tip, b),
+            Ok(Some(hashes[2]))
+        );
+    }
+
+    #[test]
+    fn find_head_breaks_ties_by_greater_hash() {
+        let (mut tree, hashes) = chain(1);
+        let low = Hash256::from_low_u64_be(0xff);
+        let high = Hash256::from_slice(&[&[0x01][..], &[0; 31][..]].concat());
+        for hash in &[high, low] {
+            let block = Block::new(2, hashes[1], &*tree.store).expect("should build block");
+            Arc::make_mut(&mut tree.store).insert(*hash, block);
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+        tree.apply_score_change(low, 3).expect("should apply vote");
+        tree.apply_score_change(high, 3).expect("should apply vote");
+
+        assert!(high > low);
+        for _ in 0..10 {
+            assert_eq!(tree.find_head(hashes[0]), Some(high));
+        }
+    }
+
+    #[test]
+    fn find_head_breaks_ties_at_branch_point() {
+        // Fork `x` starts with a greater hash than fork `y`, but ends with a lower one.
+        let build = |nodes: &[u64]| {
+            let mut tree = Tree::new(100_u64, 0);
+            for &(hash, parent, slot) in &[
+                (0x10, 100, 1),
+                (0x01, 0x10, 2),
+                (0x05, 100, 1),
+                (0x02, 0x05, 2),
+            ] {
+                let block = Block::new(slot, parent, &*tree.store).expect("should build block");
+                Arc::make_mut(&mut tree.store).insert(hash, block);
+            }
+            for hash in nodes {
+                tree.add_node(*hash, *hash).expect("should add block");
+            }
+            tree
+        };
+
+        let tips = build(&[0x01, 0x02]);
+        let all = build(&[0x10, 0x01, 0x05, 0x02]);
+        assert_eq!(tips.find_head(100), Some(0x01));
+        assert_eq!(all.find_head(100), Some(0x01));
+        assert_eq!(tips.find_head_lazy(100), Some(0x01));
+
+        // Skipped slots are stepped over to the first block after the branch point.
+        let mut tree = Tree::new(0_u64, 0);
+        for &(hash, parent, slot) in &[(1, 0, 3), (2, 1, 9), (3, 2, 40)] {
+            let block = Block::new(slot, parent, &*tree.store).expect("should build block");
+            Arc::make_mut(&mut tree.store).insert(hash, block);
+        }
+        for (slot, first) in [(0, 1), (2, 1), (3, 2), (8, 2), (9, 3), (39, 3), (40, 3)] {
+            assert_eq!(
+                get_oldest_ancestor_after_slot(slot, 3, &*tree.store),
+                Ok(first)
+            );
+        }
+    }
+
+    #[test]
+    fn reorg_distance() {
+        let (mut tree, hashes) = chain(8);
+        let fork = add_block(&mut tree, hashes[2], 4);
+        let fork_tip = add_block(&mut tree, fork, 9);
+
+        assert_eq!(tree.reorg_distance(hashes[3], hashes[8]), Some(0));
+        assert_eq!(tree.reorg_distance(hashes[8], hashes[3]), Some(0));
+        assert_eq!(tree.reorg_distance(hashes[8], hashes[8]), Some(0));
+        assert_eq!(tree.reorg_distance(hashes[8], fork_tip), Some(7));
+        assert_eq!(tree.reorg_distance(fork_tip, hashes[8]), Some(6));
+        assert_eq!(tree.reorg_distance(hashes[3], fork), Some(2));
+        assert_eq!(tree.reorg_distance(hashes[8], Hash256::random()), None);
+    }
+
+    #[derive(Default)]
+    struct Events {
+        add_node: usize,
+        find_head: Vec<bool>,
+        prune: Vec<usize>,
+    }
+
+    /// Records every event in shared `Events`, which the test keeps a handle to.
+    struct RecordingMetrics(Arc<Mutex<Events>>);
+
+    impl Metrics for RecordingMetrics {
+        fn on_add_node(&self, _duration: Duration) {
+            self.0.lock().unwrap().add_node += 1;
+        }
+
+        fn on_find_head(&self, _duration: Duration, reorg: bool) {
+            self.0.lock().unwrap().find_head.push(reorg);
+        }
+
+        fn on_prune(&self, removed: usize) {
+            self.0.lock().unwrap().prune.push(removed);
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let (tree, hashes) = chain(2);
+        let mut tree = tree.with_metrics(RecordingMetrics(events.clone()));
+        let a = add_block(&mut tree, hashes[2], 3);
+        let a_tip = add_block(&mut tree, a, 4);
+        let b = add_block(&mut tree, hashes[2], 3);
+        for hash in &[a, a_tip, b] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+
+        tree.apply_score_change(a_tip, 1)
+            .expect("should apply vote");
+        tree.update_head().expect("should update head");
+        tree.apply_score_change(b, 2).expect("should apply vote");
+        tree.update_head().expect("should update head");
+        tree.prune(b).expect("should prune");
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.add_node, 3);
+        assert_eq!(events.find_head, vec![false, true]);
+        assert_eq!(events.prune, vec![4]);
+    }
+
+    #[test]
+    fn apply_score_change_moves_head() {
+        let (mut tree, hashes) = chain(2);
+        let a = add_block(&mut tree, hashes[2], 3);
+        let b = add_block(&mut tree, hashes[2], 3);
+        tree.add_node(a, a).expect("should add block");
+        tree.add_node(b, b).expect("should add block");
+
+        tree.apply_score_change(a, 5).expect("should apply vote");
+        assert_eq!(tree.nodes[&a].score, 5);
+        assert_eq!(tree.nodes[&hashes[2]].score, 5);
+        assert_eq!(tree.nodes[&hashes[0]].score, 5);
+        assert_eq!(tree.find_head(hashes[0]), Some(a));
+
+        tree.apply_score_change(a, -5).expect("should remove vote");
+        tree.apply_score_change(b, 5).expect("should apply vote");
+        assert_eq!(tree.nodes[&a].score, 0);
+        assert_eq!(tree.nodes[&b].score, 5);
+        assert_eq!(tree.nodes[&hashes[0]].score, 5);
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+    }
+
+    #[test]
+    fn apply_score_change_underflow() {
+        let (mut tree, hashes) = chain(1);
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+
+        tree.apply_score_change(hashes[1], 2)
+            .expect("should apply vote");
+        assert_eq!(
+            tree.apply_score_change(hashes[1], -3),
+            Err(Error::ScoreUnderflow(hashes[1]))
+        );
+        assert_eq!(tree.nodes[&hashes[1]].score, 2);
+        assert_eq!(tree.nodes[&hashes[0]].score, 2);
+
+        // No score changes if any ancestor would underflow.
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&hashes[0])
+            .unwrap()
+            .score = 0;
+        assert_eq!(
+            tree.apply_score_change(hashes[1], -1),
+            Err(Error::ScoreUnderflow(hashes[0]))
+        );
+        assert_eq!(tree.nodes[&hashes[1]].score, 2);
+        assert_eq!(tree.nodes[&hashes[0]].score, 0);
+
+        let unknown = Hash256::random();
+        assert_eq!(
+            tree.apply_score_change(unknown, 1),
+            Err(Error::UnknownBlock(unknown))
+        );
+    }
+
+    #[test]
+    fn apply_score_change_overflow() {
+        let (mut tree, hashes) = chain(1);
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+
+        for _ in 0..2 {
+            tree.apply_score_change(hashes[1], i64::MAX)
+                .expect("should apply vote");
+        }
+        assert_eq!(
+            tree.apply_score_change(hashes[1], 2),
+            Err(Error::ScoreOverflow(hashes[1]))
+        );
+        assert_eq!(tree.nodes[&hashes[1]].score, u64::MAX - 1);
+    }
+
+    #[test]
+    fn branch_point_inherits_score() {
+        let (mut tree, hashes) = chain(2);
+        let a = add_block(&mut tree, hashes[2], 3);
+        let b = add_block(&mut tree, hashes[2], 3);
+        tree.add_node(a, a).expect("should add block");
+        tree.apply_score_change(a, 4).expect("should apply vote");
+        tree.add_node(b, b).expect("should add block");
+
+        assert_eq!(tree.nodes[&hashes[2]].score, 4);
+        assert_eq!(tree.nodes[&hashes[0]].score, 4);
+    }
+
+    /// Returns a tree with a chain `genesis -> 1 -> 2`, where `2` forks into `a -> a_tip` and `b`.
+    ///
+    /// Returns the tree, the chain hashes and `[a, a_tip, b]`.
+    fn forked_tree() -> (Tree, Vec<Hash256>, [Hash256; 3]) {
+        let (mut tree, hashes) = chain(2);
+        let a = add_block(&mut tree, hashes[2], 3);
+        let a_tip = add_block(&mut tree, a, 4);
+        let b = add_block(&mut tree, hashes[2], 3);
+        for hash in &[a, a_tip, b] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+        (tree, hashes, [a, a_tip, b])
+    }
+
+    #[test]
+    fn prune_to_fork() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(b, 10).expect("should apply vote");
+
+        tree.prune(a).expect("should prune");
+
+        assert_eq!(tree.root(), a);
+        assert_eq!(tree.nodes.len(), 2);
+        for hash in &[hashes[0], hashes[2], b] {
+            assert!(!tree.nodes.contains_key(hash));
+        }
+        assert_eq!(tree.nodes[&a].parent_hash, None);
+        assert_eq!(tree.slots_at_height.len(), 2);
+        assert_eq!(tree.slot_at_height(0), Some(3));
+        assert_eq!(tree.blocks_at_height[&0], vec![a]);
+        assert_eq!(tree.blocks_at_height[&1], vec![a_tip]);
+        assert_eq!(tree.find_head(a), Some(a_tip));
+    }
+
+    #[test]
+    fn update_finalized_prunes() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let genesis = tree.finalized();
+        assert_eq!(genesis.root, hashes[0]);
+        assert_eq!(tree.justified(), genesis);
+
+        let finalized = Checkpoint { root: a, epoch: 1 };
+        tree.update_finalized(finalized)
+            .expect("should update finalized");
+
+        assert_eq!(tree.root(), a);
+        assert_eq!(tree.finalized(), finalized);
+        assert_eq!(tree.justified(), finalized);
+        for hash in &[hashes[0], hashes[2], b] {
+            assert!(!tree.nodes.contains_key(hash));
+        }
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(a_tip));
+
+        // An older checkpoint is ignored.
+        tree.update_finalized(genesis)
+            .expect("should ignore older checkpoint");
+        assert_eq!(tree.root(), a);
+        assert_eq!(tree.finalized(), finalized);
+    }
+
+    #[test]
+    fn update_justified_filters_head() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 10)
+            .expect("should apply vote");
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(a_tip));
+
+        let justified = Checkpoint { root: b, epoch: 1 };
+        tree.update_justified(justified)
+            .expect("should update justified");
+
+        assert_eq!(tree.justified(), justified);
+        assert_eq!(tree.root(), hashes[0]);
+        assert_eq!(tree.update_head().map(|update| update.new_head), Ok(b));
+
+        let unknown = Hash256::random();
+        assert_eq!(
+            tree.update_justified(Checkpoint {
+                root: unknown,
+                epoch: 2
+            }),
+            Err(Error::UnknownBlock(unknown))
+        );
+        assert_eq!(tree.justified(), justified);
+    }
+
+    #[test]
+    fn prune_keeps_shared_history() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        tree.prune(hashes[2]).expect("should prune");
+
+        assert_eq!(tree.root(), hashes[2]);
+        assert!(!tree.nodes.contains_key(&hashes[0]));
+        for hash in &[hashes[2], a, a_tip, b] {
+            assert!(tree.nodes.contains_key(hash));
+        }
+        assert_eq!(tree.slots_at_height.len(), 3);
+        assert!(!tree.slots_at_height.contains(&0));
+
+        tree.apply_score_change(b, 1).expect("should apply vote");
+        assert_eq!(tree.find_head(hashes[2]), Some(b));
+    }
+
+    #[test]
+    fn prune_adds_missing_finalized_root() {
+        let (mut tree, hashes) = chain(4);
+        tree.add_node(hashes[4], hashes[4])
+            .expect("should add block");
+
+        tree.prune(hashes[2]).expect("should prune");
+
+        assert_eq!(tree.root(), hashes[2]);
+        assert_eq!(tree.nodes.len(), 2);
+        assert_eq!(tree.nodes[&hashes[4]].parent_hash, Some(hashes[2]));
+        assert_eq!(tree.find_head(hashes[2]), Some(hashes[4]));
+    }
+
+    #[test]
+    fn prune_to_current_root() {
+        let (mut tree, hashes, _forks) = forked_tree();
+
+        tree.prune(hashes[0]).expect("should prune");
+
+        assert_eq!(tree.root(), hashes[0]);
+        assert_eq!(tree.nodes.len(), 5);
+        assert_eq!(tree.slots_at_height.len(), 4);
+    }
+
+    #[test]
+    fn remove_node() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(a_tip, 3)
+            .expect("should apply vote");
+        tree.apply_score_change(b, 2).expect("should apply vote");
+
+        tree.remove_node(a).expect("should remove node");
+
+        assert!(!tree.contains_block(&a));
+        assert!(!tree.contains_block(&a_tip));
+        assert!(tree.store.contains_key(&a));
+        assert_eq!(tree.nodes[&hashes[2]].children, vec![b]);
+        assert_eq!(tree.nodes[&hashes[2]].score, 2);
+        assert_eq!(tree.nodes[&hashes[0]].score, 2);
+        assert_eq!(tree.slot_at_height(tree.slots_at_height.len() - 1), Some(3));
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+    }
+
+    #[test]
+    fn remove_node_forgets_votes() {
+        let (mut tree, _hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.process_attestation(1, b, 3)
+            .expect("should process vote");
+
+        tree.remove_node(a).expect("should remove node");
+        assert_eq!(tree.latest_message(0), None);
+        assert_eq!(tree.latest_message(1), Some(b));
+
+        tree.process_attestation(0, b, 5)
+            .expect("should process vote");
+        let expected = scores(&tree);
+        tree.recompute_scores();
+        assert_eq!(scores(&tree), expected);
+        assert_eq!(tree.nodes[&b].score, 2);
+    }
+
+    #[test]
+    fn collapse() {
+        let (mut tree, hashes) = chain(6);
+        let fork = add_block(&mut tree, hashes[1], 3);
+        tree.add_nodes(hashes[1..].iter().chain(&[fork]).map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+        tree.process_attestation(0, hashes[6], 6)
+            .expect("should process vote");
+        tree.process_attestation(1, hashes[3], 3)
+            .expect("should process vote");
+        tree.process_attestation(2, fork, 3)
+            .expect("should process vote");
+
+        tree.prune(hashes[1]).expect("should prune");
+        tree.remove_node(fork).expect("should remove node");
+        tree.collapse();
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.nodes[&hashes[1]].children, vec![hashes[6]]);
+        assert_eq!(tree.nodes[&hashes[6]].parent_hash, Some(hashes[1]));
+        assert_eq!(tree.nodes[&hashes[1]].score, 2);
+        assert_eq!(tree.nodes[&hashes[6]].score, 1);
+        assert_eq!(tree.slots_at_height.len(), 2);
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert_eq!(tree.find_head(hashes[1]), Some(hashes[6]));
+
+        let expected = scores(&tree);
+        tree.recompute_scores();
+        assert_eq!(scores(&tree), expected);
+
+        // Blocks on the collapsed chain can be added again.
+        tree.add_node(hashes[4], hashes[4])
+            .expect("should add block");
+        assert_eq!(tree.nodes[&hashes[4]].parent_hash, Some(hashes[1]));
+        assert_eq!(tree.nodes[&hashes[4]].children, vec![hashes[6]]);
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn remove_node_errors() {
+        let (mut tree, hashes, _) = forked_tree();
+
+        assert_eq!(tree.remove_node(hashes[0]), Err(Error::RemoveRoot));
+        assert_eq!(
+            tree.remove_node(hashes[1]),
+            Err(Error::UnknownBlock(hashes[1]))
+        );
+        assert_eq!(tree.len(), 5);
+    }
+
+    #[test]
+    fn error_display() {
+        fn add(
+            tree: &mut Tree<u64>,
+            hash: u64,
+        ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+            tree.add_node(hash, hash)?;
+            Ok(())
+        }
+
+        let mut tree = Tree::new(0_u64, 0);
+        assert_eq!(
+            add(&mut tree, 7).map_err(|e| e.to_string()),
+            Err("unknown block 7".to_string())
+        );
+        assert_eq!(
+            Error::<u64>::NonMonotonicSlot {
+                slot: 3,
+                parent_slot: 4
+            }
+            .to_string(),
+            "block slot 3 does not exceed parent slot 4"
+        );
+    }
+
+    #[test]
+    fn set_root() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        tree.set_root(a).expect("should re-anchor");
+
+        assert_eq!(tree.root(), a);
+        assert!(!tree.contains_block(&hashes[0]));
+        assert!(!tree.contains_block(&b));
+        assert!(tree.contains_block(&a_tip));
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn prune_moves_checkpoints() {
+        let (mut tree, _hashes, [a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(b, 1).expect("should apply vote");
+        tree.update_justified(Checkpoint {
+            root: a_tip,
+            epoch: 1,
+        })
+        .expect("should update justified");
+
+        // The justified root is kept, while the finalized root is pruned.
+        tree.prune(a).expect("should prune");
+        assert_eq!(tree.finalized(), Checkpoint { root: a, epoch: 0 });
+        assert_eq!(
+            tree.justified(),
+            Checkpoint {
+                root: a_tip,
+                epoch: 1
+            }
+        );
+        assert_eq!(tree.select_head(), Ok(a_tip));
+
+        tree.set_root(a_tip).expect("should re-anchor");
+        assert_eq!(tree.finalized().root, a_tip);
+        assert_eq!(tree.justified().root, a_tip);
+        assert_eq!(tree.select_head(), Ok(a_tip));
+
+        // A justified root on a pruned fork moves to the new root.
+        let (mut tree, _hashes, [a, a_tip, b]) = forked_tree();
+        tree.update_justified(Checkpoint { root: b, epoch: 1 })
+            .expect("should update justified");
+        tree.prune(a).expect("should prune");
+        assert_eq!(tree.justified(), Checkpoint { root: a, epoch: 1 });
+        assert_eq!(tree.select_head(), Ok(a_tip));
+    }
+
+    #[test]
+    fn set_root_errors() {
+        let (mut tree, hashes, [a, _a_tip, b]) = forked_tree();
+
+        assert_eq!(
+            tree.set_root(hashes[1]),
+            Err(Error::UnknownBlock(hashes[1]))
+        );
+
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&b)
+            .unwrap()
+            .parent_hash = None;
+        assert_eq!(tree.set_root(b), Err(Error::NotDescendant(b)));
+        assert_eq!(tree.root(), hashes[0]);
+
+        tree.set_root(a).expect("should re-anchor");
+        assert_eq!(tree.set_root(a), Ok(()));
+    }
+
+    #[test]
+    fn ancestors() {
+        let (tree, hashes) = chain(10);
+        let ancestors = tree.ancestors(hashes[10]).collect::<Vec<_>>();
+        assert_eq!(ancestors, hashes.iter().rev().copied().collect::<Vec<_>>());
+        assert_eq!(
+            tree.ancestors(hashes[0]).collect::<Vec<_>>(),
+            vec![hashes[0]]
+        );
+        assert_eq!(tree.ancestors(Hash256::random()).count(), 0);
+
+        // A store that links blocks in a cycle does not loop forever.
+        let mut tree = tree;
+        Arc::make_mut(&mut tree.store)
+            .get_mut(&hashes[2])
+            .unwrap()
+            .ancestor_skip_list[0] = hashes[8];
+        assert_eq!(tree.ancestors(hashes[10]).count(), tree.store.len());
+    }
+
+    #[test]
+    fn descendants_breadth_first() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let b_tip = add_block(&mut tree, b, 5);
+        tree.add_node(b_tip, b_tip).expect("should add block");
+
+        let descendants = tree.descendants(hashes[0]).collect::<Vec<_>>();
+        assert_eq!(descendants.len(), 5);
+        assert_eq!(descendants[0], hashes[2]);
+        let mut level = descendants[1..3].to_vec();
+        level.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(level, expected);
+        let mut level = descendants[3..].to_vec();
+        level.sort();
+        let mut expected = vec![a_tip, b_tip];
+        expected.sort();
+        assert_eq!(level, expected);
+
+        assert_eq!(tree.descendants(a).collect::<Vec<_>>(), vec![a_tip]);
+        assert_eq!(tree.descendants(a_tip).count(), 0);
+        assert_eq!(tree.descendants(Hash256::random()).count(), 0);
+    }
+
+    #[test]
+    fn check_invariants() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.prune(hashes[1]).expect("should prune");
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let mut dangling = forked_tree().0;
+        let parent = Hash256::random();
+        let node = dangling.root;
+        Arc::make_mut(&mut dangling.nodes)
+            .get_mut(&node)
+            .unwrap()
+            .parent_hash = Some(parent);
+        assert_eq!(
+            dangling.check_invariants(),
+            Err(Error::DanglingParent { node, parent })
+        );
+
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&a_tip)
+            .unwrap()
+            .parent_hash = Some(b);
+        assert_eq!(
+            tree.check_invariants(),
+            Err(Error::UnlinkedChild {
+                parent: a,
+                child: a_tip
+            })
+        );
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&a_tip)
+            .unwrap()
+            .parent_hash = Some(a);
+
+        let height = tree.slots_at_height.index_of(&3).unwrap();
+        Arc::make_mut(&mut tree.blocks_at_height)
+            .get_mut(&height)
+            .unwrap()
+            .retain(|hash| *hash != b);
+        assert_eq!(tree.check_invariants(), Err(Error::InconsistentHeight(b)));
+        Arc::make_mut(&mut tree.blocks_at_height)
+            .get_mut(&(height + 1))
+            .unwrap()
+            .push(b);
+        assert_eq!(tree.check_invariants(), Err(Error::InconsistentHeight(b)));
+        Arc::make_mut(&mut tree.blocks_at_height)
+            .get_mut(&(height + 1))
+            .unwrap()
+            .pop();
+        Arc::make_mut(&mut tree.blocks_at_height)
+            .get_mut(&height)
+            .unwrap()
+            .push(b);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // Every link in this loop is consistent in both directions, so only the traversal from
+        // the root can catch it.
+        let root = tree.root;
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&a_tip)
+            .unwrap()
+            .children
+            .push(root);
+        Arc::make_mut(&mut tree.nodes)
+            .get_mut(&root)
+            .unwrap()
+            .parent_hash = Some(a_tip);
+        assert_eq!(tree.check_invariants(), Err(Error::Cycle(root)));
+    }
+
+    #[test]
+    fn children_sorted_without_duplicates() {
+        let mut node = Node::new(Hash256::random());
+        let child = Hash256::random();
+        node.add_child(child);
+        node.add_child(child);
+        assert_eq!(node.children, vec![child]);
+
+        let (mut tree, hashes) = chain(1);
+        let siblings = (0..8)
+            .map(|_| add_block(&mut tree, hashes[1], 2))
+            .collect::<Vec<_>>();
+        for hash in &siblings {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+
+        let mut sorted = siblings.clone();
+        sorted.sort();
+        assert_eq!(tree.nodes[&hashes[1]].children, sorted);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        tree.get_mut_node(hashes[1]).unwrap().children.swap(0, 1);
+        assert_eq!(
+            tree.check_invariants(),
+            Err(Error::UnsortedChildren(hashes[1]))
+        );
+    }
+
+    #[test]
+    fn approx_memory_bytes_grows_with_nodes() {
+        let (mut tree, hashes) = chain(200);
+        let mut estimate = tree.approx_memory_bytes();
+
+        for hash in &hashes[1..] {
+            tree.add_node(*hash, *hash).expect("should add block");
+            let next = tree.approx_memory_bytes();
+            assert!(next > estimate, "{} should exceed {}", next, estimate);
+            estimate = next;
+        }
+
+        // Adding a block again allocates nothing.
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+        assert_eq!(tree.approx_memory_bytes(), estimate);
+    }
+
+    #[test]
+    fn leaves() {
+        let (mut tree, hashes) = chain(1);
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+        assert_eq!(tree.leaves(), vec![hashes[1]]);
+        assert_eq!(tree.leaf_count(), 1);
+
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        let c = add_block(&mut tree, hashes[1], 2);
+        tree.add_node(c, c).expect("should add block");
+
+        let mut tips = vec![a_tip, b, c];
+        tips.sort();
+        assert_eq!(tree.leaves(), tips);
+        assert_eq!(tree.leaf_count(), 3);
+
+        tree.prune(b).expect("should prune");
+        assert_eq!(tree.leaves(), vec![b]);
+        assert_eq!(tree.leaf_count(), 1);
+    }
+
+    #[test]
+    fn head_candidates() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        assert_eq!(tree.head_candidates().len(), 2);
+
+        tree.process_weighted_attestation(0, a, 3, 3)
+            .expect("should process vote");
+        tree.process_weighted_attestation(1, a_tip, 4, 1)
+            .expect("should process vote");
+        tree.process_weighted_attestation(2, b, 3, 6)
+            .expect("should process vote");
+        tree.process_weighted_attestation(3, hashes[2], 2, 5)
+            .expect("should process vote");
+        assert_eq!(tree.head_candidates(), vec![(b, 11), (a_tip, 9)]);
+
+        tree.process_weighted_attestation(2, a_tip, 4, 6)
+            .expect("should process vote");
+        assert_eq!(tree.head_candidates(), vec![(a_tip, 15), (b, 5)]);
+        assert_eq!(tree.find_head(tree.root()), Some(a_tip));
+    }
+
+    #[test]
+    fn score_of() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_weighted_attestation(0, a, 3, 5)
+            .expect("should process vote");
+        tree.process_weighted_attestation(1, a_tip, 4, 7)
+            .expect("should process vote");
+
+        assert_eq!(tree.score_of(a), Some(12));
+        assert_eq!(tree.score_of(a_tip), Some(7));
+        assert_eq!(tree.score_of(b), Some(0));
+        assert_eq!(tree.score_of(hashes[0]), Some(12));
+        assert_eq!(tree.score_of(Hash256::random()), None);
+    }
+
+    #[test]
+    fn children_of() {
+        let (tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        let mut forks = vec![a, b];
+        forks.sort();
+        assert_eq!(tree.children_of(hashes[2]), Some(forks.as_slice()));
+        assert_eq!(tree.children_of(hashes[0]), Some(&[hashes[2]][..]));
+        assert_eq!(tree.children_of(a), Some(&[a_tip][..]));
+        assert_eq!(tree.children_of(a_tip), Some(&[][..]));
+        assert_eq!(tree.children_of(hashes[1]), None);
+    }
+
+    #[test]
+    fn parent_of() {
+        let (tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        assert_eq!(tree.parent_of(hashes[0]), Some(None));
+        assert_eq!(tree.parent_of(hashes[2]), Some(Some(hashes[0])));
+        assert_eq!(tree.parent_of(a), Some(Some(hashes[2])));
+        assert_eq!(tree.parent_of(b), Some(Some(hashes[2])));
+        assert_eq!(tree.parent_of(a_tip), Some(Some(a)));
+        // Blocks that are not in the tree have no parent in it, even if they are in the store.
+        assert_eq!(tree.parent_of(hashes[1]), None);
+    }
+
+    #[test]
+    fn blocks_at_height() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        let c = add_block(&mut tree, hashes[2], 3);
+        tree.add_node(c, c).expect("should add block");
+
+        assert_eq!(tree.blocks_at_height(0), &[hashes[0]]);
+        assert_eq!(tree.blocks_at_height(1), &[hashes[2]]);
+        assert_eq!(tree.blocks_at_height(3), &[a_tip]);
+
+        let mut at_slot_3 = tree.blocks_at_height(2).to_vec();
+        at_slot_3.sort();
+        let mut expected = vec![a, b, c];
+        expected.sort();
+        assert_eq!(at_slot_3, expected);
+
+        assert_eq!(tree.height_fanout(2), 3);
+        assert_eq!(tree.height_fanout(3), 1);
+        assert_eq!(tree.blocks_at_height(4), &[] as &[Hash256]);
+        assert_eq!(tree.height_fanout(4), 0);
+    }
+
+    #[test]
+    fn min_and_max_slot() {
+        let (mut tree, hashes) = chain(4);
+        assert_eq!((tree.min_slot(), tree.max_slot()), (Some(0), Some(0)));
+
+        for hash in &hashes[1..] {
+            tree.add_node(*hash, *hash).expect("should add block");
+        }
+        assert_eq!((tree.min_slot(), tree.max_slot()), (Some(0), Some(4)));
+
+        tree.prune(hashes[2]).expect("should prune");
+        assert_eq!((tree.min_slot(), tree.max_slot()), (Some(2), Some(4)));
+    }
+
+    #[test]
+    fn debug() {
+        let (tree, hashes, _) = forked_tree();
+        let root = format!("{:?}", hashes[0]);
+
+        let debug = format!("{:?}", tree);
+        assert!(debug.contains(&root[..10]));
+        assert!(debug.contains("nodes: 5, leaves: 2"));
+        assert!(!debug.contains(&format!("{:?}", hashes[1])));
+    }
+
+    #[test]
+    fn walk_subtree() {
+        let (tree, hashes, [a, a_tip, b]) = forked_tree();
+        let walk = |root, order| {
+            let mut visited = vec![];
+            tree.walk_subtree(root, order, |node| visited.push(node.block_hash));
+            visited
+        };
+
+        // Children are visited in order of hash.
+        let (pre, post, breadth) = if a < b {
+            (
+                vec![hashes[0], hashes[2], a, a_tip, b],
+                vec![a_tip, a, b, hashes[2], hashes[0]],
+                vec![hashes[0], hashes[2], a, b, a_tip],
+            )
+        } else {
+            (
+                vec![hashes[0], hashes[2], b, a, a_tip],
+                vec![b, a_tip, a, hashes[2], hashes[0]],
+                vec![hashes[0], hashes[2], b, a, a_tip],
+            )
+        };
+        assert_eq!(walk(hashes[0], TraversalOrder::PreOrder), pre);
+        assert_eq!(walk(hashes[0], TraversalOrder::PostOrder), post);
+        assert_eq!(walk(hashes[0], TraversalOrder::BreadthFirst), breadth);
+        assert_eq!(walk(a, TraversalOrder::PostOrder), vec![a_tip, a]);
+        assert_eq!(walk(hashes[1], TraversalOrder::PreOrder), vec![]);
+    }
+
+    #[test]
+    fn snapshot_cow() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        let snapshot = tree.snapshot_cow();
+        assert!(Arc::ptr_eq(&tree.nodes, &snapshot.nodes));
+        assert!(Arc::ptr_eq(&tree.store, &snapshot.store));
+
+        // Only the collections that are mutated are copied.
+        tree.apply_score_change(b, 2).expect("should apply vote");
+        assert!(!Arc::ptr_eq(&tree.nodes, &snapshot.nodes));
+        assert!(Arc::ptr_eq(&tree.store, &snapshot.store));
+        assert!(Arc::ptr_eq(
+            &tree.latest_messages,
+            &snapshot.latest_messages
+        ));
+
+        let c = Hash256::random();
+        tree.on_block(c, Block::with_parent(4, b))
+            .expect("should import block");
+        tree.process_attestation(0, c, 5)
+            .expect("should process vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(c));
+        tree.prune(b).expect("should prune");
+
+        assert_eq!(snapshot.root(), hashes[0]);
+        assert_eq!(snapshot.len(), 5);
+        assert!(!snapshot.store.contains_key(&c));
+        assert_eq!(snapshot.nodes[&a].score, 1);
+        assert_eq!(snapshot.nodes[&b].score, 0);
+        assert_eq!(snapshot.latest_message(0), Some(a_tip));
+        assert_eq!(snapshot.find_head(hashes[0]), Some(a_tip));
+        assert_eq!(snapshot.check_invariants(), Ok(()));
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn snapshot_full() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        let snapshot = tree.snapshot_full();
+        assert!(!Arc::ptr_eq(&tree.nodes, &snapshot.nodes));
+        assert!(!Arc::ptr_eq(&tree.store, &snapshot.store));
+        assert!(!Arc::ptr_eq(
+            &tree.latest_messages,
+            &snapshot.latest_messages
+        ));
+        assert!(snapshot.diff(&tree).is_empty());
+
+        tree.apply_score_change(b, 2).expect("should apply vote");
+        assert_eq!(snapshot.find_head(hashes[0]), Some(a_tip));
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+        assert_eq!(snapshot.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn diff() {
+        let (mut tree, _, [a, a_tip, b]) = forked_tree();
+        let before = tree.snapshot_cow();
+        assert!(before.diff(&tree).is_empty());
+
+        let c = Hash256::random();
+        tree.on_block(c, Block::with_parent(5, a_tip))
+            .expect("should import block");
+        let diff = before.diff(&tree);
+        assert_eq!(diff.added, vec![c]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), 1);
+        let (hash, old, new) = &diff.changed[0];
+        assert_eq!(*hash, a_tip);
+        assert!(old.children.is_empty());
+        assert_eq!(new.children, vec![c]);
+
+        // Going the other way swaps what was added and removed.
+        tree.apply_score_change(b, 3).expect("should apply vote");
+        let diff = tree.diff(&before);
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed, vec![c]);
+        // The vote changed the score of `b` and of its ancestors in the tree, back to the root.
+        let changed: Vec<_> = diff.changed.iter().map(|(hash, _, _)| *hash).collect();
+        let mut expected: Vec<_> =
+            std::iter::successors(Some(b), |hash| tree.nodes[hash].parent_hash)
+                .chain(std::iter::once(a_tip))
+                .collect();
+        expected.sort();
+        assert_eq!(changed, expected);
+        assert!(!changed.contains(&a));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let (mut tree, hashes, [a, a_tip, b]) = forked_tree();
+        tree.process_attestation(0, a_tip, 4)
+            .expect("should process vote");
+        tree.update_head().expect("should update head");
+        let snapshot = tree.snapshot();
+        let (store, nodes) = (tree.store.clone(), tree.nodes.clone());
+        let slots_at_height = tree.slots_at_height.clone();
+        let blocks_at_height = tree.blocks_at_height.clone();
+
+        let c = Hash256::random();
+        tree.on_block(c, Block::with_parent(4, b))
+            .expect("should import block");
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+        tree.process_attestation(0, c, 5)
+            .expect("should process vote");
+        tree.prune(b).expect("should prune");
+
+        let restored = Tree::restore(snapshot);
+        assert_eq!(restored.root(), hashes[0]);
+        assert!(!restored.store.contains_key(&c));
+        assert_eq!(restored.store, store);
+        assert_eq!(restored.nodes, nodes);
+        assert_eq!(restored.nodes[&a].score, 1);
+        assert_eq!(restored.slots_at_height, slots_at_height);
+        assert_eq!(restored.blocks_at_height, blocks_at_height);
+        assert_eq!(restored.latest_message(0), Some(a_tip));
+        assert_eq!(restored.head, a_tip);
+        assert_eq!(restored.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn expand() {
+        let (mut tree, hashes) = chain(20);
+        let fork = add_block(&mut tree, hashes[5], 6);
+        tree.add_nodes(vec![(hashes[20], hashes[20]), (fork, fork)])
+            .expect("should add blocks");
+        assert_eq!(tree.len(), 4);
+
+        let full = tree.expand();
+
+        assert_eq!(full.root, hashes[0]);
+        assert_eq!(full.blocks.len(), 22);
+        assert_eq!(full.blocks[&hashes[0]].parent, None);
+        for (slot, pair) in hashes.windows(2).enumerate() {
+            let block = &full.blocks[&pair[1]];
+            assert_eq!(block.parent, Some(pair[0]));
+            assert_eq!(block.slot, slot as Slot + 1);
+        }
+        assert_eq!(full.blocks[&fork].parent, Some(hashes[5]));
+
+        let mut children = vec![hashes[6], fork];
+        children.sort();
+        assert_eq!(full.blocks[&hashes[5]].children, children);
+        assert_eq!(full.blocks[&hashes[19]].children, vec![hashes[20]]);
+        assert!(full.blocks[&hashes[20]].children.is_empty());
+
+        let in_tree = full
+            .blocks
+            .iter()
+            .filter(|(_hash, block)| block.in_tree)
+            .count();
+        assert_eq!(in_tree, tree.len());
+        assert!(full.blocks[&hashes[5]].in_tree);
+        assert!(!full.blocks[&hashes[6]].in_tree);
+    }
+
+    #[test]
+    fn to_dot() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.apply_score_change(b, 1).expect("should apply vote");
+        let dot = tree.to_dot();
+        let lines = dot.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.first(), Some(&"digraph tree {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        assert_eq!(lines.iter().filter(|line| line.contains("->")).count(), 4);
+        assert_eq!(
+            lines.iter().filter(|line| line.contains("[label=")).count(),
+            5
+        );
+
+        let head = lines
+            .iter()
+            .find(|line| line.contains("style=filled"))
+            .unwrap();
+        assert!(head.starts_with(&format!("    \"{:x}\"", b)));
+        assert!(head.contains("slot 3 height 2\\nscore 1"));
+        assert!(dot.contains(&format!("\"{:x}\" -> \"{:x}\"", hashes[2], b)));
+        assert!(!dot.contains(&format!("\"{:x}\" -> \"{:x}\"", hashes[0], a_tip)));
+
+        // The head must build on the justified checkpoint, even if another fork is heavier.
+        tree.update_justified(Checkpoint {
+            root: a_tip,
+            epoch: 1,
+        })
+        .expect("should update justified");
+        let dot = tree.to_dot();
+        let head = dot.lines().find(|line| line.contains("style=filled"));
+        assert!(head.unwrap().starts_with(&format!("    \"{:x}\"", a_tip)));
+    }
+
+    #[test]
+    fn to_dot_is_independent_of_insertion_order() {
+        let (mut store_tree, hashes) = chain(6);
+        let mut blocks = hashes[1..].to_vec();
+        for &(parent, slot) in &[(1, 2), (1, 3), (3, 4), (3, 5), (3, 5), (5, 7)] {
+            blocks.push(add_block(&mut store_tree, hashes[parent], slot));
+        }
+
+        let build = |order: &[Hash256]| {
+            let mut tree = Tree::new(hashes[0], 0).with_store((*store_tree.store).clone());
+            for hash in order {
+                tree.add_node(*hash, *hash).expect("should add block");
+            }
+            assert_eq!(tree.check_invariants(), Ok(()));
+            tree.apply_score_change(blocks[8], 2)
+                .expect("should apply vote");
+            tree.to_dot()
+        };
+
+        let dot = build(&blocks);
+        let mut reversed = blocks.clone();
+        reversed.reverse();
+        assert_eq!(build(&reversed), dot);
+        let mut interleaved = blocks.iter().step_by(2).copied().collect::<Vec<_>>();
+        interleaved.extend(blocks.iter().skip(1).step_by(2));
+        assert_eq!(build(&interleaved), dot);
+    }
+
+    #[test]
+    fn size_queries() {
+        let (mut tree, hashes) = chain(3);
+        assert!(tree.contains_block(&hashes[0]));
+        assert_eq!(tree.len(), 1);
+        assert!(!tree.is_empty());
+
+        tree.add_node(hashes[1], hashes[1])
+            .expect("should add block");
+        tree.add_node(hashes[3], hashes[3])
+            .expect("should add block");
+
+        assert_eq!(tree.len(), 3);
+        assert!(tree.contains_block(&hashes[3]));
+        assert!(!tree.contains_block(&hashes[2]));
+    }
+
+    #[test]
+    fn is_ancestor() {
+        let (tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        assert_eq!(tree.is_ancestor(hashes[0], a_tip), Some(true));
+        assert_eq!(tree.is_ancestor(hashes[1], b), Some(true));
+        assert_eq!(tree.is_ancestor(a, a_tip), Some(true));
+        assert_eq!(tree.is_ancestor(a, a), Some(true));
+
+        assert_eq!(tree.is_ancestor(a_tip, a), Some(false));
+        assert_eq!(tree.is_ancestor(b, a_tip), Some(false));
+        assert_eq!(tree.is_ancestor(a, b), Some(false));
+
+        assert_eq!(tree.is_ancestor(Hash256::random(), a), None);
+        assert_eq!(tree.is_ancestor(a, Hash256::random()), None);
+    }
+
+    #[test]
+    fn path_to_ancestor() {
+        let (tree, hashes, [a, a_tip, b]) = forked_tree();
+
+        assert_eq!(
+            tree.path_to_ancestor(a_tip, hashes[0]),
+            Some(vec![a_tip, a, hashes[2], hashes[1], hashes[0]])
+        );
+        assert_eq!(
+            tree.path_to_ancestor(b, hashes[2]),
+            Some(vec![b, hashes[2]])
+        );
+        assert_eq!(tree.path_to_ancestor(a, a), Some(vec![a]));
+        assert_eq!(tree.path_to_ancestor(a_tip, b), None);
+        assert_eq!(tree.path_to_ancestor(hashes[2], a_tip), None);
+        assert_eq!(tree.path_to_ancestor(a_tip, Hash256::random()), None);
+    }
+
+    #[test]
+    fn find_head_unknown_start() {
+        let (tree, _hashes) = chain(1);
+
+        assert_eq!(tree.find_head(Hash256::random()), None);
+    }
+
+    #[test]
+    fn forest() {
+        // Both trees know of every block, but only hold their own.
+        let (mut a, hashes) = chain(5);
+        let fork = add_block(&mut a, hashes[4], 5);
+        let mut b = Tree::new(hashes[0], 0);
+        b.store = a.store.clone();
+        b.add_nodes(vec![
+            (hashes[4], hashes[4]),
+            (hashes[5], hashes[5]),
+            (fork, fork),
+        ])
+        .expect("should add blocks");
+        b.prune(hashes[4]).expect("should prune");
+        a.add_nodes(vec![(hashes[1], hashes[1]), (hashes[2], hashes[2])])
+            .expect("should add blocks");
+
+        let mut forest = Forest::new();
+        forest.insert(a);
+        forest.insert(b);
+        assert_eq!(forest.roots().count(), 2);
+
+        // The trees grow independently.
+        let a = forest.tree_mut(&hashes[0]).unwrap();
+        a.apply_score_change(hashes[2], 2)
+            .expect("should apply vote");
+        assert_eq!(forest.find_global_head(), Some(hashes[2]));
+        let b = forest.tree_mut(&hashes[4]).unwrap();
+        b.apply_score_change(fork, 2).expect("should apply vote");
+        b.apply_score_change(hashes[5], 1)
+            .expect("should apply vote");
+        assert_eq!(forest.find_global_head(), Some(fork));
+
+        forest.merge(hashes[0], hashes[4]).expect("should merge");
+
+        assert_eq!(forest.roots().collect::<Vec<_>>(), vec![hashes[0]]);
+        let tree = forest.tree(&hashes[0]).unwrap();
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert_eq!(tree.nodes[&hashes[4]].parent_hash, Some(hashes[2]));
+        assert_eq!(tree.nodes[&hashes[4]].score, 3);
+        assert_eq!(tree.nodes[&hashes[2]].score, 5);
+        assert_eq!(tree.nodes[&hashes[0]].score, 5);
+        assert_eq!(forest.find_global_head(), Some(fork));
+    }
+
+    /// Returns a forest holding a tree rooted at `hashes[0]` with `hashes[2]` and one rooted at
+    /// `hashes[3]`, with validator `0` voting for `hashes[2]` at `a_slot` in the first tree and
+    /// for `hashes[3]` at `b_slot` in the second.
+    fn forest_with_votes(a_slot: Slot, b_slot: Slot) -> (Forest, Vec<Hash256>) {
+        let (mut a, hashes) = chain(3);
+        let mut b = Tree::new(hashes[0], 0);
+        b.store = a.store.clone();
+        b.add_node(hashes[3], hashes[3]).expect("should add block");
+        b.prune(hashes[3]).expect("should prune");
+        a.add_node(hashes[2], hashes[2]).expect("should add block");
+        a.process_attestation(0, hashes[2], a_slot)
+            .expect("should process vote");
+        b.process_attestation(0, hashes[3], b_slot)
+            .expect("should process vote");
+
+        let mut forest = Forest::new();
+        forest.insert(a);
+        forest.insert(b);
+        (forest, hashes)
+    }
+
+    #[test]
+    fn forest_merge_counts_newer_message_once() {
+        let (mut forest, hashes) = forest_with_votes(2, 3);
+        forest.merge(hashes[0], hashes[3]).expect("should merge");
+        let tree = forest.tree(&hashes[0]).unwrap();
+        assert_eq!(tree.nodes[&hashes[0]].score, 1);
+        assert_eq!(tree.nodes[&hashes[2]].score, 1);
+        assert_eq!(tree.nodes[&hashes[3]].score, 1);
+        assert_eq!(tree.latest_messages[&0], (hashes[3], 3));
+
+        // When the message of the first tree is newer, the weight of the other is dropped.
+        let (mut forest, hashes) = forest_with_votes(4, 3);
+        forest.merge(hashes[0], hashes[3]).expect("should merge");
+        let tree = forest.tree(&hashes[0]).unwrap();
+        assert_eq!(tree.nodes[&hashes[0]].score, 1);
+        assert_eq!(tree.nodes[&hashes[2]].score, 1);
+        assert_eq!(tree.nodes[&hashes[3]].score, 0);
+        assert_eq!(tree.latest_messages[&0], (hashes[2], 4));
+
+        let mut tree = Tree::restore(tree.snapshot());
+        tree.process_attestation(0, hashes[3], 5)
+            .expect("should process vote");
+        assert_eq!(tree.nodes[&hashes[2]].score, 1);
+        assert_eq!(tree.nodes[&hashes[3]].score, 1);
+    }
+
+    #[test]
+    fn forest_merge_failure_leaves_forest_unchanged() {
+        let (mut forest, hashes) = forest_with_votes(2, 3);
+        let a = forest.tree_mut(&hashes[0]).unwrap();
+        a.apply_score_change(hashes[2], i64::MAX)
+            .expect("should apply vote");
+        a.apply_score_change(hashes[2], i64::MAX)
+            .expect("should apply vote");
+        let before = Tree::restore(a.snapshot());
+        forest
+            .tree_mut(&hashes[3])
+            .unwrap()
+            .apply_score_change(hashes[3], 1)
+            .expect("should apply vote");
+
+        assert_eq!(
+            forest.merge(hashes[0], hashes[3]),
+            Err(Error::ScoreOverflow(hashes[2]))
+        );
+        assert_eq!(forest.roots().count(), 2);
+        let a = forest.tree(&hashes[0]).unwrap();
+        assert!(before.diff(a).is_empty());
+        assert_eq!(a.latest_messages[&0], (hashes[2], 2));
+        assert_eq!(forest.tree(&hashes[3]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn forest_merge_errors() {
+        let (mut a, hashes) = chain(2);
+        let other_genesis = Hash256::random();
+        let b = Tree::new(other_genesis, 0);
+        Arc::make_mut(&mut a.store).insert(other_genesis, Block::genesis(other_genesis, 0));
+
+        let mut forest = Forest::new();
+        forest.insert(a);
+        forest.insert(b);
+
+        assert_eq!(
+            forest.merge(hashes[0], other_genesis),
+            Err(Error::MissingParent(other_genesis))
+        );
+        assert_eq!(
+            forest.merge(hashes[1], other_genesis),
+            Err(Error::UnknownBlock(hashes[1]))
+        );
+        assert_eq!(forest.roots().count(), 2);
+    }
+
+    #[test]
+    fn shared_tree_concurrent_reads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Tree>();
+        assert_send_sync::<SharedTree>();
+
+        let (tree, hashes) = chain(200);
+        let tip = *hashes.last().unwrap();
+        let fork_parent = hashes[100];
+        let shared = SharedTree::from(tree);
+
+        let readers = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let mut last_slot = 0;
+                    loop {
+                        let (head, slot) = shared.with_read(|tree| {
+                            let head = tree.find_head(tree.root()).expect("should find head");
+                            (head, tree.store[&head].slot)
+                        });
+                        // Blocks are only ever added, so the head never moves backwards.
+                        assert!(slot >= last_slot);
+                        last_slot = slot;
+                        if head == tip {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for hash in &hashes[1..] {
+                    shared
+                        .with_write(|tree| tree.add_node(*hash, *hash))
+                        .expect("should add block");
+                }
+            })
+        };
+
+        writer.join().expect("writer should not panic");
+        for reader in readers {
+            reader.join().expect("reader should not panic");
+        }
+        assert_eq!(shared.read_head(), Some(tip));
+
+        // The head must build on the justified checkpoint, even if another fork is heavier.
+        let fork = shared.with_write(|tree| {
+            let fork = add_block(tree, fork_parent, 101);
+            tree.add_node(fork, fork).expect("should add block");
+            tree.apply_score_change(tip, 1).expect("should apply vote");
+            tree.update_justified(Checkpoint {
+                root: fork,
+                epoch: 1,
+            })
+            .expect("should update justified");
+            fork
+        });
+        assert_eq!(shared.read_head(), Some(fork));
+    }
+
+    #[test]
+    fn ancestor_cache_evicts_least_recently_used() {
+        let mut cache = AncestorCache::new(2);
+        cache.insert((1_u64, 0), Some(10));
+        cache.insert((2, 0), None);
+        assert_eq!(cache.get(&(1, 0)), Some(Some(10)));
+
+        cache.insert((3, 0), Some(30));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&(2, 0)), None);
+        assert_eq!(cache.get(&(1, 0)), Some(Some(10)));
+        assert_eq!(cache.get(&(3, 0)), Some(Some(30)));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn ancestor_cache_matches_uncached() {
+        let (mut uncached, hashes) = chain(64);
+        let mut tips = vec![];
+        for (i, parent) in hashes.iter().enumerate().step_by(7) {
+            let fork = add_block(&mut uncached, *parent, i as Slot + 1);
+            tips.push(add_block(&mut uncached, fork, i as Slot + 3));
+        }
+        let blocks = hashes[1..].iter().chain(&tips).map(|hash| (*hash, *hash));
+
+        // A small capacity so that entries are evicted along the way.
+        let mut cached = Tree::new(hashes[0], 0);
+        cached.store = uncached.store.clone();
+        cached.enable_ancestor_cache(16);
+
+        cached.add_nodes(blocks.clone()).expect("should add blocks");
+        uncached.add_nodes(blocks).expect("should add blocks");
+
+        for hash in uncached.store.keys() {
+            for slot in 0..70 {
+                assert_eq!(
+                    cached.find_ancestor_at_slot(*hash, slot),
+                    uncached.find_ancestor_at_slot(*hash, slot)
+                );
+            }
+            assert_eq!(
+                cached.nodes.get(hash).map(|node| node.parent_hash),
+                uncached.nodes.get(hash).map(|node| node.parent_hash)
+            );
+        }
+
+        cached.prune(hashes[7]).expect("should prune");
+        assert!(cached
+            .ancestor_cache
+            .as_mut()
+            .unwrap()
+            .get_mut()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn ancestor_cache_cleared_with_store() {
+        let (mut tree, hashes) = chain(8);
+        let store = (*tree.store).clone();
+        tree.enable_ancestor_cache(16);
+        tree.add_node(hashes[8], hashes[8])
+            .expect("should add node");
+        let cache_is_empty = |tree: &mut Tree| {
+            let cache = tree.ancestor_cache.as_mut().unwrap().get_mut().unwrap();
+            cache.is_empty()
+        };
+        assert!(!cache_is_empty(&mut tree));
+
+        let mut tree = tree.with_store(store);
+        assert!(cache_is_empty(&mut tree));
+
+        // A block removed from the store again after a failed import leaves no cached lookups.
+        tree.prune(hashes[8]).expect("should prune");
+        let late = Hash256::random();
+        assert_eq!(
+            tree.on_block(late, Block::with_parent(3, hashes[2])),
+            Err(Error::MissingParent(late))
+        );
+        assert!(!tree.store.contains_key(&late));
+        assert!(cache_is_empty(&mut tree));
+    }
+
+    #[test]
+    fn add_node_uses_ancestor_cache() {
+        let (mut tree, hashes) = chain(16);
+        tree.enable_ancestor_cache(16);
+
+        tree.add_node(hashes[8], hashes[8])
+            .expect("should add node");
+        tree.add_node(hashes[12], hashes[12])
+            .expect("should add node");
+
+        // Finding the previous node in the tree for `hashes[12]` looks up its ancestor at the
+        // slot of `hashes[8]`.
+        let cache = tree.ancestor_cache.as_mut().unwrap().get_mut().unwrap();
+        assert_eq!(cache.get(&(hashes[12], 8)), Some(Some(hashes[8])));
+    }
+
+    /// Returns a tree with `len` blocks where each block is the child of a pseudo-random earlier
+    /// block, and a vote from each of `len` validators for a pseudo-random block.
+    fn random_tree(len: usize) -> Tree {
+        let (mut tree, genesis) = genesis();
+        let mut hashes = vec![genesis];
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % bound
+        };
+
+        for _ in 0..len {
+            // Favour recent blocks so the tree has long branches as well as wide ones.
+            let parent = hashes[hashes.len() - 1 - next(hashes.len().min(8))];
+            let slot = tree.store[&parent].slot + 1;
+            hashes.push(add_block(&mut tree, parent, slot));
+        }
+        tree.add_nodes(hashes[1..].iter().map(|hash| (*hash, *hash)))
+            .expect("should add blocks");
+
+        for validator in 0..len as ValidatorIndex {
+            let block = hashes[next(hashes.len())];
+            let slot = tree.store[&block].slot;
+            tree.process_attestation(validator, block, slot)
+                .expect("should process vote");
+        }
+
+        tree
+    }
+
+    #[test]
+    fn remove_validator() {
+        let (mut tree, hashes, [_a, a_tip, b]) = forked_tree();
+        tree.process_weighted_attestation(1, b, 3, 20)
+            .expect("should process vote");
+        let without = tree.snapshot();
+
+        tree.process_weighted_attestation(0, a_tip, 4, 32)
+            .expect("should process vote");
+        assert_eq!(tree.find_head(hashes[0]), Some(a_tip));
+
+        tree.remove_validator(0).expect("should remove validator");
+        let without = Tree::restore(without);
+        assert_eq!(tree.find_head(hashes[0]), Some(b));
+        assert_eq!(scores(&tree), scores(&without));
+        assert_eq!(tree.latest_message(0), None);
+
+        // The weight is not added back by a recompute, and removing it again does nothing.
+        tree.recompute_scores();
+        assert_eq!(scores(&tree), scores(&without));
+        tree.remove_validator(0).expect("should remove validator");
+        assert_eq!(scores(&tree), scores(&without));
+    }
+
+    #[test]
+    fn process_aggregate() {
+        let (mut tree, _hashes, [a, a_tip, b]) = forked_tree();
+        let balances = [32, 20, 31, 16, 8, 40, 24];
+        tree.process_weighted_attestation(0, b, 3, balances[0])
+            .expect("should process vote");
+        tree.process_weighted_attestation(1, a_tip, 4, 10)
+            .expect("should process vote");
+        // Already votes for the target with its balance, so contributes nothing.
+        tree.process_weighted_attestation(2, a_tip, 4, balances[2])
+            .expect("should process vote");
+        // Newer than the aggregate, so is ignored.
+        tree.process_weighted_attestation(3, b, 6, balances[3])
+            .expect("should process vote");
+        tree.process_weighted_attestation(5, a, 3, balances[5])
+            .expect("should process vote");
+
+        let mut one_by_one = Tree::restore(tree.snapshot());
+        // Every validator but 5.
+        let participants = [0b0101_1111];
+        tree.process_aggregate(&participants, a_tip, 5, &balances)
+            .expect("should process aggregate");
+
+        for i in (0..balances.len()).filter(|i| i != &5) {
+            one_by_one
+                .process_weighted_attestation(i as ValidatorIndex, a_tip, 5, balances[i])
+                .expect("should process vote");
+        }
+        assert_eq!(scores(&tree), scores(&one_by_one));
+        assert_eq!(tree.latest_messages, one_by_one.latest_messages);
+        assert_eq!(tree.message_balances, one_by_one.message_balances);
+        assert_eq!(tree.nodes[&b].score, 16);
+        assert_eq!(tree.nodes[&a].score, 40 + 32 + 20 + 31 + 8 + 24);
+
+        let before = scores(&tree);
+        // The bit of validator 8 is the first of the second byte.
+        assert_eq!(
+            tree.process_aggregate(&[0, 1], b, 6, &balances),
+            Err(Error::MissingBalance(8))
+        );
+        let missing = Hash256::random();
+        assert_eq!(
+            tree.process_aggregate(&participants, missing, 6, &balances),
+            Err(Error::UnknownBlock(missing))
+        );
+        assert_eq!(scores(&tree), before);
+        assert_eq!(tree.latest_message(0), Some(a_tip));
+    }
+
+    fn scores(tree: &Tree) -> HashMap<Hash256, u64> {
+        tree.nodes
+            .iter()
+            .map(|(hash, node)| (*hash, node.score))
+            .collect()
+    }
+
+    #[test]
+    fn recompute_scores() {
+        let mut tree = random_tree(300);
+        assert_eq!(tree.check_invariants(), Ok(()));
+        let expected = scores(&tree);
+        assert_eq!(expected[&tree.root()], 300);
+
+        for node in Arc::make_mut(&mut tree.nodes).values_mut() {
+            node.score = 7;
+        }
+        tree.recompute_scores();
+
+        assert_eq!(scores(&tree), expected);
+    }
+
+    #[test]
+    fn best_descendant_matches_find_head_by_score() {
+        let mut tree = random_tree(300);
+        let check = |tree: &Tree| {
+            for hash in tree.nodes.keys() {
+                let from_scratch = tree.find_head_by(*hash, |hash| Some(tree.nodes[hash].score));
+                assert_eq!(tree.find_head(*hash), from_scratch);
+            }
+        };
+        check(&tree);
+
+        let hashes = tree.nodes.keys().copied().collect::<Vec<_>>();
+        let mut seed = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize % bound
+        };
+        for round in 0..200 {
+            let validator = next(300) as ValidatorIndex;
+            let block = hashes[next(hashes.len())];
+            tree.process_weighted_attestation(validator, block, 1_000 + round, next(64) as u64)
+                .expect("should process vote");
+            if round % 50 == 0 {
+                tree.remove_validator(next(300) as ValidatorIndex)
+                    .expect("should remove validator");
+            }
+            check(&tree);
+        }
+
+        let leaf = tree.find_head(tree.root()).unwrap();
+        tree.remove_node(leaf).expect("should remove node");
+        check(&tree);
+        tree.collapse();
+        check(&tree);
+        tree.recompute_scores();
+        check(&tree);
+
+        // Adding blocks out of order places them between nodes and creates branch points, and a
+        // block without votes may win a tie against its siblings.
+        let root = tree.root();
+        let mut blocks = tree
+            .store
+            .keys()
+            .copied()
+            .filter(|hash| *hash != root)
+            .collect::<Vec<_>>();
+        blocks.sort();
+        for i in (1..blocks.len()).rev() {
+            blocks.swap(i, next(i + 1));
+        }
+        let mut tree = TreeBuilder::new()
+            .root(root)
+            .with_store((*tree.store).clone())
+            .build()
+            .expect("should build tree");
+        for (i, block) in blocks.into_iter().enumerate() {
+            tree.add_node(block, block).expect("should add block");
+            if i % 10 == 0 {
+                tree.process_attestation(i as ValidatorIndex, block, 1_000)
+                    .expect("should process vote");
+            }
+            check(&tree);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn recompute_scores_parallel() {
+        let mut tree = random_tree(300);
+        tree.recompute_scores();
+        let expected = scores(&tree);
+
+        for node in Arc::make_mut(&mut tree.nodes).values_mut() {
+            node.score = 0;
+        }
+        tree.recompute_scores_parallel();
+
+        assert_eq!(scores(&tree), expected);
+    }
+
+    /// A block of a generated tree: the index of its parent among the blocks before it (with
+    /// genesis at index `0`), the number of slots after its parent, and whether to import it
+    /// with `on_block` rather than inserting it into the store and calling `add_node`.
+    type GeneratedBlock = (usize, Slot, bool);
+
+    /// Builds a tree from `blocks` and `votes` (indices of the voted blocks), checking that it is
+    /// consistent and that its head has no children.
+    fn check_generated_tree(blocks: &[GeneratedBlock], votes: &[usize]) {
+        let (mut tree, genesis) = genesis();
+        let mut hashes = vec![genesis];
+
+        for &(parent, slots, on_block) in blocks {
+            let parent = hashes[parent % hashes.len()];
+            let slot = tree.store[&parent].slot + slots;
+            let hash = Hash256::random();
+            if on_block {
+                tree.on_block(hash, Block::with_parent(slot, parent))
+                    .expect("should import block");
+            } else {
+                let block = Block::new(slot, parent, &*tree.store).expect("should build block");
+                Arc::make_mut(&mut tree.store).insert(hash, block);
+                tree.add_node(hash, hash).expect("should add block");
+            }
+            hashes.push(hash);
+        }
+
+        for (validator, &block) in votes.iter().enumerate() {
+            let block = hashes[block % hashes.len()];
+            let slot = tree.store[&block].slot;
+            tree.process_attestation(validator as ValidatorIndex, block, slot)
+                .expect("should process vote");
+        }
+
+        assert_eq!(tree.check_invariants(), Ok(()));
+        let head = tree.find_head(genesis).expect("should find head");
+        assert!(tree.nodes[&head].children.is_empty());
+    }
+
+    #[test]
+    fn generated_tree_corpus() {
+        let corpus: &[(&[GeneratedBlock], &[usize])] = &[
+            // A single chain without gaps.
+            (
+                &[(0, 1, true), (1, 1, false), (2, 1, true), (3, 1, false)],
+                &[4],
+            ),
+            // A chain with skipped slots.
+            (&[(0, 3, true), (1, 2, true), (2, 5, false)], &[1, 3]),
+            // Two forks from genesis, the later one imported first.
+            (
+                &[(0, 4, true), (0, 1, false), (2, 1, true), (1, 1, false)],
+                &[4, 4, 1],
+            ),
+            // A fork below an existing branch.
+            (
+                &[
+                    (0, 1, true),
+                    (1, 1, true),
+                    (1, 3, true),
+                    (2, 1, false),
+                    (1, 1, false),
+                ],
+                &[5, 3],
+            ),
+            // A block above skipped slots, with forks filling the heights it skips.
+            (
+                &[
+                    (0, 1, false),
+                    (0, 1, false),
+                    (0, 1, false),
+                    (0, 3, false),
+                    (0, 1, false),
+                    (4, 1, false),
+                ],
+                &[],
+            ),
+        ];
+
+        for (blocks, votes) in corpus {
+            check_generated_tree(blocks, votes);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn generated_tree(
+            blocks in proptest::collection::vec((0..64_usize, 1..4_u64, proptest::bool::ANY), 1..64),
+            votes in proptest::collection::vec(0..64_usize, 0..16),
+        ) {
+            check_generated_tree(&blocks, &votes);
+        }
+    }
+
+    /// The tree with `u64` hashes instead of `H256`.
+    mod u64_hash {
+        use super::super::*;
+
+        /// Returns a tree rooted at a genesis block `0` with the following blocks in the store
+        /// (but not in the tree), where each block's hash is its slot plus `10` per fork:
+        ///
+        /// ```text
+        /// 0 - 1 - 2 - 3 - 4
+        ///          \
+        ///           13 - 14
+        /// ```
+        fn forked_tree() -> Tree<u64> {
+            let mut tree = Tree::new(0, 0);
+            for (hash, parent) in &[(1, 0), (2, 1), (3, 2), (4, 3), (13, 2), (14, 13)] {
+                let block =
+                    Block::new(hash % 10, *parent, &*tree.store).expect("should build block");
+                Arc::make_mut(&mut tree.store).insert(*hash, block);
+            }
+            tree
+        }
+
+        #[test]
+        fn add_node_and_find_head() {
+            let mut tree = forked_tree();
+            tree.add_nodes(vec![(4, 4), (14, 14)])
+                .expect("should add blocks");
+
+            assert_eq!(tree.nodes[&0].children, vec![2]);
+            assert_eq!(tree.nodes[&2].children, vec![4, 14]);
+            assert_eq!(tree.find_head(0), Some(14));
+
+            tree.apply_score_change(4, 1).expect("should apply vote");
+            assert_eq!(tree.find_head(0), Some(4));
+            assert_eq!(tree.check_invariants(), Ok(()));
+        }
+
+        #[test]
+        fn ancestors() {
+            let tree = forked_tree();
+
+            assert_eq!(get_ancestor_hash_at_slot(1, 14, &*tree.store), Ok(Some(1)));
+            assert_eq!(find_least_common_ancestor(4, 14, &*tree.store), Ok(Some(2)));
+            assert_eq!(tree.is_ancestor(13, 14), Some(true));
+            assert_eq!(tree.is_ancestor(3, 14), Some(false));
+            assert_eq!(
+                get_ancestor_hash_at_slot(1, 5, &*tree.store),
+                Err(Error::UnknownBlock(5))
+            );
+        }
+
+        #[test]
+        fn prune() {
+            let mut tree = forked_tree();
+            tree.add_nodes(vec![(4, 4), (14, 14)])
+                .expect("should add blocks");
+
+            tree.prune(13).expect("should prune");
+
+            assert_eq!(tree.root(), 13);
+            assert_eq!(tree.len(), 2);
+            assert_eq!(tree.find_head(13), Some(14));
+        }
+
+        #[test]
+        fn debug() {
+            let mut tree = forked_tree();
+            tree.add_nodes(vec![(4, 4), (14, 14)])
+                .expect("should add blocks");
+
+            assert_eq!(
+                format!("{:?}", tree),
+                "Tree { root: 0, head: 0, nodes: 4, leaves: 2, blocks: 7, .. }"
+            );
+            assert_eq!(
+                format!("{:?}", tree.store[&14]),
+                "Block { slot: 4, ancestor_skip_list: [13, 2, 0, ..] }"
+            );
+            assert_eq!(
+                format!("{:?}", Block::<u64, 2>::genesis(7, 0)),
+                "Block { slot: 0, ancestor_skip_list: [7, 7] }"
+            );
+        }
     }
 }